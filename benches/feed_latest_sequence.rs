@@ -0,0 +1,55 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use ssb_db::{SqliteSsbDb, SsbDb};
+use ssb_multiformats::multikey::Multikey;
+
+// get_feed_latest_sequence is the hottest query in replication scheduling, so check it stays
+// cheap without a cache on a db with many feeds.
+fn feed_latest_sequence(c: &mut Criterion) {
+    let num_feeds = 100;
+    let messages_per_feed = 1000;
+    let feed_id = |feed: usize| format!("@{:042}0=.ed25519", feed);
+
+    let db_path = "/tmp/bench_feed_latest_sequence.sqlite3";
+    let offset_path = "/tmp/bench_feed_latest_sequence.offset";
+    let _ = std::fs::remove_file(db_path);
+    let _ = std::fs::remove_file(offset_path);
+    let db = SqliteSsbDb::new(db_path, offset_path);
+
+    let feeds = (0..num_feeds)
+        .map(|feed| Multikey::from_legacy(feed_id(feed).as_bytes()).unwrap().0)
+        .collect::<Vec<_>>();
+    feeds.iter().enumerate().for_each(|(feed, author)| {
+        let entries = (1..=messages_per_feed)
+            .map(|seq| {
+                serde_json::to_vec(&serde_json::json!({
+                    "key": format!("%{:042}0=.sha256", feed * messages_per_feed + seq),
+                    "value": {
+                        "author": feed_id(feed),
+                        "sequence": seq,
+                        "content": { "type": "post", "text": "hello" }
+                    }
+                }))
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        db.append_batch(author, &entries).unwrap();
+    });
+
+    let mut feed = 0;
+    c.bench_function(
+        "get_feed_latest_sequence, 100 feeds of 1000 messages",
+        |b| {
+            b.iter(|| {
+                feed = (feed + 1) % num_feeds;
+                db.get_feed_latest_sequence(&feeds[feed]).unwrap()
+            })
+        },
+    );
+
+    drop(db);
+    std::fs::remove_file(db_path).unwrap();
+    std::fs::remove_file(offset_path).unwrap();
+}
+
+criterion_group!(benches, feed_latest_sequence);
+criterion_main!(benches);
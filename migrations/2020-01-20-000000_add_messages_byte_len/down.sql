@@ -0,0 +1,12 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE messages_without_byte_len (
+  flume_seq BIGINT PRIMARY KEY,
+  seq INTEGER NOT NULL,
+  key_id INTEGER UNIQUE NOT NULL,
+  author_id INTEGER NOT NULL
+);
+INSERT INTO messages_without_byte_len SELECT flume_seq, seq, key_id, author_id FROM messages;
+DROP TABLE messages;
+ALTER TABLE messages_without_byte_len RENAME TO messages;
+CREATE INDEX IF NOT EXISTS messages_author_id_index ON messages(author_id);
+CREATE INDEX IF NOT EXISTS messages_author_id_seq_index ON messages(author_id, seq);
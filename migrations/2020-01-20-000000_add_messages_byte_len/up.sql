@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN byte_len BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,14 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS messages_content_type_index;
+CREATE TABLE messages_without_content_type (
+  flume_seq BIGINT PRIMARY KEY,
+  seq INTEGER NOT NULL,
+  key_id INTEGER UNIQUE NOT NULL,
+  author_id INTEGER NOT NULL,
+  byte_len BIGINT NOT NULL DEFAULT 0
+);
+INSERT INTO messages_without_content_type SELECT flume_seq, seq, key_id, author_id, byte_len FROM messages;
+DROP TABLE messages;
+ALTER TABLE messages_without_content_type RENAME TO messages;
+CREATE INDEX IF NOT EXISTS messages_author_id_index ON messages(author_id);
+CREATE INDEX IF NOT EXISTS messages_author_id_seq_index ON messages(author_id, seq);
@@ -0,0 +1,2 @@
+ALTER TABLE messages ADD COLUMN content_type TEXT;
+CREATE INDEX IF NOT EXISTS messages_content_type_index ON messages(content_type);
@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN previous_key_id INTEGER;
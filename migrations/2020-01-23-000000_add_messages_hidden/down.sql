@@ -0,0 +1,16 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE messages_without_hidden (
+  flume_seq BIGINT PRIMARY KEY,
+  seq INTEGER NOT NULL,
+  key_id INTEGER UNIQUE NOT NULL,
+  author_id INTEGER NOT NULL,
+  byte_len BIGINT NOT NULL DEFAULT 0,
+  content_type TEXT,
+  previous_key_id INTEGER
+);
+INSERT INTO messages_without_hidden SELECT flume_seq, seq, key_id, author_id, byte_len, content_type, previous_key_id FROM messages;
+DROP TABLE messages;
+ALTER TABLE messages_without_hidden RENAME TO messages;
+CREATE INDEX IF NOT EXISTS messages_author_id_index ON messages(author_id);
+CREATE INDEX IF NOT EXISTS messages_author_id_seq_index ON messages(author_id, seq);
+CREATE INDEX IF NOT EXISTS messages_content_type_index ON messages(content_type);
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS messages_author_id_flume_seq_index ON messages(author_id, flume_seq);
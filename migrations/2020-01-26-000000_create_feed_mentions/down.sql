@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS feed_mentions_mentioned_author_index;
+DROP TABLE IF EXISTS feed_mentions;
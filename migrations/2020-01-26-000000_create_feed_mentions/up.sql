@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS feed_mentions (
+  from_flume_seq BIGINT NOT NULL,
+  mentioned_author TEXT NOT NULL,
+  PRIMARY KEY (from_flume_seq, mentioned_author)
+);
+CREATE INDEX IF NOT EXISTS feed_mentions_mentioned_author_index ON feed_mentions(mentioned_author);
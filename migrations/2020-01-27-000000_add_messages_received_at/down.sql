@@ -0,0 +1,20 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS messages_author_id_received_at_index;
+CREATE TABLE messages_without_received_at (
+  flume_seq BIGINT PRIMARY KEY,
+  seq INTEGER NOT NULL,
+  key_id INTEGER UNIQUE NOT NULL,
+  author_id INTEGER NOT NULL,
+  byte_len BIGINT NOT NULL DEFAULT 0,
+  content_type TEXT,
+  previous_key_id INTEGER,
+  hidden BOOLEAN NOT NULL DEFAULT 0,
+  raw BLOB
+);
+INSERT INTO messages_without_received_at SELECT flume_seq, seq, key_id, author_id, byte_len, content_type, previous_key_id, hidden, raw FROM messages;
+DROP TABLE messages;
+ALTER TABLE messages_without_received_at RENAME TO messages;
+CREATE INDEX IF NOT EXISTS messages_author_id_index ON messages(author_id);
+CREATE INDEX IF NOT EXISTS messages_author_id_seq_index ON messages(author_id, seq);
+CREATE INDEX IF NOT EXISTS messages_content_type_index ON messages(content_type);
+CREATE INDEX IF NOT EXISTS messages_author_id_flume_seq_index ON messages(author_id, flume_seq);
@@ -0,0 +1,2 @@
+ALTER TABLE messages ADD COLUMN received_at DOUBLE;
+CREATE INDEX IF NOT EXISTS messages_author_id_received_at_index ON messages(author_id, received_at);
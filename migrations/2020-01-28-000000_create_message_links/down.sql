@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS message_links_target_key_index;
+DROP TABLE IF EXISTS message_links;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS message_links (
+  from_flume_seq BIGINT NOT NULL,
+  target_key TEXT NOT NULL,
+  PRIMARY KEY (from_flume_seq, target_key)
+);
+CREATE INDEX IF NOT EXISTS message_links_target_key_index ON message_links(target_key);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS feed_meta (
+  author TEXT PRIMARY KEY NOT NULL,
+  nickname TEXT,
+  priority INTEGER NOT NULL DEFAULT 0,
+  muted BOOLEAN NOT NULL DEFAULT 0
+);
@@ -0,0 +1,6 @@
+-- How far through the offset log has been indexed, including entries that were skipped. There's
+-- only ever one row.
+CREATE TABLE IF NOT EXISTS indexed_offset (
+  id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+  flume_seq BIGINT NOT NULL
+);
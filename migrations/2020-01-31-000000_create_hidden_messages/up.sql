@@ -0,0 +1,5 @@
+-- The keys of the messages hidden with `set_hidden`. Unlike `messages.hidden`, which is derived
+-- from this whenever a message is indexed, it's kept when the indexes are rebuilt.
+CREATE TABLE IF NOT EXISTS hidden_messages (
+  key TEXT PRIMARY KEY NOT NULL
+);
@@ -0,0 +1,6 @@
+-- When each message was received, as set with `set_feed_received_at`. It overrides
+-- `messages.received_at` whenever a message is indexed, so it's kept when the indexes are rebuilt.
+CREATE TABLE IF NOT EXISTS received_at_overrides (
+  key TEXT PRIMARY KEY NOT NULL,
+  received_at DOUBLE NOT NULL
+);
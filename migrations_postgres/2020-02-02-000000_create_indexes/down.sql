@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS indexed_offset;
+DROP TABLE IF EXISTS messages;
+DROP TABLE IF EXISTS keys;
+DROP TABLE IF EXISTS authors;
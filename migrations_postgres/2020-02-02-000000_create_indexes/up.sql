@@ -0,0 +1,23 @@
+-- The same index tables as the sqlite migrations build up, in their current shape. Only the
+-- columns `PostgresSsbDb` queries are here.
+CREATE TABLE IF NOT EXISTS authors (
+  id SERIAL PRIMARY KEY,
+  author TEXT UNIQUE NOT NULL
+);
+CREATE TABLE IF NOT EXISTS keys (
+  id SERIAL PRIMARY KEY,
+  key TEXT UNIQUE NOT NULL
+);
+CREATE TABLE IF NOT EXISTS messages (
+  flume_seq BIGINT PRIMARY KEY,
+  seq INTEGER NOT NULL,
+  key_id INTEGER UNIQUE NOT NULL,
+  author_id INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_author_id_seq_index ON messages(author_id, seq);
+-- How far through the offset log has been indexed, including entries that were skipped. There's
+-- only ever one row.
+CREATE TABLE IF NOT EXISTS indexed_offset (
+  id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+  flume_seq BIGINT NOT NULL
+);
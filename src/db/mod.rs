@@ -1,47 +1,290 @@
 use diesel::prelude::*;
 pub use diesel::result::Error;
 use flumedb::flume_view::Sequence as FlumeSequence;
+use snafu::{ResultExt, Snafu};
+use ssb_multiformats::multihash::Multihash;
+use ssb_multiformats::multikey::Multikey;
 
 pub mod models;
+#[cfg(feature = "postgres")]
+pub mod postgres;
 pub mod schema;
 
-pub use models::{authors, keys, messages};
+pub use models::{
+    authors, feed_mentions, feed_meta, hidden_messages, indexed_offset, keys, message_links,
+    messages, received_at_overrides,
+};
 
-pub use authors::find_or_create_author;
+pub use authors::{
+    find_all_authors, find_author_id, find_author_ids, find_or_create_author, update_author,
+};
+pub use feed_mentions::{find_feed_mention_flume_seqs, insert_feed_mentions, FeedMention};
+pub use feed_meta::{find_all_feed_meta, find_feed_meta, replace_feed_meta, FeedMetaRow};
+pub use hidden_messages::{
+    find_all_hidden_keys, hide_messages_since, replace_hidden_keys, set_key_hidden,
+};
+pub use indexed_offset::{find_indexed_offset, set_indexed_offset};
 pub use keys::find_or_create_key;
+pub use message_links::{
+    find_links_to_any_flume_seqs, find_links_to_from_authors_flume_seqs, insert_message_links,
+    MessageLink,
+};
 pub use messages::{
-    find_feed_flume_seqs_newer_than, find_feed_latest_seq,
-    find_message_flume_seq_by_author_and_sequence, find_message_flume_seq_by_key, get_latest,
-    insert_message,
+    count_feed_messages_received_since, delete_feed_messages_newer_than,
+    delete_feed_messages_over_limit, find_content_type_counts, find_feed_byte_lens,
+    find_feed_chain, find_feed_first_flume_seq, find_feed_flume_seqs_after,
+    find_feed_flume_seqs_between, find_feed_flume_seqs_by_type, find_feed_flume_seqs_newer_than,
+    find_feed_is_complete, find_feed_latest_flume_seq_by_type, find_feed_latest_seq,
+    find_feed_seqs_between, find_feeds_latest_flume_seqs, find_feeds_latest_seqs,
+    find_flume_seq_at_index, find_flume_seqs_by_type, find_flume_seqs_matching,
+    find_index_events_since, find_message_author_and_seq_by_key, find_message_author_by_key,
+    find_message_flume_seq_by_author_and_sequence, find_message_flume_seq_by_key,
+    find_message_is_latest_by_key, find_message_keys_present, find_message_raw_by_flume_seq,
+    find_message_seq_by_key, find_root_flume_seqs, get_latest, get_oldest, insert_message,
+    set_feed_received_at, set_message_hidden, Message,
+};
+pub use received_at_overrides::{
+    find_all_received_at_overrides, override_feed_received_at, override_received_at_since,
+    replace_received_at_overrides, ReceivedAtOverride,
 };
 
-use crate::ssb_message::SsbMessage;
+use crate::ssb_message::SsbMessageParser;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum AppendError {
+    #[snafu(display("Key {} is already indexed for a different message", key))]
+    KeyCollision { key: String },
+    #[snafu(display("{}", source))]
+    Sqlite { source: Error },
+}
+
+impl From<Error> for AppendError {
+    fn from(source: Error) -> Self {
+        AppendError::Sqlite { source }
+    }
+}
+
+/// Whether to keep a copy of each message's bytes in the `raw` column when indexing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RawStorage {
+    /// Don't keep them, entries are read from the offset log.
+    Off,
+    /// Keep them as they are.
+    Plain,
+    /// Keep them zstd compressed, for each message where that makes it smaller.
+    #[cfg(feature = "compress-raw")]
+    Compressed,
+}
+
+// Every zstd frame starts with these bytes, and a message (a json object) never does.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+fn encode_raw(item: &[u8], raw_storage: RawStorage) -> Option<Vec<u8>> {
+    match raw_storage {
+        RawStorage::Off => None,
+        RawStorage::Plain => Some(item.to_vec()),
+        #[cfg(feature = "compress-raw")]
+        RawStorage::Compressed => match zstd::encode_all(item, 0) {
+            // Tiny messages can come out bigger than they went in.
+            Ok(compressed) if compressed.len() < item.len() => Some(compressed),
+            _ => Some(item.to_vec()),
+        },
+    }
+}
+
+/// Turn the bytes from the `raw` column back into the message, decompressing them if they were
+/// compressed. Returns `None` if they're compressed and this build can't decompress them, so the
+/// entry can be read from the offset log instead.
+pub fn decode_raw(raw: Vec<u8>) -> Option<Vec<u8>> {
+    if !raw.starts_with(&ZSTD_MAGIC) {
+        return Some(raw);
+    }
+
+    #[cfg(feature = "compress-raw")]
+    {
+        zstd::decode_all(&raw[..]).ok()
+    }
+    #[cfg(not(feature = "compress-raw"))]
+    {
+        None
+    }
+}
 
+/// Is this error sqlite telling us the db is busy or locked by another connection?
+pub fn is_busy_error(error: &Error) -> bool {
+    match error {
+        Error::DatabaseError(_, info) => {
+            let message = info.message();
+            message.contains("database is locked") || message.contains("table is locked")
+        }
+        _ => false,
+    }
+}
+
+/// The tables that aren't derived from the offset log, so can't be rebuilt from it. Every other
+/// table is an index, cleared by [clear_indexes].
+///
+/// When the whole db file has to be thrown away, save this first and restore it into the new one.
+///
+/// Hidden flags and received times are copied onto the messages as they're indexed, so once the
+/// restored db is indexed the messages are as they were.
+#[derive(Debug, Default)]
+pub struct LocalState {
+    feed_meta: Vec<FeedMetaRow>,
+    hidden_keys: Vec<String>,
+    received_at_overrides: Vec<ReceivedAtOverride>,
+}
+
+impl LocalState {
+    pub fn save(connection: &SqliteConnection) -> Result<LocalState, Error> {
+        Ok(LocalState {
+            feed_meta: find_all_feed_meta(connection)?,
+            hidden_keys: find_all_hidden_keys(connection)?,
+            received_at_overrides: find_all_received_at_overrides(connection)?,
+        })
+    }
+
+    pub fn restore(&self, connection: &SqliteConnection) -> Result<(), Error> {
+        connection.transaction(|| {
+            replace_feed_meta(connection, &self.feed_meta)?;
+            replace_hidden_keys(connection, &self.hidden_keys)?;
+            replace_received_at_overrides(connection, &self.received_at_overrides)?;
+            Ok(())
+        })
+    }
+}
+
+/// Delete everything from the index tables, leaving the [LocalState].
+pub fn clear_indexes(connection: &SqliteConnection) -> Result<(), Error> {
+    connection.transaction(|| {
+        diesel::delete(schema::messages::table).execute(connection)?;
+        diesel::delete(schema::feed_mentions::table).execute(connection)?;
+        diesel::delete(schema::message_links::table).execute(connection)?;
+        diesel::delete(schema::keys::table).execute(connection)?;
+        diesel::delete(schema::authors::table).execute(connection)?;
+        diesel::delete(schema::indexed_offset::table).execute(connection)?;
+        Ok(())
+    })
+}
+
+#[derive(QueryableByName)]
+struct MigrationVersion {
+    #[sql_type = "diesel::sql_types::Text"]
+    version: String,
+}
+
+/// The versions of the migrations that have been run on the db, oldest first.
+pub fn find_migration_versions(connection: &SqliteConnection) -> Vec<String> {
+    // The table doesn't exist until the first migration has been run.
+    diesel::sql_query("SELECT version FROM __diesel_schema_migrations ORDER BY version")
+        .load::<MigrationVersion>(connection)
+        .map(|versions| versions.into_iter().map(|v| v.version).collect())
+        .unwrap_or_default()
+}
+
+/// Delete authors and keys that no message refers to.
+pub fn delete_orphans(connection: &SqliteConnection) -> Result<(), Error> {
+    connection.transaction(|| {
+        diesel::sql_query("DELETE FROM authors WHERE id NOT IN (SELECT author_id FROM messages)")
+            .execute(connection)?;
+        diesel::sql_query(
+            "DELETE FROM keys WHERE id NOT IN (SELECT key_id FROM messages)
+            AND id NOT IN (SELECT previous_key_id FROM messages WHERE previous_key_id IS NOT NULL)",
+        )
+        .execute(connection)?;
+        Ok(())
+    })
+}
+
+/// Index a single offset log entry. Returns `false` if it was skipped because it isn't a message.
+///
+/// Fails with [AppendError::KeyCollision], without writing anything, if its key is already indexed
+/// for a different message.
 pub fn append_item(
     connection: &SqliteConnection,
+    parser: &dyn SsbMessageParser,
     seq: FlumeSequence,
     item: &[u8],
-) -> Result<(), Error> {
-    let result = serde_json::from_slice::<SsbMessage>(item);
-
+    raw_storage: RawStorage,
+) -> Result<bool, AppendError> {
     // If there are deleted records with all bytes zerod then we should just skip this message.
-    if let Err(_) = result {
-        println!("We couldn't deserialize a message, skipping it because it's probably zerod bytes from blocking someone.");
-        return Ok(());
+    let message = match parser.parse_message(item) {
+        Some(message) => message,
+        None => return Ok(false),
+    };
+
+    // Keys and authors are stored as text, so make sure they're real ones. Garbage here would
+    // break anything that parses them back out of the db later.
+    if Multihash::from_legacy(message.key.as_bytes()).is_err()
+        || Multikey::from_legacy(message.value.author.as_bytes()).is_err()
+    {
+        return Ok(false);
     }
 
-    let message = result.unwrap();
+    let sequence = message.value.sequence as i32;
 
-    let message_key_id = find_or_create_key(&connection, &message.key)?;
-    let author_id = find_or_create_author(&connection, &message.value.author)?;
+    // The key might already be indexed. That's fine if it's the same message, but if it belongs
+    // to a different message then the key lookups would return the wrong message. Check before
+    // writing anything, so a collision leaves the indexes as they were.
+    match find_message_author_and_seq_by_key(connection, &message.key)? {
+        Some((author, seq)) if author == message.value.author && seq == sequence => {
+            return Ok(true)
+        }
+        Some(_) => return KeyCollision { key: message.key }.fail(),
+        None => (),
+    }
+
+    let message_key_id = find_or_create_key(connection, &message.key)?;
+    let author_id = find_or_create_author(connection, &message.value.author)?;
+
+    let content_type = message.value.content_type().map(|t| t.to_owned());
+    let previous_key_id = message
+        .value
+        .previous
+        .as_ref()
+        .map(|previous| find_or_create_key(connection, previous))
+        .transpose()?;
 
     insert_message(
         connection,
-        message.value.sequence as i32,
-        seq as i64,
-        message_key_id,
-        author_id,
-    )?;
+        Message {
+            flume_seq: Some(seq as i64),
+            seq: sequence,
+            key_id: message_key_id,
+            author_id,
+            byte_len: item.len() as i64,
+            content_type,
+            previous_key_id,
+            hidden: false,
+            raw: encode_raw(item, raw_storage),
+            // Prefer when we received it, it can't be faked by the author.
+            received_at: message.timestamp.or(message.value.timestamp),
+        },
+    )
+    .context(Sqlite)?;
+
+    let mentions = message
+        .value
+        .mentioned_feeds()
+        .into_iter()
+        .map(|mentioned_author| FeedMention {
+            from_flume_seq: seq as i64,
+            mentioned_author,
+        })
+        .collect::<Vec<_>>();
+    insert_feed_mentions(connection, &mentions).context(Sqlite)?;
+
+    let links = message
+        .value
+        .linked_messages()
+        .into_iter()
+        .map(|target_key| MessageLink {
+            from_flume_seq: seq as i64,
+            target_key,
+        })
+        .collect::<Vec<_>>();
+    insert_message_links(connection, &links).context(Sqlite)?;
 
-    Ok(())
+    Ok(true)
 }
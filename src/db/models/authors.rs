@@ -2,6 +2,7 @@ use crate::db::*;
 
 use crate::db::schema::authors;
 
+use super::messages::MAX_SQL_VARIABLES;
 use crate::db::schema::authors::dsl::{
     author as authors_author, authors as authors_table, id as authors_id,
 };
@@ -32,3 +33,48 @@ pub fn find_or_create_author(connection: &SqliteConnection, author: &str) -> Res
                 .map(|key| key.unwrap())
         })
 }
+
+pub fn find_author_id(connection: &SqliteConnection, author: &str) -> Result<Option<i32>, Error> {
+    authors_table
+        .select(authors_id)
+        .filter(authors_author.eq(author))
+        .first::<Option<i32>>(connection)
+        .optional()
+        .map(|id| id.flatten())
+}
+
+/// Find the ids of the given authors, keyed by author. Authors we don't know are left out.
+pub fn find_author_ids(
+    connection: &SqliteConnection,
+    authors: &[String],
+) -> Result<Vec<(String, i32)>, Error> {
+    authors
+        .chunks(MAX_SQL_VARIABLES)
+        .map(|chunk| {
+            authors_table
+                .select((authors_author, authors_id))
+                .filter(authors_author.eq_any(chunk))
+                .load::<(String, Option<i32>)>(connection)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|ids| {
+            ids.into_iter()
+                .flatten()
+                .filter_map(|(author, id)| id.map(|id| (author, id)))
+                .collect()
+        })
+}
+
+pub fn update_author(
+    connection: &SqliteConnection,
+    old_author: &str,
+    new_author: &str,
+) -> Result<usize, Error> {
+    diesel::update(authors_table.filter(authors_author.eq(old_author)))
+        .set(authors_author.eq(new_author))
+        .execute(connection)
+}
+
+pub fn find_all_authors(connection: &SqliteConnection) -> Result<Vec<String>, Error> {
+    authors_table.select(authors_author).load(connection)
+}
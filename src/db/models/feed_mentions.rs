@@ -0,0 +1,49 @@
+use crate::db::{Error, SqliteConnection};
+use diesel::prelude::*;
+
+use crate::db::schema::feed_mentions;
+use crate::db::schema::feed_mentions::dsl::{
+    feed_mentions as feed_mentions_table, from_flume_seq as feed_mentions_from_flume_seq,
+    mentioned_author as feed_mentions_mentioned_author,
+};
+use crate::db::schema::messages::dsl::{
+    flume_seq as messages_flume_seq, hidden as messages_hidden, messages as messages_table,
+};
+use diesel::insert_into;
+use diesel::sql_types::Bool;
+use flumedb::flume_view::Sequence as FlumeSequence;
+
+#[derive(Queryable, Insertable, Debug)]
+#[table_name = "feed_mentions"]
+pub struct FeedMention {
+    pub from_flume_seq: i64,
+    pub mentioned_author: String,
+}
+
+pub fn insert_feed_mentions(
+    connection: &SqliteConnection,
+    mentions: &[FeedMention],
+) -> Result<usize, Error> {
+    insert_into(feed_mentions_table)
+        .values(mentions)
+        .execute(connection)
+}
+
+pub fn find_feed_mention_flume_seqs(
+    connection: &SqliteConnection,
+    mentioned_author: &str,
+    include_hidden: bool,
+) -> Result<Vec<FlumeSequence>, Error> {
+    feed_mentions_table
+        .inner_join(messages_table.on(messages_flume_seq.eq(feed_mentions_from_flume_seq)))
+        .select(feed_mentions_from_flume_seq)
+        .filter(feed_mentions_mentioned_author.eq(mentioned_author))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .order(feed_mentions_from_flume_seq.asc())
+        .load::<i64>(connection)
+        .map(|seqs| seqs.into_iter().map(|s| s as FlumeSequence).collect())
+}
@@ -0,0 +1,41 @@
+use crate::db::{Error, SqliteConnection};
+use diesel::prelude::*;
+
+use crate::db::schema::feed_meta;
+use crate::db::schema::feed_meta::dsl::{author as feed_meta_author, feed_meta as feed_meta_table};
+use diesel::replace_into;
+
+/// Local metadata about a feed. It isn't derived from the offset log, so it's keyed by the author
+/// rather than the authors table's id, which changes when the indexes are rebuilt.
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[table_name = "feed_meta"]
+pub struct FeedMetaRow {
+    pub author: String,
+    pub nickname: Option<String>,
+    pub priority: i32,
+    pub muted: bool,
+}
+
+/// Insert the rows, replacing any already there for the same authors.
+pub fn replace_feed_meta(
+    connection: &SqliteConnection,
+    rows: &[FeedMetaRow],
+) -> Result<usize, Error> {
+    replace_into(feed_meta_table)
+        .values(rows)
+        .execute(connection)
+}
+
+pub fn find_feed_meta(
+    connection: &SqliteConnection,
+    author: &str,
+) -> Result<Option<FeedMetaRow>, Error> {
+    feed_meta_table
+        .filter(feed_meta_author.eq(author))
+        .first(connection)
+        .optional()
+}
+
+pub fn find_all_feed_meta(connection: &SqliteConnection) -> Result<Vec<FeedMetaRow>, Error> {
+    feed_meta_table.load(connection)
+}
@@ -0,0 +1,56 @@
+use crate::db::{Error, SqliteConnection};
+use diesel::prelude::*;
+
+use crate::db::schema::hidden_messages::dsl::{
+    hidden_messages as hidden_messages_table, key as hidden_messages_key,
+};
+use diesel::replace_into;
+use flumedb::flume_view::Sequence as FlumeSequence;
+
+/// Remember whether the message with the given key is hidden. It isn't derived from the offset
+/// log, so it's keyed by the message key rather than the keys table's id, which changes when the
+/// indexes are rebuilt.
+pub fn set_key_hidden(connection: &SqliteConnection, key: &str, hidden: bool) -> Result<(), Error> {
+    if hidden {
+        replace_into(hidden_messages_table)
+            .values(hidden_messages_key.eq(key))
+            .execute(connection)?;
+    } else {
+        diesel::delete(hidden_messages_table.filter(hidden_messages_key.eq(key)))
+            .execute(connection)?;
+    }
+    Ok(())
+}
+
+pub fn find_all_hidden_keys(connection: &SqliteConnection) -> Result<Vec<String>, Error> {
+    hidden_messages_table
+        .select(hidden_messages_key)
+        .load(connection)
+}
+
+/// Remember that the messages with the given keys are hidden.
+pub fn replace_hidden_keys(connection: &SqliteConnection, keys: &[String]) -> Result<usize, Error> {
+    let rows = keys
+        .iter()
+        .map(|key| hidden_messages_key.eq(key))
+        .collect::<Vec<_>>();
+
+    replace_into(hidden_messages_table)
+        .values(&rows)
+        .execute(connection)
+}
+
+/// Hide the messages indexed from `since_flume_seq` on whose keys were hidden.
+pub fn hide_messages_since(
+    connection: &SqliteConnection,
+    since_flume_seq: FlumeSequence,
+) -> Result<usize, Error> {
+    diesel::sql_query(
+        "UPDATE messages SET hidden = 1
+        WHERE flume_seq >= ? AND key_id IN (
+            SELECT keys.id FROM keys JOIN hidden_messages ON hidden_messages.key = keys.key
+        )",
+    )
+    .bind::<diesel::sql_types::BigInt, _>(since_flume_seq as i64)
+    .execute(connection)
+}
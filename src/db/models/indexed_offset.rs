@@ -0,0 +1,40 @@
+use super::messages::get_latest;
+use crate::db::{Error, SqliteConnection};
+use diesel::prelude::*;
+
+use crate::db::schema::indexed_offset::dsl::{
+    flume_seq as indexed_offset_flume_seq, id as indexed_offset_id,
+    indexed_offset as indexed_offset_table,
+};
+use diesel::replace_into;
+use flumedb::flume_view::Sequence as FlumeSequence;
+
+/// Record that everything up to and including `offset` has been indexed.
+pub fn set_indexed_offset(
+    connection: &SqliteConnection,
+    offset: FlumeSequence,
+) -> Result<(), Error> {
+    replace_into(indexed_offset_table)
+        .values((
+            indexed_offset_id.eq(0),
+            indexed_offset_flume_seq.eq(offset as i64),
+        ))
+        .execute(connection)
+        .map(|_| ())
+}
+
+/// The offset of the last entry that has been indexed, whether or not it was a message, or `None`
+/// if nothing has been.
+///
+/// Dbs indexed before the offset was recorded only know about the messages they hold, so this is
+/// never behind the latest message.
+pub fn find_indexed_offset(connection: &SqliteConnection) -> Result<Option<FlumeSequence>, Error> {
+    let recorded = indexed_offset_table
+        .select(indexed_offset_flume_seq)
+        .first::<i64>(connection)
+        .optional()?
+        .map(|offset| offset as FlumeSequence);
+    let latest = get_latest(connection)?;
+
+    Ok(recorded.max(latest))
+}
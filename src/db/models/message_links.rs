@@ -0,0 +1,97 @@
+use crate::db::{Error, SqliteConnection};
+use diesel::prelude::*;
+
+use super::messages::MAX_SQL_VARIABLES;
+use crate::db::schema::authors::dsl::{
+    author as authors_author, authors as authors_table, id as authors_id,
+};
+use crate::db::schema::message_links;
+use crate::db::schema::message_links::dsl::{
+    from_flume_seq as message_links_from_flume_seq, message_links as message_links_table,
+    target_key as message_links_target_key,
+};
+use crate::db::schema::messages::dsl::{
+    author_id as messages_author_id, flume_seq as messages_flume_seq, hidden as messages_hidden,
+    messages as messages_table,
+};
+use diesel::insert_into;
+use diesel::sql_types::Bool;
+use flumedb::flume_view::Sequence as FlumeSequence;
+
+#[derive(Queryable, Insertable, Debug)]
+#[table_name = "message_links"]
+pub struct MessageLink {
+    pub from_flume_seq: i64,
+    pub target_key: String,
+}
+
+pub fn insert_message_links(
+    connection: &SqliteConnection,
+    links: &[MessageLink],
+) -> Result<usize, Error> {
+    insert_into(message_links_table)
+        .values(links)
+        .execute(connection)
+}
+
+/// Find the messages that link to any of the `target_keys`, in the order they were appended. A
+/// message linking to several of the targets is only found once.
+pub fn find_links_to_any_flume_seqs(
+    connection: &SqliteConnection,
+    target_keys: &[String],
+    include_hidden: bool,
+) -> Result<Vec<FlumeSequence>, Error> {
+    // Filtering out hidden messages takes two variables.
+    let mut seqs = target_keys
+        .chunks(MAX_SQL_VARIABLES - 2)
+        .map(|chunk| {
+            message_links_table
+                .inner_join(messages_table.on(messages_flume_seq.eq(message_links_from_flume_seq)))
+                .select(message_links_from_flume_seq)
+                .filter(message_links_target_key.eq_any(chunk))
+                .filter(
+                    messages_hidden
+                        .eq(false)
+                        .or(include_hidden.into_sql::<Bool>()),
+                )
+                .load::<i64>(connection)
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .concat();
+
+    seqs.sort_unstable();
+    seqs.dedup();
+    Ok(seqs.into_iter().map(|s| s as FlumeSequence).collect())
+}
+
+/// Find the messages by any of the `authors` that link to `target_key`, in the order they were
+/// appended.
+pub fn find_links_to_from_authors_flume_seqs(
+    connection: &SqliteConnection,
+    target_key: &str,
+    authors: &[String],
+    include_hidden: bool,
+) -> Result<Vec<FlumeSequence>, Error> {
+    // Filtering on the target takes one variable, and filtering out hidden messages takes two.
+    let mut seqs = authors
+        .chunks(MAX_SQL_VARIABLES - 3)
+        .map(|chunk| {
+            message_links_table
+                .inner_join(messages_table.on(messages_flume_seq.eq(message_links_from_flume_seq)))
+                .inner_join(authors_table.on(messages_author_id.nullable().eq(authors_id)))
+                .select(message_links_from_flume_seq)
+                .filter(message_links_target_key.eq(target_key))
+                .filter(authors_author.eq_any(chunk))
+                .filter(
+                    messages_hidden
+                        .eq(false)
+                        .or(include_hidden.into_sql::<Bool>()),
+                )
+                .load::<i64>(connection)
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .concat();
+
+    seqs.sort_unstable();
+    Ok(seqs.into_iter().map(|s| s as FlumeSequence).collect())
+}
@@ -4,15 +4,23 @@ use crate::db::{Error, SqliteConnection};
 use crate::db::schema::authors::dsl::{
     author as authors_author, authors as authors_table, id as authors_id,
 };
+use crate::db::schema::feed_mentions::dsl::{
+    feed_mentions as feed_mentions_table, from_flume_seq as feed_mentions_from_flume_seq,
+};
 use crate::db::schema::keys::dsl::{id as keys_id, key as keys_key, keys as keys_table};
+use crate::db::schema::message_links::dsl::{
+    from_flume_seq as message_links_from_flume_seq, message_links as message_links_table,
+};
 use crate::db::schema::messages;
 use crate::db::schema::messages::dsl::{
-    author_id as messages_author_id, flume_seq as messages_flume_seq, key_id as messages_key_id,
-    messages as messages_table, seq as messages_seq,
+    author_id as messages_author_id, content_type as messages_content_type,
+    flume_seq as messages_flume_seq, hidden as messages_hidden, key_id as messages_key_id,
+    messages as messages_table, raw as messages_raw, received_at as messages_received_at,
+    seq as messages_seq,
 };
-use diesel::dsl::max;
 use diesel::insert_into;
 use diesel::prelude::*;
+use diesel::sql_types::Bool;
 use flumedb::flume_view::Sequence as FlumeSequence;
 
 #[derive(Queryable, Insertable, Associations, Identifiable, Debug, Default)]
@@ -24,82 +32,745 @@ pub struct Message {
     pub seq: i32,
     pub key_id: i32,
     pub author_id: i32,
+    pub byte_len: i64,
+    pub content_type: Option<String>,
+    pub previous_key_id: Option<i32>,
+    pub hidden: bool,
+    pub raw: Option<Vec<u8>>,
+    pub received_at: Option<f64>,
 }
 
-pub fn get_latest(connection: &SqliteConnection) -> Result<Option<f64>, Error> {
+pub fn get_latest(connection: &SqliteConnection) -> Result<Option<FlumeSequence>, Error> {
     messages_table
-        .select(max(messages_flume_seq))
+        .select(diesel::dsl::max(messages_flume_seq))
         .first(connection)
-        .map(|res: Option<i64>| res.map(|val| val as f64))
+        .map(|res: Option<i64>| res.map(|val| val as FlumeSequence))
 }
 
-pub fn insert_message(
-    connection: &SqliteConnection,
-    seq: i32,
-    flume_seq: i64,
-    message_key_id: i32,
-    author_id: i32,
-) -> Result<usize, Error> {
-    let message = Message {
-        flume_seq: Some(flume_seq),
-        key_id: message_key_id,
-        seq,
-        author_id,
-    };
+pub fn get_oldest(connection: &SqliteConnection) -> Result<Option<FlumeSequence>, Error> {
+    messages_table
+        .select(diesel::dsl::min(messages_flume_seq))
+        .first(connection)
+        .map(|res: Option<i64>| res.map(|val| val as FlumeSequence))
+}
 
+pub fn insert_message(connection: &SqliteConnection, message: Message) -> Result<usize, Error> {
     insert_into(messages_table)
         .values(message)
         .execute(connection)
 }
 
+pub fn find_flume_seq_at_index(
+    connection: &SqliteConnection,
+    index: u64,
+) -> Result<Option<FlumeSequence>, Error> {
+    messages_table
+        .select(messages_flume_seq)
+        .order(messages_flume_seq.asc())
+        .limit(1)
+        .offset(index as i64)
+        .first::<i64>(connection)
+        .optional()
+        .map(|flume_seq| flume_seq.map(|flume_seq| flume_seq as FlumeSequence))
+}
+
+pub fn set_message_hidden(
+    connection: &SqliteConnection,
+    key: &str,
+    hidden: bool,
+) -> Result<usize, Error> {
+    let key_id = keys_table
+        .select(keys_id)
+        .filter(keys_key.eq(key))
+        .first::<Option<i32>>(connection)?;
+
+    diesel::update(messages_table.filter(messages_key_id.nullable().eq(key_id)))
+        .set(messages_hidden.eq(hidden))
+        .execute(connection)
+}
+
+pub fn set_feed_received_at(
+    connection: &SqliteConnection,
+    author: &str,
+    received_at: f64,
+) -> Result<usize, Error> {
+    let author_id = authors_table
+        .select(authors_id)
+        .filter(authors_author.eq(author))
+        .first::<Option<i32>>(connection)
+        .optional()?
+        .flatten();
+
+    diesel::update(messages_table.filter(messages_author_id.nullable().eq(author_id)))
+        .set(messages_received_at.eq(received_at))
+        .execute(connection)
+}
+
 pub fn find_message_flume_seq_by_key(
     connection: &SqliteConnection,
     key: &str,
+    include_hidden: bool,
 ) -> Result<FlumeSequence, Error> {
     let flume_seq = keys_table
         .inner_join(messages_table.on(messages_key_id.nullable().eq(keys_id)))
         .select(messages_flume_seq)
-        .filter(keys_key.eq(key.clone()))
+        .filter(keys_key.eq(key))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
         .first::<i64>(connection)? as u64;
 
     Ok(flume_seq)
 }
 
+pub fn find_message_raw_by_flume_seq(
+    connection: &SqliteConnection,
+    flume_seq: FlumeSequence,
+) -> Result<Option<Vec<u8>>, Error> {
+    messages_table
+        .select(messages_raw)
+        .filter(messages_flume_seq.eq(flume_seq as i64))
+        .first::<Option<Vec<u8>>>(connection)
+        .optional()
+        .map(|raw| raw.flatten())
+}
+
+pub fn find_message_author_by_key(
+    connection: &SqliteConnection,
+    key: &str,
+    include_hidden: bool,
+) -> Result<Option<String>, Error> {
+    keys_table
+        .inner_join(messages_table.on(messages_key_id.nullable().eq(keys_id)))
+        .inner_join(authors_table.on(messages_author_id.nullable().eq(authors_id)))
+        .select(authors_author)
+        .filter(keys_key.eq(key))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .first(connection)
+        .optional()
+}
+
+pub fn find_message_seq_by_key(
+    connection: &SqliteConnection,
+    key: &str,
+    include_hidden: bool,
+) -> Result<Option<i32>, Error> {
+    keys_table
+        .inner_join(messages_table.on(messages_key_id.nullable().eq(keys_id)))
+        .select(messages_seq)
+        .filter(keys_key.eq(key))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .first(connection)
+        .optional()
+}
+
+/// Turn an optional query limit into one for sqlite.
+///
+/// Same as js: a negative limit (eg. -1) means no limit, but 0 means nothing.
+fn normalize_limit(limit: Option<i64>) -> i64 {
+    match limit {
+        Some(limit) if limit >= 0 => limit,
+        _ => i64::MAX,
+    }
+}
+
+/// Sqlite's default limit on the number of `?` variables in one query.
+pub const MAX_SQL_VARIABLES: usize = 999;
+
+/// Of the given message keys, find the ones we have messages for.
+///
+/// The keys table also has keys of messages we only know as a `previous`, so this checks the
+/// messages table.
+pub fn find_message_keys_present(
+    connection: &SqliteConnection,
+    keys: &[String],
+) -> Result<Vec<String>, Error> {
+    keys.chunks(MAX_SQL_VARIABLES)
+        .map(|chunk| {
+            keys_table
+                .inner_join(messages_table.on(messages_key_id.nullable().eq(keys_id)))
+                .select(keys_key)
+                .filter(keys_key.eq_any(chunk))
+                .load::<String>(connection)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|present| present.concat())
+}
+
+pub fn find_message_author_and_seq_by_key(
+    connection: &SqliteConnection,
+    key: &str,
+) -> Result<Option<(String, i32)>, Error> {
+    keys_table
+        .inner_join(messages_table.on(messages_key_id.nullable().eq(keys_id)))
+        .inner_join(authors_table.on(messages_author_id.nullable().eq(authors_id)))
+        .select((authors_author, messages_seq))
+        .filter(keys_key.eq(key))
+        .first(connection)
+        .optional()
+}
+
 pub fn find_message_flume_seq_by_author_and_sequence(
     connection: &SqliteConnection,
     author: &str,
     sequence: i32,
+    include_hidden: bool,
 ) -> Result<Option<i64>, Error> {
     authors_table
         .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
         .select(messages_flume_seq)
         .filter(messages_seq.eq(sequence))
         .filter(authors_author.eq(author))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
         .first(connection)
         .optional()
 }
+pub fn find_feed_first_flume_seq(
+    connection: &SqliteConnection,
+    author: &str,
+    include_hidden: bool,
+) -> Result<Option<i64>, Error> {
+    authors_table
+        .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
+        .select(messages_flume_seq)
+        .filter(authors_author.eq(author))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .order(messages_seq.asc())
+        .first(connection)
+        .optional()
+}
+pub fn delete_feed_messages_newer_than(
+    connection: &SqliteConnection,
+    author: &str,
+    sequence: i32,
+) -> Result<usize, Error> {
+    let author_id = authors_table
+        .select(authors_id)
+        .filter(authors_author.eq(author))
+        .first::<Option<i32>>(connection)
+        .optional()?
+        .flatten();
+
+    let removed = messages_table
+        .filter(messages_author_id.nullable().eq(author_id))
+        .filter(messages_seq.gt(sequence));
+
+    diesel::delete(
+        feed_mentions_table
+            .filter(feed_mentions_from_flume_seq.eq_any(removed.select(messages_flume_seq))),
+    )
+    .execute(connection)?;
+    diesel::delete(
+        message_links_table
+            .filter(message_links_from_flume_seq.eq_any(removed.select(messages_flume_seq))),
+    )
+    .execute(connection)?;
+
+    diesel::delete(removed).execute(connection)
+}
+// The messages of feeds that have had messages indexed from offset `?` on, that are at least `?`
+// sequences behind the latest in the same feed. The latest is looked up once per feed using the
+// (author_id, seq) index, rather than counting the newer messages for every row.
+const EVICTABLE_FLUME_SEQS: &str = "SELECT m.flume_seq FROM messages AS m
+    JOIN (
+        SELECT author_id, (
+            SELECT MAX(seq) FROM messages AS latest WHERE latest.author_id = feeds.author_id
+        ) AS max_seq
+        FROM (SELECT DISTINCT author_id FROM messages WHERE flume_seq >= ?) AS feeds
+    ) AS limits ON m.author_id = limits.author_id
+    WHERE m.seq <= limits.max_seq - ?";
+
+/// Delete the oldest messages of each feed with messages indexed from `since_flume_seq` on, so
+/// that each keeps at most `max_per_feed` messages. Returns how many messages were deleted.
+pub fn delete_feed_messages_over_limit(
+    connection: &SqliteConnection,
+    max_per_feed: usize,
+    since_flume_seq: FlumeSequence,
+) -> Result<usize, Error> {
+    ["feed_mentions", "message_links"]
+        .iter()
+        .try_for_each(|table| {
+            diesel::sql_query(format!(
+                "DELETE FROM {} WHERE from_flume_seq IN ({})",
+                table, EVICTABLE_FLUME_SEQS
+            ))
+            .bind::<diesel::sql_types::BigInt, _>(since_flume_seq as i64)
+            .bind::<diesel::sql_types::BigInt, _>(max_per_feed as i64)
+            .execute(connection)
+            .map(|_| ())
+        })?;
+
+    diesel::sql_query(format!(
+        "DELETE FROM messages WHERE flume_seq IN ({})",
+        EVICTABLE_FLUME_SEQS
+    ))
+    .bind::<diesel::sql_types::BigInt, _>(since_flume_seq as i64)
+    .bind::<diesel::sql_types::BigInt, _>(max_per_feed as i64)
+    .execute(connection)
+}
+
+pub fn find_feed_seqs_between(
+    connection: &SqliteConnection,
+    author: &str,
+    start: i32,
+    end: i32,
+) -> Result<Vec<i32>, Error> {
+    authors_table
+        .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
+        .select(messages_seq)
+        .filter(authors_author.eq(author))
+        .filter(messages_seq.between(start, end))
+        .load(connection)
+}
+
+pub fn find_feed_flume_seqs_between(
+    connection: &SqliteConnection,
+    author: &str,
+    start: i32,
+    end: i32,
+    include_hidden: bool,
+) -> Result<Vec<FlumeSequence>, Error> {
+    let flume_seqs = authors_table
+        .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
+        .select(messages_flume_seq)
+        .filter(authors_author.eq(author))
+        .filter(messages_seq.between(start, end))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .order(messages_seq.asc())
+        .load(connection)?
+        .iter()
+        .map(|s: &i64| *s as FlumeSequence)
+        .collect();
+
+    Ok(flume_seqs)
+}
+
+pub fn count_feed_messages_received_since(
+    connection: &SqliteConnection,
+    author: &str,
+    since: f64,
+) -> Result<i64, Error> {
+    authors_table
+        .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
+        .select(diesel::dsl::count_star())
+        .filter(authors_author.eq(author))
+        .filter(messages_received_at.ge(since))
+        .first(connection)
+}
+
 pub fn find_feed_latest_seq(
     connection: &SqliteConnection,
     author: &str,
 ) -> Result<Option<i32>, Error> {
+    // This is the hottest query in replication scheduling. Sqlite answers it from the
+    // (author_id, seq) index without touching the messages table, see the
+    // `feed_latest_sequence` benchmark.
     authors_table
         .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
-        .select(max(messages_seq))
-        .filter(authors_author.eq(author.clone()))
+        .select(diesel::dsl::max(messages_seq))
+        .filter(authors_author.eq(author))
         .first(connection)
 }
+/// Find the flume seq of the message with the highest seq in each of the given feeds, keyed by
+/// author. Feeds we have no messages for are left out.
+pub fn find_feeds_latest_flume_seqs(
+    connection: &SqliteConnection,
+    authors: &[String],
+    include_hidden: bool,
+) -> Result<Vec<(String, i64)>, Error> {
+    // Filtering out hidden messages takes two variables.
+    authors
+        .chunks(MAX_SQL_VARIABLES - 2)
+        .map(|chunk| {
+            // Sqlite takes the bare `flume_seq` from the row with the `MAX(seq)` of each group.
+            authors_table
+                .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
+                .filter(authors_author.eq_any(chunk))
+                .filter(
+                    messages_hidden
+                        .eq(false)
+                        .or(include_hidden.into_sql::<Bool>()),
+                )
+                .group_by(authors_id)
+                .select((
+                    authors_author,
+                    messages_flume_seq,
+                    diesel::dsl::sql::<diesel::sql_types::Integer>("MAX(messages.seq)"),
+                ))
+                .load::<(String, i64, i32)>(connection)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|latest| {
+            latest
+                .into_iter()
+                .flatten()
+                .map(|(author, flume_seq, _)| (author, flume_seq))
+                .collect()
+        })
+}
+/// Find the highest seq we have of each of the given feeds, keyed by author. Feeds we have no
+/// messages for are left out.
+pub fn find_feeds_latest_seqs(
+    connection: &SqliteConnection,
+    authors: &[String],
+) -> Result<Vec<(String, i32)>, Error> {
+    authors
+        .chunks(MAX_SQL_VARIABLES)
+        .map(|chunk| {
+            authors_table
+                .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
+                .filter(authors_author.eq_any(chunk))
+                .group_by(authors_id)
+                .select((
+                    authors_author,
+                    diesel::dsl::sql::<diesel::sql_types::Integer>("MAX(messages.seq)"),
+                ))
+                .load::<(String, i32)>(connection)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|latest| latest.concat())
+}
+pub fn find_feed_is_complete(connection: &SqliteConnection, author: &str) -> Result<bool, Error> {
+    let feed_messages =
+        authors_table.inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)));
+
+    let count: i64 = feed_messages
+        .select(diesel::dsl::count_star())
+        .filter(authors_author.eq(author))
+        .first(connection)?;
+    let min_seq: Option<i32> = feed_messages
+        .select(diesel::dsl::min(messages_seq))
+        .filter(authors_author.eq(author))
+        .first(connection)?;
+    let max_seq: Option<i32> = feed_messages
+        .select(diesel::dsl::max(messages_seq))
+        .filter(authors_author.eq(author))
+        .first(connection)?;
+
+    Ok(min_seq == Some(1) && max_seq.map(i64::from) == Some(count))
+}
+#[derive(QueryableByName, Debug)]
+pub struct ChainLink {
+    #[sql_type = "diesel::sql_types::Integer"]
+    pub seq: i32,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Text>"]
+    pub previous: Option<String>,
+    #[sql_type = "diesel::sql_types::Text"]
+    pub key: String,
+}
+
+pub fn find_feed_chain(
+    connection: &SqliteConnection,
+    author: &str,
+) -> Result<Vec<ChainLink>, Error> {
+    diesel::sql_query(
+        "SELECT messages.seq AS seq, previous_keys.key AS previous, keys.key AS key
+        FROM messages
+        JOIN authors ON messages.author_id = authors.id
+        JOIN keys ON messages.key_id = keys.id
+        LEFT JOIN keys AS previous_keys ON messages.previous_key_id = previous_keys.id
+        WHERE authors.author = ?
+        ORDER BY messages.seq",
+    )
+    .bind::<diesel::sql_types::Text, _>(author)
+    .load(connection)
+}
+
+#[derive(QueryableByName, Debug)]
+pub struct ContentTypeCount {
+    #[sql_type = "diesel::sql_types::Text"]
+    pub content_type: String,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub count: i64,
+}
+
+pub fn find_content_type_counts(
+    connection: &SqliteConnection,
+) -> Result<Vec<ContentTypeCount>, Error> {
+    diesel::sql_query(
+        "SELECT content_type, COUNT(*) AS count
+        FROM messages
+        WHERE content_type IS NOT NULL
+        GROUP BY content_type
+        ORDER BY count DESC, content_type",
+    )
+    .load(connection)
+}
+
+#[derive(QueryableByName, Debug)]
+pub struct AuthorByteLen {
+    #[sql_type = "diesel::sql_types::Text"]
+    pub author: String,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub byte_len: i64,
+}
+
+pub fn find_feed_byte_lens(connection: &SqliteConnection) -> Result<Vec<AuthorByteLen>, Error> {
+    diesel::sql_query(
+        "SELECT authors.author AS author, SUM(messages.byte_len) AS byte_len
+        FROM messages JOIN authors ON messages.author_id = authors.id
+        GROUP BY authors.id",
+    )
+    .load(connection)
+}
+#[derive(QueryableByName, Debug)]
+struct IsLatest {
+    #[sql_type = "diesel::sql_types::Bool"]
+    is_latest: bool,
+}
+
+pub fn find_message_is_latest_by_key(
+    connection: &SqliteConnection,
+    key: &str,
+    include_hidden: bool,
+) -> Result<Option<bool>, Error> {
+    diesel::sql_query(
+        "SELECT messages.seq = (
+            SELECT MAX(feed.seq) FROM messages AS feed WHERE feed.author_id = messages.author_id
+        ) AS is_latest
+        FROM messages JOIN keys ON messages.key_id = keys.id
+        WHERE keys.key = ? AND (messages.hidden = 0 OR ?)",
+    )
+    .bind::<diesel::sql_types::Text, _>(key)
+    .bind::<Bool, _>(include_hidden)
+    .get_result::<IsLatest>(connection)
+    .optional()
+    .map(|res| res.map(|res| res.is_latest))
+}
+
+#[derive(QueryableByName, Debug)]
+pub struct ContentTypeFlumeSeq {
+    #[sql_type = "diesel::sql_types::Text"]
+    pub content_type: String,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub flume_seq: i64,
+}
+
+/// For each content type in the given feed, find the message with that type with the highest seq.
+pub fn find_feed_latest_flume_seq_by_type(
+    connection: &SqliteConnection,
+    author: &str,
+    include_hidden: bool,
+) -> Result<Vec<ContentTypeFlumeSeq>, Error> {
+    diesel::sql_query(
+        "SELECT m.content_type AS content_type, m.flume_seq AS flume_seq
+        FROM messages AS m JOIN authors ON m.author_id = authors.id
+        WHERE authors.author = ? AND m.content_type IS NOT NULL AND (m.hidden = 0 OR ?)
+        AND m.seq = (
+            SELECT MAX(newest.seq) FROM messages AS newest
+            WHERE newest.author_id = m.author_id AND newest.content_type = m.content_type
+            AND (newest.hidden = 0 OR ?)
+        )",
+    )
+    .bind::<diesel::sql_types::Text, _>(author)
+    .bind::<Bool, _>(include_hidden)
+    .bind::<Bool, _>(include_hidden)
+    .load(connection)
+}
+
+pub fn find_flume_seqs_by_type(
+    connection: &SqliteConnection,
+    content_type: &str,
+    include_hidden: bool,
+) -> Result<Vec<FlumeSequence>, Error> {
+    let flume_seqs = messages_table
+        .select(messages_flume_seq)
+        .filter(messages_content_type.eq(content_type))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .order(messages_flume_seq.asc())
+        .load(connection)?
+        .iter()
+        .map(|s: &i64| *s as FlumeSequence)
+        .collect();
+
+    Ok(flume_seqs)
+}
+
+pub fn find_root_flume_seqs(
+    connection: &SqliteConnection,
+    include_hidden: bool,
+) -> Result<Vec<FlumeSequence>, Error> {
+    let flume_seqs = messages_table
+        .select(messages_flume_seq)
+        .filter(messages_seq.eq(1))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .order(messages_flume_seq.asc())
+        .load(connection)?
+        .iter()
+        .map(|s: &i64| *s as FlumeSequence)
+        .collect();
+
+    Ok(flume_seqs)
+}
+
+/// Get the offset, key and author of every message at or after `flume_seq`, in the order they
+/// were appended. Only reads the index tables.
+pub fn find_index_events_since(
+    connection: &SqliteConnection,
+    flume_seq: FlumeSequence,
+    include_hidden: bool,
+) -> Result<Vec<(FlumeSequence, String, String)>, Error> {
+    let events = messages_table
+        .inner_join(keys_table.on(messages_key_id.nullable().eq(keys_id)))
+        .inner_join(authors_table.on(messages_author_id.nullable().eq(authors_id)))
+        .select((messages_flume_seq, keys_key, authors_author))
+        .filter(messages_flume_seq.ge(flume_seq as i64))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .order(messages_flume_seq.asc())
+        .load::<(i64, String, String)>(connection)?
+        .into_iter()
+        .map(|(seq, key, author)| (seq as FlumeSequence, key, author))
+        .collect();
+
+    Ok(events)
+}
+
+pub fn find_feed_flume_seqs_after(
+    connection: &SqliteConnection,
+    author: &str,
+    after: Option<FlumeSequence>,
+    limit: i64,
+    include_hidden: bool,
+) -> Result<Vec<FlumeSequence>, Error> {
+    let flume_seqs = authors_table
+        .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
+        .select(messages_flume_seq)
+        .filter(authors_author.eq(author))
+        .filter(messages_flume_seq.gt(after.map(|after| after as i64).unwrap_or(-1)))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .order(messages_flume_seq.asc())
+        .limit(limit)
+        .load(connection)?
+        .iter()
+        .map(|s: &i64| *s as FlumeSequence)
+        .collect();
+
+    Ok(flume_seqs)
+}
+
+pub fn find_flume_seqs_matching(
+    connection: &SqliteConnection,
+    author: Option<&str>,
+    content_type: Option<&str>,
+    received_since: Option<f64>,
+    limit: Option<i64>,
+    include_hidden: bool,
+) -> Result<Vec<FlumeSequence>, Error> {
+    let mut query = authors_table
+        .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
+        .select(messages_flume_seq)
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .order(messages_flume_seq.asc())
+        .limit(normalize_limit(limit))
+        .into_boxed();
+
+    if let Some(author) = author {
+        query = query.filter(authors_author.eq(author));
+    }
+    if let Some(content_type) = content_type {
+        query = query.filter(messages_content_type.eq(content_type));
+    }
+    if let Some(received_since) = received_since {
+        query = query.filter(messages_received_at.ge(received_since));
+    }
+
+    let flume_seqs = query
+        .load(connection)?
+        .iter()
+        .map(|s: &i64| *s as FlumeSequence)
+        .collect();
+
+    Ok(flume_seqs)
+}
+
+pub fn find_feed_flume_seqs_by_type(
+    connection: &SqliteConnection,
+    author: &str,
+    content_type: &str,
+    limit: Option<i64>,
+    include_hidden: bool,
+) -> Result<Vec<FlumeSequence>, Error> {
+    let flume_seqs = authors_table
+        .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
+        .select(messages_flume_seq)
+        .filter(authors_author.eq(author))
+        .filter(messages_content_type.eq(content_type))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .order(messages_seq.desc())
+        .limit(normalize_limit(limit))
+        .load(connection)?
+        .iter()
+        .map(|s: &i64| *s as FlumeSequence)
+        .collect();
+
+    Ok(flume_seqs)
+}
 pub fn find_feed_flume_seqs_newer_than(
     connection: &SqliteConnection,
     author: &str,
     sequence: i32,
     limit: Option<i64>,
+    include_hidden: bool,
 ) -> Result<Vec<FlumeSequence>, Error> {
     let flume_seqs = authors_table
         .inner_join(messages_table.on(messages_author_id.nullable().eq(authors_id)))
         .select(messages_flume_seq)
-        .filter(messages_seq.gt(sequence))
-        .filter(authors_author.eq(author.clone()))
-        .limit(limit.unwrap_or(std::i64::MAX))
+        // Feeds start at 1, so any sequence below that (0 or negative) means the whole feed.
+        .filter(messages_seq.gt(sequence.max(0)))
+        .filter(authors_author.eq(author))
+        .filter(
+            messages_hidden
+                .eq(false)
+                .or(include_hidden.into_sql::<Bool>()),
+        )
+        .order(messages_seq.asc())
+        .limit(normalize_limit(limit))
         .load(connection)?
         .iter()
         .map(|s: &i64| *s as FlumeSequence)
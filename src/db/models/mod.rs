@@ -1,3 +1,9 @@
 pub mod authors;
+pub mod feed_mentions;
+pub mod feed_meta;
+pub mod hidden_messages;
+pub mod indexed_offset;
 pub mod keys;
+pub mod message_links;
 pub mod messages;
+pub mod received_at_overrides;
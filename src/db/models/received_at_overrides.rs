@@ -0,0 +1,72 @@
+use crate::db::{Error, SqliteConnection};
+use diesel::prelude::*;
+
+use crate::db::schema::received_at_overrides;
+use crate::db::schema::received_at_overrides::dsl::received_at_overrides as received_at_overrides_table;
+use diesel::replace_into;
+use diesel::sql_types::{BigInt, Double, Text};
+use flumedb::flume_view::Sequence as FlumeSequence;
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[table_name = "received_at_overrides"]
+pub struct ReceivedAtOverride {
+    pub key: String,
+    pub received_at: f64,
+}
+
+pub fn find_all_received_at_overrides(
+    connection: &SqliteConnection,
+) -> Result<Vec<ReceivedAtOverride>, Error> {
+    received_at_overrides_table.load(connection)
+}
+
+/// Insert the rows, replacing any already there for the same keys.
+pub fn replace_received_at_overrides(
+    connection: &SqliteConnection,
+    rows: &[ReceivedAtOverride],
+) -> Result<usize, Error> {
+    replace_into(received_at_overrides_table)
+        .values(rows)
+        .execute(connection)
+}
+
+/// Remember that every message we have by `author` was received at `received_at`. It isn't
+/// derived from the offset log, so it's keyed by the message key rather than the keys table's id,
+/// which changes when the indexes are rebuilt.
+pub fn override_feed_received_at(
+    connection: &SqliteConnection,
+    author: &str,
+    received_at: f64,
+) -> Result<usize, Error> {
+    diesel::sql_query(
+        "REPLACE INTO received_at_overrides (key, received_at)
+        SELECT keys.key, ? FROM messages
+        JOIN keys ON messages.key_id = keys.id
+        JOIN authors ON messages.author_id = authors.id
+        WHERE authors.author = ?",
+    )
+    .bind::<Double, _>(received_at)
+    .bind::<Text, _>(author)
+    .execute(connection)
+}
+
+/// Set when the messages indexed from `since_flume_seq` on were received, for the ones it was
+/// overridden for.
+pub fn override_received_at_since(
+    connection: &SqliteConnection,
+    since_flume_seq: FlumeSequence,
+) -> Result<usize, Error> {
+    diesel::sql_query(
+        "UPDATE messages SET received_at = (
+            SELECT overrides.received_at FROM received_at_overrides AS overrides
+            JOIN keys ON overrides.key = keys.key
+            WHERE keys.id = messages.key_id
+        )
+        WHERE flume_seq >= ? AND key_id IN (
+            SELECT keys.id FROM keys
+            JOIN received_at_overrides AS overrides ON overrides.key = keys.key
+        )",
+    )
+    .bind::<BigInt, _>(since_flume_seq as i64)
+    .execute(connection)
+}
@@ -0,0 +1,180 @@
+//! The queries [PostgresSsbDb](crate::PostgresSsbDb) indexes with. They use the same tables as
+//! the sqlite ones, made by the migrations in `migrations_postgres`.
+use crate::db::schema::{authors, indexed_offset, keys, messages};
+use crate::db::{AppendError, Error};
+use crate::ssb_message::SsbMessageParser;
+use diesel::insert_into;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use flumedb::flume_view::Sequence as FlumeSequence;
+
+pub fn find_or_create_author(connection: &PgConnection, author: &str) -> Result<i32, Error> {
+    insert_into(authors::table)
+        .values(authors::author.eq(author))
+        .on_conflict_do_nothing()
+        .execute(connection)?;
+
+    authors::table
+        .select(authors::id)
+        .filter(authors::author.eq(author))
+        .first::<Option<i32>>(connection)?
+        .ok_or(Error::NotFound)
+}
+
+pub fn find_or_create_key(connection: &PgConnection, key: &str) -> Result<i32, Error> {
+    insert_into(keys::table)
+        .values(keys::key.eq(key))
+        .on_conflict_do_nothing()
+        .execute(connection)?;
+
+    keys::table
+        .select(keys::id)
+        .filter(keys::key.eq(key))
+        .first::<Option<i32>>(connection)?
+        .ok_or(Error::NotFound)
+}
+
+/// Index the entry at `offset`. Returns `false` if it was skipped because it isn't a message.
+///
+/// Like the sqlite [append_item](crate::db::append_item), this fails with
+/// [AppendError::KeyCollision], without writing anything, if its key is already indexed for a
+/// different message.
+pub fn append_item(
+    connection: &PgConnection,
+    parser: &dyn SsbMessageParser,
+    offset: FlumeSequence,
+    item: &[u8],
+) -> Result<bool, AppendError> {
+    let message = match parser.parse_message(item) {
+        Some(message) => message,
+        None => return Ok(false),
+    };
+    let seq = message.value.sequence as i32;
+
+    // The same message appended again is already indexed.
+    match find_message_author_and_seq_by_key(connection, &message.key)? {
+        Some((author, existing_seq)) if author == message.value.author && existing_seq == seq => {
+            return Ok(true)
+        }
+        Some(_) => return Err(AppendError::KeyCollision { key: message.key }),
+        None => (),
+    }
+
+    let author_id = find_or_create_author(connection, &message.value.author)?;
+    let key_id = find_or_create_key(connection, &message.key)?;
+    insert_into(messages::table)
+        .values((
+            messages::flume_seq.eq(offset as i64),
+            messages::seq.eq(seq),
+            messages::key_id.eq(key_id),
+            messages::author_id.eq(author_id),
+        ))
+        .execute(connection)?;
+
+    Ok(true)
+}
+
+pub fn find_message_author_and_seq_by_key(
+    connection: &PgConnection,
+    key: &str,
+) -> Result<Option<(String, i32)>, Error> {
+    keys::table
+        .inner_join(messages::table.on(messages::key_id.nullable().eq(keys::id)))
+        .inner_join(authors::table.on(messages::author_id.nullable().eq(authors::id)))
+        .select((authors::author, messages::seq))
+        .filter(keys::key.eq(key))
+        .first(connection)
+        .optional()
+}
+
+pub fn find_message_flume_seq_by_key(
+    connection: &PgConnection,
+    key: &str,
+) -> Result<FlumeSequence, Error> {
+    keys::table
+        .inner_join(messages::table.on(messages::key_id.nullable().eq(keys::id)))
+        .select(messages::flume_seq)
+        .filter(keys::key.eq(key))
+        .first::<i64>(connection)
+        .map(|flume_seq| flume_seq as FlumeSequence)
+}
+
+pub fn find_message_flume_seq_by_author_and_sequence(
+    connection: &PgConnection,
+    author: &str,
+    sequence: i32,
+) -> Result<Option<FlumeSequence>, Error> {
+    authors::table
+        .inner_join(messages::table.on(messages::author_id.nullable().eq(authors::id)))
+        .select(messages::flume_seq)
+        .filter(authors::author.eq(author))
+        .filter(messages::seq.eq(sequence))
+        .first::<i64>(connection)
+        .optional()
+        .map(|flume_seq| flume_seq.map(|flume_seq| flume_seq as FlumeSequence))
+}
+
+pub fn find_feed_latest_seq(connection: &PgConnection, author: &str) -> Result<Option<i32>, Error> {
+    authors::table
+        .inner_join(messages::table.on(messages::author_id.nullable().eq(authors::id)))
+        .select(diesel::dsl::max(messages::seq))
+        .filter(authors::author.eq(author))
+        .first(connection)
+}
+
+pub fn find_feed_flume_seqs_newer_than(
+    connection: &PgConnection,
+    author: &str,
+    sequence: i32,
+    limit: Option<i64>,
+) -> Result<Vec<FlumeSequence>, Error> {
+    let flume_seqs = authors::table
+        .inner_join(messages::table.on(messages::author_id.nullable().eq(authors::id)))
+        .select(messages::flume_seq)
+        // Feeds start at 1, so any sequence below that (0 or negative) means the whole feed.
+        .filter(messages::seq.gt(sequence.max(0)))
+        .filter(authors::author.eq(author))
+        .order(messages::seq.asc())
+        // Same as js: a negative limit (eg. -1) means no limit, but 0 means nothing.
+        .limit(limit.filter(|limit| *limit >= 0).unwrap_or(i64::MAX))
+        .load::<i64>(connection)?
+        .into_iter()
+        .map(|flume_seq| flume_seq as FlumeSequence)
+        .collect();
+
+    Ok(flume_seqs)
+}
+
+/// Record that everything up to and including `offset` has been indexed.
+pub fn set_indexed_offset(connection: &PgConnection, offset: FlumeSequence) -> Result<(), Error> {
+    insert_into(indexed_offset::table)
+        .values((
+            indexed_offset::id.eq(0),
+            indexed_offset::flume_seq.eq(offset as i64),
+        ))
+        .on_conflict(indexed_offset::id)
+        .do_update()
+        .set(indexed_offset::flume_seq.eq(offset as i64))
+        .execute(connection)
+        .map(|_| ())
+}
+
+/// The offset of the last entry that has been indexed, or `None` if nothing has been.
+pub fn find_indexed_offset(connection: &PgConnection) -> Result<Option<FlumeSequence>, Error> {
+    indexed_offset::table
+        .select(indexed_offset::flume_seq)
+        .first::<i64>(connection)
+        .optional()
+        .map(|offset| offset.map(|offset| offset as FlumeSequence))
+}
+
+/// Delete everything derived from the offset log, so it can be indexed again.
+pub fn clear_indexes(connection: &PgConnection) -> Result<(), Error> {
+    connection.transaction(|| {
+        diesel::delete(messages::table).execute(connection)?;
+        diesel::delete(keys::table).execute(connection)?;
+        diesel::delete(authors::table).execute(connection)?;
+        diesel::delete(indexed_offset::table).execute(connection)?;
+        Ok(())
+    })
+}
@@ -5,6 +5,35 @@ table! {
     }
 }
 
+table! {
+    feed_meta (author) {
+        author -> Text,
+        nickname -> Nullable<Text>,
+        priority -> Integer,
+        muted -> Bool,
+    }
+}
+
+table! {
+    feed_mentions (from_flume_seq, mentioned_author) {
+        from_flume_seq -> BigInt,
+        mentioned_author -> Text,
+    }
+}
+
+table! {
+    hidden_messages (key) {
+        key -> Text,
+    }
+}
+
+table! {
+    indexed_offset (id) {
+        id -> Integer,
+        flume_seq -> BigInt,
+    }
+}
+
 table! {
     keys (id) {
         id -> Nullable<Integer>,
@@ -12,6 +41,13 @@ table! {
     }
 }
 
+table! {
+    message_links (from_flume_seq, target_key) {
+        from_flume_seq -> BigInt,
+        target_key -> Text,
+    }
+}
+
 table! {
     messages (id) {
         id -> Nullable<Integer>,
@@ -19,7 +55,30 @@ table! {
         seq -> Integer,
         key_id -> Integer,
         author_id -> Integer,
+        byte_len -> BigInt,
+        content_type -> Nullable<Text>,
+        previous_key_id -> Nullable<Integer>,
+        hidden -> Bool,
+        raw -> Nullable<Binary>,
+        received_at -> Nullable<Double>,
+    }
+}
+
+table! {
+    received_at_overrides (key) {
+        key -> Text,
+        received_at -> Double,
     }
 }
 
-allow_tables_to_appear_in_same_query!(authors, keys, messages,);
+allow_tables_to_appear_in_same_query!(
+    authors,
+    feed_meta,
+    feed_mentions,
+    hidden_messages,
+    indexed_offset,
+    keys,
+    message_links,
+    messages,
+    received_at_overrides,
+);
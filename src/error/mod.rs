@@ -6,20 +6,113 @@ use snafu::Snafu;
 pub enum Error {
     #[snafu(display("`include_keys` and `include_values` were both false. Pick one or both."))]
     IncludeKeysIncludeValuesBothFalse {},
-    #[snafu(display("Could not encode legacy value as vec"))]
-    EncodingValueAsVecError {},
+    #[snafu(display(
+        "Could not encode legacy value as vec for the entry at offset {}",
+        offset
+    ))]
+    EncodingValueAsVecError { offset: u64 },
     #[snafu(display("Error, tried to parse contents of db as legacy Value. This should never fail. The db may be corrupt. Rebuild the indexes"))]
     ErrorParsingAsLegacyValue {},
     #[snafu(display("Error, could not find message in db. {}", source))]
     MessageNotFound { source: db::Error },
+    #[snafu(display("Error, could not parse author {} from the db as a feed id.", author))]
+    ErrorParsingAuthor { author: String },
+    #[snafu(display("Error, could not parse key {} from the db as a message key.", key))]
+    ErrorParsingKey { key: String },
     #[snafu(display("Error, could not find feed in db. {}", source))]
     FeedNotFound { source: db::Error },
     #[snafu(display("Error, could not batch append to offset file."))]
     OffsetAppendError {},
+    #[snafu(display("Error, could not batch append to the mirror offset file. The main offset file was appended to."))]
+    MirrorAppendError {},
     #[snafu(display("Error, could not batch append to sqlite db."))]
     SqliteAppendError {},
+    #[snafu(display(
+        "Error, message key {} is already indexed for a different message.",
+        key
+    ))]
+    KeyCollision { key: String },
+    #[snafu(display(
+        "Error, author {} already exists. Remapping to it would merge two feeds.",
+        author
+    ))]
+    AuthorAlreadyExists { author: String },
+    #[snafu(display("Error, could not remap the author in the sqlite db. {}", source))]
+    SqliteRemapAuthorError { source: db::Error },
+    #[snafu(display("Error, could not clear the sqlite indexes. {}", source))]
+    SqliteClearError { source: db::Error },
+    #[snafu(display("Error, could not delete messages from the sqlite db. {}", source))]
+    SqliteDeleteError { source: db::Error },
+    #[snafu(display("Error, could not decompress the gzipped offset file at {}.", path))]
+    OffsetDecompressError { path: String },
+    #[snafu(display("Error, could not export to a new offset file at {}.", path))]
+    OffsetExportError { path: String },
+    #[snafu(display("Error, could not read the offset file to merge from at {}.", path))]
+    OffsetMergeError { path: String },
+    #[snafu(display("Error, could not read line {} of the JSONL to import.", line))]
+    ImportReadError { line: usize },
+    #[snafu(display("Error, could not write to the offset file."))]
+    OffsetWriteError {},
+    #[snafu(display("Error, could not compact the offset file."))]
+    OffsetCompactError {},
+    #[snafu(display("Error, could not checkpoint the sqlite db. {}", source))]
+    SqliteCheckpointError { source: db::Error },
+    #[snafu(display(
+        "Error, could not start or finish a read transaction on the sqlite db. {}",
+        source
+    ))]
+    SqliteReadTransactionError { source: db::Error },
+    #[snafu(display("Error, could not write to the sqlite db. {}", source))]
+    SqliteWriteError { source: db::Error },
+    #[snafu(display(
+        "Error, could not carry the local state over to the new sqlite db. {}",
+        source
+    ))]
+    LocalStateRestoreError { source: db::Error },
+    #[snafu(display("Error, could not open the sqlite db at {}.", path))]
+    SqliteConnectError { path: String },
+    #[snafu(display("Error, could not run the migrations on the sqlite db."))]
+    SqliteMigrationError {},
+    #[snafu(display("Error, could not delete the sqlite db at {} to make it again.", path))]
+    SqliteResetError { path: String },
+    #[snafu(display("Error, could not clean up the sqlite db. {}", source))]
+    SqliteMaintenanceError { source: db::Error },
+    #[snafu(display("Error, could not connect to the postgres db."))]
+    PostgresConnectError {},
+    #[snafu(display("Error, could not run the migrations on the postgres db."))]
+    PostgresMigrationError {},
+    #[snafu(display("Error, could not batch append to the postgres db."))]
+    PostgresAppendError {},
+    #[snafu(display("Error, could not clear the postgres indexes. {}", source))]
+    PostgresClearError { source: db::Error },
+    #[snafu(display("Error, could not watch the offset file for changes."))]
+    WatchError {},
+    #[snafu(display("Error, could not read the size of {}.", path))]
+    DiskUsageError { path: String },
+    #[snafu(display("Error, the sqlite indexes are missing. Rebuild the indexes"))]
+    IndexUnavailable {},
+    #[snafu(display("Error, a query took longer than its timeout and was aborted."))]
+    Timeout {},
     #[snafu(display("Error, could not find entry at expected offset."))]
     OffsetGetError {},
+    #[snafu(display("Error, the entry at offset {} is not a message. The indexes may be out of sync with the offset file. Rebuild the indexes", offset))]
+    CorruptEntry { offset: u64 },
+    #[snafu(display(
+        "Error, messages {} and {} are in different feeds.",
+        start_key,
+        end_key
+    ))]
+    KeysInDifferentFeeds { start_key: String, end_key: String },
+    #[snafu(display(
+        "Error, message {} in the batch is not a message by {}.",
+        index,
+        feed_id
+    ))]
+    MessageNotInFeed { index: usize, feed_id: String },
+    #[snafu(display("Error, message {} has no content.", key))]
+    MissingContent { key: String },
+    #[snafu(display("Error, could not query the content types in the db. {}", source))]
+    UnableToQueryContentTypes { source: db::Error },
     #[snafu(display(
         "Error, could not get the latest sequence number from the db. {}",
         source
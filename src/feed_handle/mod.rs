@@ -0,0 +1,87 @@
+use ssb_multiformats::multihash::Multihash;
+use ssb_multiformats::multikey::Multikey;
+
+use crate::error::Result;
+use crate::SsbDb;
+
+/// A handle to a single feed in an [SsbDb], so you don't have to keep passing the feed id.
+///
+/// Get one with [SsbDb::feed].
+pub struct FeedHandle<'a, D: SsbDb> {
+    db: &'a D,
+    feed_id: Multikey,
+}
+
+impl<'a, D: SsbDb> FeedHandle<'a, D> {
+    pub fn new(db: &'a D, feed_id: &Multikey) -> FeedHandle<'a, D> {
+        FeedHandle {
+            db,
+            feed_id: feed_id.clone(),
+        }
+    }
+
+    /// The id of the feed this handle is for.
+    pub fn feed_id(&self) -> &Multikey {
+        &self.feed_id
+    }
+
+    /// See [SsbDb::append_batch].
+    pub fn append_batch<T: 'static + AsRef<[u8]>>(&self, messages: &[T]) -> Result<()> {
+        self.db.append_batch(&self.feed_id, messages)
+    }
+
+    /// See [SsbDb::get_feed_latest_sequence].
+    pub fn latest_seq(&self) -> Result<Option<i32>> {
+        self.db.get_feed_latest_sequence(&self.feed_id)
+    }
+
+    /// See [SsbDb::get_entry_by_seq].
+    pub fn entry_at(&self, sequence: i32) -> Result<Option<Vec<u8>>> {
+        self.db.get_entry_by_seq(&self.feed_id, sequence)
+    }
+
+    /// See [SsbDb::get_feed_first_entry].
+    pub fn first_entry(&self) -> Result<Option<Vec<u8>>> {
+        self.db.get_feed_first_entry(&self.feed_id)
+    }
+
+    /// See [SsbDb::get_entries_newer_than_sequence].
+    pub fn entries_newer_than(
+        &self,
+        sequence: i32,
+        limit: Option<i64>,
+        include_keys: bool,
+        include_values: bool,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.db.get_entries_newer_than_sequence(
+            &self.feed_id,
+            sequence,
+            limit,
+            include_keys,
+            include_values,
+        )
+    }
+
+    /// Get the keys of every message in the feed, in sequence order.
+    pub fn keys(&self) -> Result<Vec<Multihash>> {
+        self.db
+            .get_feed_chain(&self.feed_id)
+            .map(|chain| chain.into_iter().map(|(_, _, key)| key).collect())
+    }
+
+    /// See [SsbDb::get_feed_entries_by_type].
+    pub fn entries_by_type(&self, content_type: &str, limit: Option<i64>) -> Result<Vec<Vec<u8>>> {
+        self.db
+            .get_feed_entries_by_type(&self.feed_id, content_type, limit)
+    }
+
+    /// See [SsbDb::is_feed_complete].
+    pub fn is_complete(&self) -> Result<bool> {
+        self.db.is_feed_complete(&self.feed_id)
+    }
+
+    /// See [SsbDb::find_feed_fork].
+    pub fn find_fork(&self) -> Result<Option<i32>> {
+        self.db.find_feed_fork(&self.feed_id)
+    }
+}
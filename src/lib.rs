@@ -20,7 +20,8 @@
 //!
 //! ## Architecture
 //!
-//! [SqliteSsbDb] implements the [SsbDb] trait.
+//! [SqliteSsbDb] implements the [SsbDb] trait. With the `postgres` feature, `PostgresSsbDb`
+//! implements it with the indexes in Postgres instead.
 //!
 //! The underlying architecture is based on [flume-db](https://github.com/sunrise-choir/flumedb-rs).
 //!
@@ -33,6 +34,8 @@
 //! `ssb-db` **does not validate any messages before appending them.** The caller must validate them first.
 //! See [ssb-validate](https://github.com/sunrise-choir/ssb-validate) and [ssb-verify-signatures](https://github.com/sunrise-choir/ssb-verify-signatures).
 //!
+// diesel 1.x's `table!` and derive macros expand to impls nested inside functions.
+#![allow(non_local_definitions)]
 #[macro_use]
 extern crate diesel_migrations;
 #[macro_use]
@@ -42,28 +45,379 @@ pub use flumedb::flume_view::Sequence as FlumeSequence;
 
 mod db;
 pub mod error;
+pub mod feed_handle;
+#[cfg(feature = "postgres")]
+pub mod postgres_ssb_db;
 pub mod sqlite_ssb_db;
-mod ssb_message;
+pub mod ssb_message;
 
 pub use error::Error;
-pub use sqlite_ssb_db::SqliteSsbDb;
+pub use feed_handle::FeedHandle;
+#[cfg(feature = "postgres")]
+pub use postgres_ssb_db::PostgresSsbDb;
+#[cfg(feature = "watch")]
+pub use sqlite_ssb_db::WatchHandle;
+pub use sqlite_ssb_db::{
+    DiskUsage, ImportReport, MaintenanceReport, MigrationOutcome, ReadView, SqliteSsbDb,
+};
+pub use ssb_message::{CommonContentType, SerdeJsonParser, SsbMessageParser};
 
 use error::Result;
+use serde::{Deserialize, Serialize};
+use sqlite_ssb_db::{parse_author, parse_key};
+use ssb_message::SsbMessage;
 use ssb_multiformats::multihash::Multihash;
 use ssb_multiformats::multikey::Multikey;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
 
+/// How many offset log entries were indexed, and how many were skipped because they aren't
+/// messages (eg. zeroed out deleted entries, or corruption).
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct IndexStats {
+    pub indexed: u64,
+    pub skipped: u64,
+}
+
+/// Local metadata about a feed, set with [SqliteSsbDb::set_feed_meta]. Unlike the indexes it isn't
+/// derived from messages, so it survives [SsbDb::rebuild_indexes].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FeedMeta {
+    /// What to call the feed locally, whatever it calls itself.
+    pub nickname: Option<String>,
+    /// How soon to replicate the feed compared to others. Higher is sooner.
+    pub priority: i32,
+    pub muted: bool,
+}
+
+/// Where a page from [SqliteSsbDb::page] ended, to get the page after it.
+///
+/// It's opaque, but can be serialized so a client can hold on to it between requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor(FlumeSequence);
+
+/// Everything you need to make and handle legacy replication requests.
+///
+/// Only the methods replication can't do without have to be implemented. The rest have default
+/// implementations built on those, which an implementation can override with faster queries, like
+/// [SqliteSsbDb] does.
 pub trait SsbDb {
+    /// Get a [FeedHandle] for doing several things with one feed.
+    fn feed(&self, feed_id: &Multikey) -> FeedHandle<'_, Self>
+    where
+        Self: Sized,
+    {
+        FeedHandle::new(self, feed_id)
+    }
     /// Append a batch of valid ssb messages authored by the `feed_id`.
-    fn append_batch<T: 'static + AsRef<[u8]>>(&self, feed_id: &Multikey, messages: &[T]) -> Result<()>;
+    ///
+    /// An empty batch does nothing, not even updating the indexes.
+    fn append_batch<T: 'static + AsRef<[u8]>>(
+        &self,
+        feed_id: &Multikey,
+        messages: &[T],
+    ) -> Result<()>;
+    /// Append a single valid ssb message authored by the `feed_id`.
+    ///
+    /// This is the same as calling [SsbDb::append_batch] with a batch of one.
+    fn append<T: 'static + AsRef<[u8]>>(&self, feed_id: &Multikey, message: T) -> Result<()> {
+        self.append_batch(feed_id, &[message])
+    }
     /// Get an entry by its ssb message key.
+    ///
+    /// Keys are stored and matched on their whole legacy encoding, sigil and hash suffix
+    /// included, so keys hashed with different algorithms never match each other. Fails with
+    /// [Error::MessageNotFound] if we don't have the message, which the default methods that look
+    /// up a message by key rely on.
     fn get_entry_by_key(&self, message_key: &Multihash) -> Result<Vec<u8>>;
+    /// Get just the `content` of a message by its ssb message key, or `None` if we don't have it.
+    ///
+    /// Private messages have their encrypted content as a JSON string.
+    fn get_content_by_key(&self, message_key: &Multihash) -> Result<Option<serde_json::Value>> {
+        let entry = match found(self.get_entry_by_key(message_key))? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        match parse_entry(&entry)?.value.content {
+            serde_json::Value::Null => Err(Error::MissingContent {
+                key: message_key.to_legacy_string(),
+            }),
+            content => Ok(Some(content)),
+        }
+    }
+    /// Check the message with the given key has all the fields a real message has, eg. to catch
+    /// truncated or garbage entries that still parse as json.
+    ///
+    /// This doesn't verify the signature, only that there is one. See
+    /// [ssb_message::is_structurally_valid].
+    fn is_structurally_valid(&self, message_key: &Multihash) -> Result<bool> {
+        self.get_entry_by_key(message_key)
+            .map(|entry| ssb_message::is_structurally_valid(&entry))
+    }
+    /// Get the author of a message by its ssb message key, without reading the message itself.
+    ///
+    /// Returns `None` if the message isn't in the db.
+    fn get_author_by_key(&self, message_key: &Multihash) -> Result<Option<Multikey>> {
+        found(self.get_entry_by_key(message_key))?
+            .map(|entry| parse_author(&parse_entry(&entry)?.value.author))
+            .transpose()
+    }
+    /// Get the sequence of a message within its feed by its ssb message key, without reading the
+    /// message itself.
+    ///
+    /// Returns `None` if the message isn't in the db.
+    fn get_seq_by_key(&self, message_key: &Multihash) -> Result<Option<i32>> {
+        found(self.get_entry_by_key(message_key))?
+            .map(|entry| Ok(parse_entry(&entry)?.value.sequence as i32))
+            .transpose()
+    }
+    /// Check whether a message is the latest one we have in its feed, by its ssb message key.
+    ///
+    /// Returns `false` if the message isn't in the db.
+    fn is_latest_in_feed(&self, message_key: &Multihash) -> Result<bool> {
+        let message = match found(self.get_entry_by_key(message_key))? {
+            Some(entry) => parse_entry(&entry)?,
+            None => return Ok(false),
+        };
+        let latest = self.get_feed_latest_sequence(&parse_author(&message.value.author)?)?;
+
+        Ok(latest == Some(message.value.sequence as i32))
+    }
+    /// Find which of the given message keys we don't have, eg. the keys a peer advertised.
+    ///
+    /// Returns the missing keys in the same order as `candidate_keys`. [SqliteSsbDb] looks them
+    /// all up in one query, which is much faster than looking up each key on its own.
+    fn missing_keys(&self, candidate_keys: &[Multihash]) -> Result<Vec<Multihash>> {
+        candidate_keys
+            .iter()
+            .filter_map(|key| match found(self.get_entry_by_key(key)) {
+                Ok(Some(_)) => None,
+                Ok(None) => Some(Ok(key.clone())),
+                Err(err) => Some(Err(err)),
+            })
+            .collect()
+    }
     /// Get an entry by its sequence key + author.
     fn get_entry_by_seq(&self, feed_id: &Multikey, sequence: i32) -> Result<Option<Vec<u8>>>;
+    /// Get the message with the given key, along with up to `before` messages before it and
+    /// `after` messages after it in the same feed, ordered by sequence. Eg. to show the context
+    /// around a search hit.
+    ///
+    /// Any sequences we don't have in that window are skipped, so there may be fewer entries.
+    /// Fails with [Error::MessageNotFound] if we don't have the message itself.
+    fn get_context_around_key(
+        &self,
+        message_key: &Multihash,
+        before: i64,
+        after: i64,
+    ) -> Result<Vec<Vec<u8>>> {
+        let message = parse_entry(&self.get_entry_by_key(message_key)?)?;
+        let seq = i64::from(message.value.sequence);
+
+        let start = seq.saturating_sub(before.max(0)).max(1) as i32;
+        let end = seq.saturating_add(after.max(0)).min(i64::from(i32::MAX)) as i32;
+        get_feed_entries_between(self, &parse_author(&message.value.author)?, start, end)
+    }
+    /// Get the messages from `start_key` to `end_key` inclusive in their feed, ordered by
+    /// sequence, whichever of the two comes first. Eg. to sync a slice of a feed identified by
+    /// its endpoints.
+    ///
+    /// Any sequences we don't have in between are skipped. Fails with [Error::MessageNotFound] if
+    /// we don't have either message, or [Error::KeysInDifferentFeeds] if they aren't in the same
+    /// feed.
+    fn get_entries_between_keys(
+        &self,
+        start_key: &Multihash,
+        end_key: &Multihash,
+    ) -> Result<Vec<Vec<u8>>> {
+        let start = parse_entry(&self.get_entry_by_key(start_key)?)?;
+        let end = parse_entry(&self.get_entry_by_key(end_key)?)?;
+        if start.value.author != end.value.author {
+            return Err(Error::KeysInDifferentFeeds {
+                start_key: start_key.to_legacy_string(),
+                end_key: end_key.to_legacy_string(),
+            });
+        }
+
+        let start_seq = start.value.sequence as i32;
+        let end_seq = end.value.sequence as i32;
+        get_feed_entries_between(
+            self,
+            &parse_author(&start.value.author)?,
+            start_seq.min(end_seq),
+            start_seq.max(end_seq),
+        )
+    }
+    /// Get the entry before the message with the given key in its feed, eg. to load older context
+    /// around a linked message.
+    ///
+    /// Returns `None` if the message is the first in its feed, or if we don't have it or the
+    /// message before it.
+    fn get_previous_entry(&self, message_key: &Multihash) -> Result<Option<Vec<u8>>> {
+        let author = self.get_author_by_key(message_key)?;
+        let seq = self.get_seq_by_key(message_key)?;
+        match (author, seq) {
+            (Some(author), Some(seq)) if seq > 1 => self.get_entry_by_seq(&author, seq - 1),
+            _ => Ok(None),
+        }
+    }
+    /// Get the entry after the message with the given key in its feed, eg. to load newer context
+    /// around a linked message.
+    ///
+    /// Returns `None` if the message is the latest we have in its feed, or if we don't have it or
+    /// the message after it.
+    fn get_next_entry(&self, message_key: &Multihash) -> Result<Option<Vec<u8>>> {
+        let author = self.get_author_by_key(message_key)?;
+        let seq = self.get_seq_by_key(message_key)?;
+        match (author, seq.and_then(|seq| seq.checked_add(1))) {
+            (Some(author), Some(next_seq)) => self.get_entry_by_seq(&author, next_seq),
+            _ => Ok(None),
+        }
+    }
+    /// Get the entry with the lowest sequence number we have for the given feed.
+    ///
+    /// This is usually sequence 1, but may not be if the start of the feed wasn't replicated.
+    /// Returns `None` if the feed is unknown.
+    fn get_feed_first_entry(&self, feed_id: &Multikey) -> Result<Option<Vec<u8>>> {
+        let entries = self.get_entries_newer_than_sequence(feed_id, 0, Some(1), true, true)?;
+        Ok(entries.into_iter().next())
+    }
+    /// Get the entries in the given feed with the given content `type`, newest first.
+    ///
+    /// You may `limit` the maximum number of entries to get. A negative `limit` means no limit.
+    fn get_feed_entries_by_type(
+        &self,
+        feed_id: &Multikey,
+        content_type: &str,
+        limit: Option<i64>,
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut entries = self.get_entries_newer_than_sequence(feed_id, 0, None, true, true)?;
+        entries.reverse();
+        let mut entries = filter_entries(entries, |message| {
+            message.value.content_type() == Some(content_type)
+        })?;
+
+        if let Some(limit) = limit.filter(|limit| *limit >= 0) {
+            entries.truncate(limit as usize);
+        }
+        Ok(entries)
+    }
+    /// Like [SsbDb::get_feed_entries_by_type], but with a [CommonContentType] instead of a string.
+    fn get_feed_entries_by_common_type(
+        &self,
+        feed_id: &Multikey,
+        content_type: &CommonContentType,
+        limit: Option<i64>,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.get_feed_entries_by_type(feed_id, content_type.as_str(), limit)
+    }
+    /// Check which sequences in `range` we have for the given feed, without reading any messages.
+    ///
+    /// Returns one `bool` per sequence in `range`, in order. Useful for requesting exactly the
+    /// messages we're missing in a window of the feed.
+    fn have_sequences(&self, feed_id: &Multikey, range: RangeInclusive<i32>) -> Result<Vec<bool>> {
+        let have = get_feed_entries_between(self, feed_id, *range.start(), *range.end())?
+            .iter()
+            .map(|entry| Ok(parse_entry(entry)?.value.sequence as i32))
+            .collect::<Result<HashSet<_>>>()?;
+
+        Ok(range.map(|seq| have.contains(&seq)).collect())
+    }
+    /// Get the newest message of each content type in the given feed, keyed by type. Eg. the
+    /// latest post and the latest about for a profile summary.
+    fn get_latest_by_type(&self, feed_id: &Multikey) -> Result<HashMap<String, Vec<u8>>> {
+        let mut latest = HashMap::new();
+        // In sequence order, so each type ends up with its newest message.
+        for entry in self.get_entries_newer_than_sequence(feed_id, 0, None, true, true)? {
+            let message = parse_entry(&entry)?;
+            if let Some(content_type) = message.value.content_type() {
+                latest.insert(content_type.to_owned(), entry);
+            }
+        }
+        Ok(latest)
+    }
+    /// Get the latest message of each of the given `feeds`, in the same order as `feeds`. Feeds we
+    /// have no messages for are skipped. Eg. for a "recent from everyone I follow" screen.
+    ///
+    /// [SqliteSsbDb] finds all the latest messages in one query, rather than one per feed.
+    fn get_latest_entries_for_feeds(&self, feeds: &[Multikey]) -> Result<Vec<Vec<u8>>> {
+        feeds
+            .iter()
+            .filter_map(|feed_id| match self.get_feed_latest_sequence(feed_id) {
+                Ok(Some(seq)) => self.get_entry_by_seq(feed_id, seq).transpose(),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            })
+            .collect()
+    }
     /// Get the latest sequence number for the given feed.
     fn get_feed_latest_sequence(&self, feed_id: &Multikey) -> Result<Option<i32>>;
+    /// Get the `(sequence, previous, key)` of every message we have for the given feed, in
+    /// sequence order.
+    ///
+    /// Useful for checking the hash chain links up without reading the messages.
+    fn get_feed_chain(
+        &self,
+        feed_id: &Multikey,
+    ) -> Result<Vec<(i32, Option<Multihash>, Multihash)>> {
+        self.get_entries_newer_than_sequence(feed_id, 0, None, true, true)?
+            .iter()
+            .map(|entry| {
+                let message = parse_entry(entry)?;
+                let previous = message
+                    .value
+                    .previous
+                    .as_deref()
+                    .map(parse_key)
+                    .transpose()?;
+                Ok((
+                    message.value.sequence as i32,
+                    previous,
+                    parse_key(&message.key)?,
+                ))
+            })
+            .collect()
+    }
+    /// Find the first sequence at which the given feed forks, if it does.
+    ///
+    /// A feed forks when we have two messages with the same sequence, or when a message's
+    /// `previous` isn't the key of the message before it.
+    fn find_feed_fork(&self, feed_id: &Multikey) -> Result<Option<i32>> {
+        let chain = self.get_feed_chain(feed_id)?;
+
+        let fork = chain.windows(2).find_map(|pair| {
+            let (prev_seq, _, prev_key) = &pair[0];
+            let (seq, previous, _) = &pair[1];
+
+            if seq == prev_seq || (*seq == prev_seq + 1 && previous.as_ref() != Some(prev_key)) {
+                Some(*seq)
+            } else {
+                None
+            }
+        });
+
+        Ok(fork)
+    }
+    /// Check that the given feed has every message from sequence 1 up to its latest, with no gaps.
+    ///
+    /// This is a cheap completeness check. It does not verify the hash chain.
+    fn is_feed_complete(&self, feed_id: &Multikey) -> Result<bool> {
+        match self.get_feed_latest_sequence(feed_id)? {
+            Some(latest) => Ok(self
+                .have_sequences(feed_id, 1..=latest)?
+                .into_iter()
+                .all(|have| have)),
+            None => Ok(false),
+        }
+    }
     /// Get all the entries for the given `feed_id`, with a sequence larger than `sequence`.
     ///
-    /// You may `limit` the maximum number of entries to get.
+    /// Feeds start at sequence 1, so a `sequence` of `0` returns the whole feed. A negative
+    /// `sequence` is treated the same as `0`, and `i32::MAX` returns no entries.
+    ///
+    /// You may `limit` the maximum number of entries to get. `None` or a negative limit means no
+    /// limit, like the js sbot. `Some(0)` returns no entries.
     ///
     /// You can control whether to `include_keys`, `include_values`, or both.
     ///
@@ -77,15 +431,132 @@ pub trait SsbDb {
         include_keys: bool,
         include_values: bool,
     ) -> Result<Vec<Vec<u8>>>;
+    /// Work out what to request from a peer, given the latest sequence they have of each feed.
+    ///
+    /// Returns each feed where the peer is ahead of us, with the latest sequence we have (0 if we
+    /// don't have the feed at all). Ask the peer for entries newer than that sequence, ie.
+    /// `our_seq + 1..=their_seq`.
+    ///
+    /// Built on [SsbDb::common_frontier].
+    fn diff_frontier(&self, theirs: &[(Multikey, i32)]) -> Result<Vec<(Multikey, i32)>> {
+        // Where the peer is ahead of us, what we have in common is everything we have.
+        let common = self.common_frontier(theirs)?;
+        Ok(theirs
+            .iter()
+            .zip(common)
+            .filter(|((_, their_seq), (_, common_seq))| their_seq > common_seq)
+            .map(|(_, common)| common)
+            .collect())
+    }
+    /// Work out how much of each feed we have in common with a peer, given the latest sequence
+    /// they have of each feed.
+    ///
+    /// Returns each feed with the smaller of their latest sequence and ours (0 if either of us
+    /// doesn't have the feed), in the same order as `theirs`. Entries up to that sequence are ones
+    /// we both have. [SqliteSsbDb] looks up all our latest sequences in one go, so it's fine for
+    /// large follow lists.
+    fn common_frontier(&self, theirs: &[(Multikey, i32)]) -> Result<Vec<(Multikey, i32)>> {
+        theirs
+            .iter()
+            .map(|(feed_id, their_seq)| {
+                let our_seq = self.get_feed_latest_sequence(feed_id)?.unwrap_or(0);
+                // Feeds start at 1, so a negative sequence means they have none of it either.
+                Ok((feed_id.clone(), our_seq.min(*their_seq).max(0)))
+            })
+            .collect()
+    }
+    /// Answer a legacy replication
+    /// [createHistoryStream](https://scuttlebot.io/apis/scuttlebot/ssb.html#createhistorystream-source)
+    /// request.
+    ///
+    /// `seq` is the largest sequence the peer already has, so only entries with a sequence
+    /// greater than `seq` are returned, in sequence order.
+    ///
+    /// - `keys` and `values` both `true` returns the full `{key, value, timestamp}` entries.
+    /// - `keys` only returns the message keys.
+    /// - `values` only returns the message values, byte-exact so their signatures still verify.
+    /// - `keys` and `values` both `false` is an `Error`.
+    fn create_history_stream(
+        &self,
+        feed_id: &Multikey,
+        seq: i32,
+        limit: Option<i64>,
+        keys: bool,
+        values: bool,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.get_entries_newer_than_sequence(feed_id, seq, limit, keys, values)
+    }
     /// You can rebuild the indexes in sqlite db (but not the offset file) if they become
     /// corrupted.
-    fn rebuild_indexes(&self) -> Result<()>;
+    ///
+    /// Returns how many entries were indexed and skipped. Lots of skipped entries suggests the
+    /// offset file itself is damaged.
+    ///
+    /// Only the indexes are rebuilt. Local state that isn't derived from the offset file, like
+    /// feed metadata set with [SqliteSsbDb::set_feed_meta], messages hidden with [SqliteSsbDb::set_hidden] and
+    /// received times set with [SqliteSsbDb::set_feed_received_at], is kept.
+    fn rebuild_indexes(&self) -> Result<IndexStats>;
+}
+
+// Parse an entry from the db, for the default methods that need more than its bytes.
+fn parse_entry(entry: &[u8]) -> Result<SsbMessage> {
+    serde_json::from_slice(entry).map_err(|_| Error::ErrorParsingAsLegacyValue {})
+}
+
+// Keep the entries whose message passes `keep`, in order.
+fn filter_entries<F: Fn(&SsbMessage) -> bool>(
+    entries: Vec<Vec<u8>>,
+    keep: F,
+) -> Result<Vec<Vec<u8>>> {
+    entries
+        .into_iter()
+        .filter_map(|entry| match parse_entry(&entry) {
+            Ok(message) if keep(&message) => Some(Ok(entry)),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        })
+        .collect()
+}
+
+// The entries in the feed with a sequence from `start` to `end` inclusive, skipping any we don't
+// have.
+fn get_feed_entries_between<D: SsbDb + ?Sized>(
+    db: &D,
+    feed_id: &Multikey,
+    start: i32,
+    end: i32,
+) -> Result<Vec<Vec<u8>>> {
+    if end < start {
+        return Ok(Vec::new());
+    }
+    let limit = i64::from(end) - i64::from(start) + 1;
+    let entries = db.get_entries_newer_than_sequence(
+        feed_id,
+        start.saturating_sub(1),
+        Some(limit),
+        true,
+        true,
+    )?;
+    filter_entries(entries, |message| message.value.sequence as i32 <= end)
+}
+
+// A message that isn't in the db is `None` rather than an error.
+fn found<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::MessageNotFound {
+            source: diesel::result::Error::NotFound,
+        }) => Ok(None),
+        Err(err) => Err(err),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::ssb_message::{SsbMessage, SsbValue};
-    use crate::{SqliteSsbDb, SsbDb};
+    use crate::ssb_message::{SsbMessage, SsbMessageParser, SsbValue};
+    use crate::{
+        CommonContentType, Error, FeedMeta, IndexStats, MigrationOutcome, SqliteSsbDb, SsbDb,
+    };
     use flumedb::offset_log::OffsetLog;
     use ssb_multiformats::multihash::Multihash;
     use ssb_multiformats::multikey::Multikey;
@@ -106,7 +577,7 @@ mod tests {
         let actual_key_str: &str = value["key"].as_str().unwrap();
 
         assert_eq!(actual_key_str, key_str);
-        std::fs::remove_file(&db_path).unwrap();
+        std::fs::remove_file(db_path).unwrap();
     }
 
     #[test]
@@ -123,7 +594,7 @@ mod tests {
         let seq = res.unwrap();
 
         assert_eq!(seq.unwrap(), expected_seq);
-        std::fs::remove_file(&db_path).unwrap();
+        std::fs::remove_file(db_path).unwrap();
     }
     #[test]
     fn get_entries_kv_newer_than_sequence_works() {
@@ -138,12 +609,12 @@ mod tests {
             .get_entries_newer_than_sequence(&author, 6000, None, true, true)
             .unwrap()
             .iter()
-            .flat_map(|entry| serde_json::from_slice::<SsbMessage>(&entry))
+            .flat_map(|entry| serde_json::from_slice::<SsbMessage>(entry))
             .collect::<Vec<_>>();
 
         assert_eq!(res.len(), 6);
 
-        std::fs::remove_file(&db_path).unwrap();
+        std::fs::remove_file(db_path).unwrap();
     }
     #[test]
     fn get_entries_newer_than_sequence_works_with_limit() {
@@ -158,12 +629,12 @@ mod tests {
             .get_entries_newer_than_sequence(&author, 6000, Some(2), true, true)
             .unwrap()
             .iter()
-            .flat_map(|entry| serde_json::from_slice::<SsbMessage>(&entry))
+            .flat_map(|entry| serde_json::from_slice::<SsbMessage>(entry))
             .collect::<Vec<_>>();
 
         assert_eq!(res.len(), 2);
 
-        std::fs::remove_file(&db_path).unwrap();
+        std::fs::remove_file(db_path).unwrap();
     }
     #[test]
     fn get_entries_k_newer_than_sequence_works() {
@@ -184,7 +655,7 @@ mod tests {
 
         assert_eq!(res.len(), 6);
 
-        std::fs::remove_file(&db_path).unwrap();
+        std::fs::remove_file(db_path).unwrap();
     }
     #[test]
     fn get_entries_v_newer_than_sequence_works() {
@@ -205,7 +676,7 @@ mod tests {
 
         assert_eq!(res.len(), 6);
 
-        std::fs::remove_file(&db_path).unwrap();
+        std::fs::remove_file(db_path).unwrap();
     }
     #[test]
     fn get_entries_no_kv_newer_than_sequence_errors() {
@@ -221,7 +692,7 @@ mod tests {
 
         assert!(res.is_err());
 
-        std::fs::remove_file(&db_path).unwrap();
+        std::fs::remove_file(db_path).unwrap();
     }
     #[test]
     fn append_batch_works() {
@@ -229,7 +700,7 @@ mod tests {
         let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
         let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
         let offset_log_path = "./test_vecs/piet.offset";
-        let log = OffsetLog::<u32>::new(&offset_log_path).unwrap();
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
 
         let entries = log.iter().map(|entry| entry.data).collect::<Vec<_>>();
 
@@ -237,15 +708,15 @@ mod tests {
         let offset_path = "/tmp/test_append_batch.offset";
         let db = SqliteSsbDb::new(db_path, offset_path);
 
-        let res = db.append_batch(&author, &entries.as_slice());
+        let res = db.append_batch(&author, entries.as_slice());
         assert!(res.is_ok());
 
         let res = db.get_feed_latest_sequence(&author);
         let seq = res.unwrap();
         assert_eq!(seq.unwrap(), expected_seq);
 
-        std::fs::remove_file(&db_path).unwrap();
-        std::fs::remove_file(&offset_path).unwrap();
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
     }
     #[test]
     fn rebuild_indexes_works() {
@@ -267,6 +738,3930 @@ mod tests {
 
         assert_eq!(seq.unwrap(), expected_seq);
 
-        std::fs::remove_file(&db_path).unwrap();
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn with_temp_index_works() {
+        let expected_seq = 6006;
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db = SqliteSsbDb::with_temp_index("./test_vecs/piet.offset").unwrap();
+        let db_path = db.db_path().unwrap().to_owned();
+        assert!(std::path::Path::new(&db_path).exists());
+
+        let res = db.get_feed_latest_sequence(&author);
+        let seq = res.unwrap();
+
+        assert_eq!(seq.unwrap(), expected_seq);
+
+        drop(db);
+        assert!(!std::path::Path::new(&db_path).exists());
+    }
+    #[test]
+    fn is_feed_complete_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let entries = log.iter().map(|entry| entry.data).collect::<Vec<_>>();
+
+        let db_path = "/tmp/test_is_feed_complete.sqlite3";
+        let offset_path = "/tmp/test_is_feed_complete.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+
+        db.append_batch(&author, &entries[..10]).unwrap();
+        assert!(db.is_feed_complete(&author).unwrap());
+
+        db.append_batch(&author, &entries[11..20]).unwrap();
+        assert!(!db.is_feed_complete(&author).unwrap());
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn new_with_parser_uses_parser() {
+        struct SkipEverything;
+        impl SsbMessageParser for SkipEverything {
+            fn parse_message(&self, _: &[u8]) -> Option<SsbMessage> {
+                None
+            }
+        }
+
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_new_with_parser.sqlite3";
+        let db = SqliteSsbDb::new_with_parser(db_path, "./test_vecs/piet.offset", SkipEverything);
+        db.update_indexes_from_offset_file().unwrap();
+
+        let seq = db.get_feed_latest_sequence(&author).unwrap();
+        assert_eq!(seq, None);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn sqlite_ssb_db_is_send() {
+        // So each db can be moved to its own thread, see `SqliteSsbDb::from_shared_log`.
+        fn assert_send<T: Send>() {}
+        assert_send::<SqliteSsbDb>();
+    }
+    #[test]
+    fn default_methods_match_sqlite_ssb_db() {
+        // Only implements what the trait requires, so every other method uses its default.
+        struct RequiredOnly<'a>(&'a SqliteSsbDb);
+
+        impl SsbDb for RequiredOnly<'_> {
+            fn append_batch<T: 'static + AsRef<[u8]>>(
+                &self,
+                feed_id: &Multikey,
+                messages: &[T],
+            ) -> Result<(), Error> {
+                self.0.append_batch(feed_id, messages)
+            }
+            fn get_entry_by_key(&self, message_key: &Multihash) -> Result<Vec<u8>, Error> {
+                self.0.get_entry_by_key(message_key)
+            }
+            fn get_entry_by_seq(
+                &self,
+                feed_id: &Multikey,
+                sequence: i32,
+            ) -> Result<Option<Vec<u8>>, Error> {
+                self.0.get_entry_by_seq(feed_id, sequence)
+            }
+            fn get_feed_latest_sequence(&self, feed_id: &Multikey) -> Result<Option<i32>, Error> {
+                self.0.get_feed_latest_sequence(feed_id)
+            }
+            fn get_entries_newer_than_sequence(
+                &self,
+                feed_id: &Multikey,
+                sequence: i32,
+                limit: Option<i64>,
+                include_keys: bool,
+                include_values: bool,
+            ) -> Result<Vec<Vec<u8>>, Error> {
+                self.0.get_entries_newer_than_sequence(
+                    feed_id,
+                    sequence,
+                    limit,
+                    include_keys,
+                    include_values,
+                )
+            }
+            fn rebuild_indexes(&self) -> Result<IndexStats, Error> {
+                self.0.rebuild_indexes()
+            }
+        }
+
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let missing_author_str = format!("@{:042}0=.ed25519", 1);
+        let missing_author = Multikey::from_legacy(missing_author_str.as_bytes())
+            .unwrap()
+            .0;
+        let key_str = "%/v5mCnV/kmnVtnF3zXtD4tbzoEQo4kRq/0d/bgxP1WI=.sha256";
+        let key = Multihash::from_legacy(key_str.as_bytes()).unwrap().0;
+        let missing_key_str = "%AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.sha256";
+        let missing_key = Multihash::from_legacy(missing_key_str.as_bytes())
+            .unwrap()
+            .0;
+
+        let db_path = "/tmp/test_default_methods.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+        let defaults = RequiredOnly(&db);
+
+        let latest = db.get_entry_by_seq(&author, 6006).unwrap().unwrap();
+        let latest_key_str = serde_json::from_slice::<SsbMessage>(&latest).unwrap().key;
+        let latest_key = Multihash::from_legacy(latest_key_str.as_bytes()).unwrap().0;
+        let earlier = db.get_entry_by_seq(&author, 5990).unwrap().unwrap();
+        let earlier_key_str = serde_json::from_slice::<SsbMessage>(&earlier).unwrap().key;
+        let earlier_key = Multihash::from_legacy(earlier_key_str.as_bytes())
+            .unwrap()
+            .0;
+
+        for key in &[&key, &missing_key] {
+            assert_eq!(
+                defaults.get_content_by_key(key).unwrap(),
+                db.get_content_by_key(key).unwrap()
+            );
+            assert_eq!(
+                defaults.get_author_by_key(key).unwrap(),
+                db.get_author_by_key(key).unwrap()
+            );
+            assert_eq!(
+                defaults.get_seq_by_key(key).unwrap(),
+                db.get_seq_by_key(key).unwrap()
+            );
+        }
+        for key in &[&key, &latest_key, &missing_key] {
+            assert_eq!(
+                defaults.is_latest_in_feed(key).unwrap(),
+                db.is_latest_in_feed(key).unwrap()
+            );
+        }
+        let candidates = [key.clone(), missing_key.clone()];
+        assert_eq!(
+            defaults.missing_keys(&candidates).unwrap(),
+            db.missing_keys(&candidates).unwrap()
+        );
+        assert_eq!(
+            defaults.get_context_around_key(&latest_key, 3, 3).unwrap(),
+            db.get_context_around_key(&latest_key, 3, 3).unwrap()
+        );
+        assert_eq!(
+            defaults
+                .get_entries_between_keys(&latest_key, &earlier_key)
+                .unwrap(),
+            db.get_entries_between_keys(&latest_key, &earlier_key)
+                .unwrap()
+        );
+        for feed_id in &[&author, &missing_author] {
+            assert_eq!(
+                defaults.get_feed_first_entry(feed_id).unwrap(),
+                db.get_feed_first_entry(feed_id).unwrap()
+            );
+            assert_eq!(
+                defaults
+                    .get_feed_entries_by_type(feed_id, "post", Some(5))
+                    .unwrap(),
+                db.get_feed_entries_by_type(feed_id, "post", Some(5))
+                    .unwrap()
+            );
+            assert_eq!(
+                defaults.have_sequences(feed_id, 6000..=6010).unwrap(),
+                db.have_sequences(feed_id, 6000..=6010).unwrap()
+            );
+            assert_eq!(
+                defaults.get_latest_by_type(feed_id).unwrap(),
+                db.get_latest_by_type(feed_id).unwrap()
+            );
+            assert_eq!(
+                defaults.get_feed_chain(feed_id).unwrap(),
+                db.get_feed_chain(feed_id).unwrap()
+            );
+            assert_eq!(
+                defaults.is_feed_complete(feed_id).unwrap(),
+                db.is_feed_complete(feed_id).unwrap()
+            );
+        }
+        let feeds = [missing_author.clone(), author.clone()];
+        assert_eq!(
+            defaults.get_latest_entries_for_feeds(&feeds).unwrap(),
+            db.get_latest_entries_for_feeds(&feeds).unwrap()
+        );
+        let theirs = [(author.clone(), 7000), (missing_author, 5), (author, -1)];
+        assert_eq!(
+            defaults.common_frontier(&theirs).unwrap(),
+            db.common_frontier(&theirs).unwrap()
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_feed_first_entry_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let entries = log.iter().map(|entry| entry.data).collect::<Vec<_>>();
+
+        let db_path = "/tmp/test_get_feed_first_entry.sqlite3";
+        let offset_path = "/tmp/test_get_feed_first_entry.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+
+        assert!(db.get_feed_first_entry(&author).unwrap().is_none());
+
+        db.append_batch(&author, &entries[4..10]).unwrap();
+
+        let entry = db.get_feed_first_entry(&author).unwrap().unwrap();
+        let message = serde_json::from_slice::<SsbMessage>(&entry).unwrap();
+        assert_eq!(message.value.sequence, 5);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn append_batch_errors_on_key_collision() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let entries = log
+            .iter()
+            .map(|entry| entry.data)
+            .take(2)
+            .collect::<Vec<_>>();
+        let first_key = serde_json::from_slice::<SsbMessage>(&entries[0])
+            .unwrap()
+            .key;
+        let mut colliding = serde_json::from_slice::<serde_json::Value>(&entries[1]).unwrap();
+        colliding["key"] = serde_json::Value::String(first_key.clone());
+        let colliding = serde_json::to_vec(&colliding).unwrap();
+
+        let db_path = "/tmp/test_key_collision.sqlite3";
+        let offset_path = "/tmp/test_key_collision.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+
+        db.append_batch(&author, &entries[..1]).unwrap();
+
+        match db.append_batch(&author, &[colliding]) {
+            Err(Error::KeyCollision { key }) => assert_eq!(key, first_key),
+            res => panic!("expected a key collision, got {:?}", res),
+        }
+
+        // The colliding message is skipped, so it doesn't hold up indexing what comes after it.
+        db.append_batch(&author, &entries[1..]).unwrap();
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(2));
+        let key = Multihash::from_legacy(first_key.as_bytes()).unwrap().0;
+        assert_eq!(db.get_seq_by_key(&key).unwrap(), Some(1));
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn create_history_stream_returns_entries_after_seq_in_order() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_create_history_stream.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let seqs = db
+            .create_history_stream(&author, 6000, None, true, true)
+            .unwrap()
+            .iter()
+            .map(|entry| serde_json::from_slice::<SsbMessage>(entry).unwrap())
+            .map(|msg| msg.value.sequence)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, vec![6001, 6002, 6003, 6004, 6005, 6006]);
+
+        let seqs = db
+            .create_history_stream(&author, 6000, None, false, true)
+            .unwrap()
+            .iter()
+            .map(|entry| serde_json::from_slice::<SsbValue>(entry).unwrap())
+            .map(|value| value.sequence)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, vec![6001, 6002, 6003, 6004, 6005, 6006]);
+
+        let keys = db
+            .create_history_stream(&author, 6005, None, true, false)
+            .unwrap();
+        let last = db.get_entry_by_seq(&author, 6006).unwrap().unwrap();
+        let last_key = serde_json::from_slice::<SsbMessage>(&last).unwrap().key;
+        assert_eq!(keys, vec![last_key.into_bytes()]);
+
+        assert!(db
+            .create_history_stream(&author, 6006, None, true, true)
+            .unwrap()
+            .is_empty());
+        assert!(db
+            .create_history_stream(&author, 6000, None, false, false)
+            .is_err());
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_entries_newer_than_sequence_limit_matches_js() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_get_entries_limit_js.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let count = |limit| {
+            db.get_entries_newer_than_sequence(&author, 6000, limit, true, false)
+                .unwrap()
+                .len()
+        };
+
+        assert_eq!(count(None), 6);
+        assert_eq!(count(Some(0)), 0);
+        assert_eq!(count(Some(-1)), 6);
+        assert_eq!(count(Some(1)), 1);
+        assert_eq!(count(Some(100)), 6);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_entries_newer_than_sequence_boundaries() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_get_entries_seq_boundaries.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let seqs = |sequence| {
+            db.get_entries_newer_than_sequence(&author, sequence, None, false, true)
+                .unwrap()
+                .iter()
+                .flat_map(|entry| serde_json::from_slice::<SsbValue>(entry))
+                .map(|value| value.sequence)
+                .collect::<Vec<_>>()
+        };
+
+        let from_zero = seqs(0);
+        assert_eq!(from_zero.len(), 6006);
+        assert_eq!(from_zero.first(), Some(&1));
+        assert_eq!(from_zero.last(), Some(&6006));
+        assert_eq!(seqs(-1), from_zero);
+        assert_eq!(seqs(i32::MIN), from_zero);
+        assert!(seqs(i32::MAX).is_empty());
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn remap_author_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let new_author_str = "@AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.ed25519";
+        let new_author = Multikey::from_legacy(new_author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_remap_author.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(db.remap_author(author_str, &new_author).unwrap(), 1);
+        assert_eq!(
+            db.get_feed_latest_sequence(&new_author).unwrap(),
+            Some(6006)
+        );
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), None);
+
+        // Remapping something that isn't there updates nothing.
+        assert_eq!(db.remap_author(author_str, &author).unwrap(), 0);
+
+        // Remapping onto an author that's already there would merge the feeds.
+        match db.remap_author("@not-an-author", &new_author) {
+            Err(Error::AuthorAlreadyExists { author }) => assert_eq!(author, new_author_str),
+            res => panic!("expected AuthorAlreadyExists, got {:?}", res),
+        }
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn is_latest_in_feed_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let missing_key_str = "%AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.sha256";
+        let missing_key = Multihash::from_legacy(missing_key_str.as_bytes())
+            .unwrap()
+            .0;
+
+        let db_path = "/tmp/test_is_latest_in_feed.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let key_at = |sequence| {
+            let entry = db.get_entry_by_seq(&author, sequence).unwrap().unwrap();
+            let message = serde_json::from_slice::<SsbMessage>(&entry).unwrap();
+            Multihash::from_legacy(message.key.as_bytes()).unwrap().0
+        };
+
+        assert!(db.is_latest_in_feed(&key_at(6006)).unwrap());
+        assert!(!db.is_latest_in_feed(&key_at(6005)).unwrap());
+        assert!(!db.is_latest_in_feed(&key_at(1)).unwrap());
+        assert!(!db.is_latest_in_feed(&missing_key).unwrap());
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn store_raw_reads_from_sqlite() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let offset_log_path = "/tmp/test_store_raw.offset";
+        let db_path = "/tmp/test_store_raw.sqlite3";
+        std::fs::copy("./test_vecs/piet.offset", offset_log_path).unwrap();
+
+        let db = SqliteSsbDb::new(db_path, offset_log_path).with_store_raw();
+        db.update_indexes_from_offset_file().unwrap();
+
+        let expected = db
+            .get_entries_newer_than_sequence(&author, 6000, None, true, true)
+            .unwrap();
+        assert_eq!(expected.len(), 6);
+
+        // With the offset log gone, the entries can only come from sqlite.
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(offset_log_path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+
+        let entries = db
+            .get_entries_newer_than_sequence(&author, 6000, None, true, true)
+            .unwrap();
+        assert_eq!(entries, expected);
+        assert!(db.get_entry_by_seq(&author, 6006).unwrap().is_some());
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_log_path).unwrap();
+    }
+    #[test]
+    fn missing_keys_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let missing_key_str = "%AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.sha256";
+        let missing_key = Multihash::from_legacy(missing_key_str.as_bytes())
+            .unwrap()
+            .0;
+
+        let db_path = "/tmp/test_missing_keys.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        // More keys than fit in one sqlite query.
+        let mut candidates = db
+            .get_entries_newer_than_sequence(&author, 0, None, true, false)
+            .unwrap()
+            .iter()
+            .map(|key| Multihash::from_legacy(key).unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(candidates.len(), 6006);
+        candidates.insert(1000, missing_key.clone());
+
+        assert_eq!(db.missing_keys(&candidates).unwrap(), vec![missing_key]);
+        assert!(db.missing_keys(&[]).unwrap().is_empty());
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn reindexes_when_offset_log_is_shorter_than_indexes() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let offset_log_path = "/tmp/test_shorter_log.offset";
+        let db_path = "/tmp/test_shorter_log.sqlite3";
+        std::fs::copy("./test_vecs/piet.offset", offset_log_path).unwrap();
+
+        let db = SqliteSsbDb::new(db_path, offset_log_path);
+        db.update_indexes_from_offset_file().unwrap();
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+        std::mem::drop(db);
+
+        // Replace the log with one holding only the start of the feed.
+        let entries = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .take(10)
+            .map(|entry| entry.data)
+            .collect::<Vec<_>>();
+        std::fs::remove_file(offset_log_path).unwrap();
+        OffsetLog::<u32>::new(offset_log_path)
+            .unwrap()
+            .append_batch(&entries)
+            .unwrap();
+
+        let db = SqliteSsbDb::new(db_path, offset_log_path);
+        let stats = db.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(stats.indexed, 10);
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(10));
+        assert!(db.is_feed_complete(&author).unwrap());
+        assert!(db.get_entry_by_seq(&author, 10).unwrap().is_some());
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_log_path).unwrap();
+    }
+    #[cfg(feature = "postgres")]
+    #[test]
+    #[ignore]
+    fn postgres_ssb_db_works() {
+        // Needs a Postgres db to index into, that the test is free to clear.
+        use crate::PostgresSsbDb;
+        let url = std::env::var("SSB_DB_TEST_POSTGRES_URL")
+            .expect("set SSB_DB_TEST_POSTGRES_URL to a postgres db to run this test");
+
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let key_str = "%/v5mCnV/kmnVtnF3zXtD4tbzoEQo4kRq/0d/bgxP1WI=.sha256";
+        let key = Multihash::from_legacy(key_str.as_bytes()).unwrap().0;
+        let missing_key_str = "%AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.sha256";
+        let missing_key = Multihash::from_legacy(missing_key_str.as_bytes())
+            .unwrap()
+            .0;
+
+        let db = PostgresSsbDb::new(url.as_str(), "./test_vecs/piet.offset").unwrap();
+        let stats = db.rebuild_indexes().unwrap();
+        assert_eq!(stats.indexed, 6006);
+        assert_eq!(db.update_indexes_from_offset_file().unwrap().indexed, 0);
+
+        let sqlite_path = "/tmp/test_postgres_ssb_db.sqlite3";
+        let sqlite = SqliteSsbDb::new(sqlite_path, "./test_vecs/piet.offset");
+        sqlite.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+        assert_eq!(
+            db.get_entry_by_key(&key).unwrap(),
+            sqlite.get_entry_by_key(&key).unwrap()
+        );
+        match db.get_entry_by_key(&missing_key) {
+            Err(Error::MessageNotFound { .. }) => (),
+            res => panic!("expected the message not to be found, got {:?}", res),
+        }
+        assert_eq!(
+            db.get_entry_by_seq(&author, 10).unwrap(),
+            sqlite.get_entry_by_seq(&author, 10).unwrap()
+        );
+        for (keys, values) in &[(true, true), (true, false), (false, true)] {
+            assert_eq!(
+                db.get_entries_newer_than_sequence(&author, 5990, Some(10), *keys, *values)
+                    .unwrap(),
+                sqlite
+                    .get_entries_newer_than_sequence(&author, 5990, Some(10), *keys, *values)
+                    .unwrap()
+            );
+        }
+        // The rest of the trait comes from its default methods.
+        assert_eq!(
+            db.get_seq_by_key(&key).unwrap(),
+            sqlite.get_seq_by_key(&key).unwrap()
+        );
+        assert!(db.is_feed_complete(&author).unwrap());
+        drop(db);
+
+        // Appending indexes the new messages, skipping any whose key is already taken.
+        let entries = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.data)
+            .take(3)
+            .collect::<Vec<_>>();
+        let first_key = serde_json::from_slice::<SsbMessage>(&entries[0])
+            .unwrap()
+            .key;
+        let mut colliding = serde_json::from_slice::<serde_json::Value>(&entries[1]).unwrap();
+        colliding["key"] = serde_json::Value::String(first_key.clone());
+        let colliding = serde_json::to_vec(&colliding).unwrap();
+
+        let offset_path = "/tmp/test_postgres_ssb_db.offset";
+        let db = PostgresSsbDb::new(url.as_str(), offset_path).unwrap();
+        db.rebuild_indexes().unwrap();
+        db.append_batch(&author, &entries[..1]).unwrap();
+        match db.append_batch(&author, &[colliding]) {
+            Err(Error::KeyCollision { key }) => assert_eq!(key, first_key),
+            res => panic!("expected a key collision, got {:?}", res),
+        }
+        db.append_batch(&author, &entries[1..]).unwrap();
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(3));
+        let first_key = Multihash::from_legacy(first_key.as_bytes()).unwrap().0;
+        assert_eq!(db.get_seq_by_key(&first_key).unwrap(), Some(1));
+
+        std::fs::remove_file(sqlite_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[cfg(feature = "async")]
+    #[test]
+    fn append_stream_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let log = OffsetLog::<u32>::new("./test_vecs/piet.offset").unwrap();
+        let entries = log
+            .iter()
+            .take(25)
+            .map(|entry| entry.data)
+            .collect::<Vec<_>>();
+
+        let db_path = "/tmp/test_append_stream.sqlite3";
+        let offset_path = "/tmp/test_append_stream.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+
+        let appended = futures::executor::block_on(db.append_stream(
+            &author,
+            futures::stream::iter(entries),
+            10,
+        ))
+        .unwrap();
+
+        assert_eq!(appended, 25);
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(25));
+        assert!(db.is_feed_complete(&author).unwrap());
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn offset_at_index_works() {
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+        let offsets = log.iter().map(|entry| entry.offset).collect::<Vec<_>>();
+
+        let db_path = "/tmp/test_offset_at_index.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_log_path);
+        db.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(db.offset_at_index(0).unwrap(), Some(offsets[0]));
+        assert_eq!(db.offset_at_index(3000).unwrap(), Some(offsets[3000]));
+        assert_eq!(db.offset_at_index(6005).unwrap(), Some(offsets[6005]));
+        assert_eq!(db.offset_at_index(6006).unwrap(), None);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn indexing_skips_unparseable_keys_and_authors() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let log = OffsetLog::<u32>::new("./test_vecs/piet.offset").unwrap();
+        let mut entries = log
+            .iter()
+            .take(3)
+            .map(|entry| entry.data)
+            .collect::<Vec<_>>();
+        entries.push(
+            br#"{"key":"%not-a-key","value":{"author":"@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519","sequence":4}}"#
+                .to_vec(),
+        );
+        entries.push(
+            br#"{"key":"%AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.sha256","value":{"author":"@not-an-author","sequence":1}}"#
+                .to_vec(),
+        );
+
+        let db_path = "/tmp/test_skips_unparseable.sqlite3";
+        let offset_path = "/tmp/test_skips_unparseable.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch_deferred(&author, &entries).unwrap();
+
+        let stats = db.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(
+            stats,
+            IndexStats {
+                indexed: 3,
+                skipped: 2
+            }
+        );
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(3));
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn export_feed_offset_log_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_export_feed.sqlite3";
+        let export_path = "/tmp/test_export_feed.offset";
+        let export_db_path = "/tmp/test_export_feed_imported.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(
+            db.export_feed_offset_log(&author, export_path).unwrap(),
+            6006
+        );
+        // Won't overwrite an existing file.
+        assert!(db.export_feed_offset_log(&author, export_path).is_err());
+
+        let exported = SqliteSsbDb::new(export_db_path, export_path);
+        let stats = exported.update_indexes_from_offset_file().unwrap();
+        assert_eq!(stats.indexed, 6006);
+        assert!(exported.is_feed_complete(&author).unwrap());
+        assert_eq!(
+            exported.get_entry_by_seq(&author, 6006).unwrap(),
+            db.get_entry_by_seq(&author, 6006).unwrap()
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(export_path).unwrap();
+        std::fs::remove_file(export_db_path).unwrap();
+    }
+    #[cfg(feature = "async")]
+    #[test]
+    fn update_indexes_cooperatively_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_update_indexes_cooperatively.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+
+        let stats = futures::executor::block_on(db.update_indexes_cooperatively()).unwrap();
+
+        assert_eq!(stats.indexed, 6006);
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+        assert_eq!(
+            futures::executor::block_on(db.update_indexes_cooperatively()).unwrap(),
+            IndexStats::default()
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn mentioned_feeds_walks_content() {
+        let value = serde_json::from_str::<SsbValue>(
+            r#"{
+                "author": "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519",
+                "sequence": 1,
+                "content": {
+                    "type": "post",
+                    "text": "hi @AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.ed25519",
+                    "mentions": [
+                        {"link": "@AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.ed25519"},
+                        {"link": "%AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.sha256"}
+                    ],
+                    "contact": "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            value.mentioned_feeds(),
+            vec![
+                "@AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.ed25519".to_owned(),
+                "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519".to_owned(),
+            ]
+        );
+    }
+    #[test]
+    fn get_mentions_of_works() {
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let mut mentions = std::collections::HashMap::new();
+        log.iter()
+            .flat_map(|entry| serde_json::from_slice::<SsbMessage>(&entry.data))
+            .for_each(|msg| {
+                msg.value.mentioned_feeds().into_iter().for_each(|feed| {
+                    mentions
+                        .entry(feed)
+                        .or_insert_with(Vec::new)
+                        .push(msg.key.clone())
+                })
+            });
+        let (most_mentioned, expected_keys) = mentions
+            .into_iter()
+            .max_by_key(|(_, keys)| keys.len())
+            .unwrap();
+        let most_mentioned = Multikey::from_legacy(most_mentioned.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_get_mentions_of.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_log_path);
+        db.update_indexes_from_offset_file().unwrap();
+
+        let keys = db
+            .get_mentions_of(&most_mentioned)
+            .unwrap()
+            .iter()
+            .map(|entry| serde_json::from_slice::<SsbMessage>(entry).unwrap().key)
+            .collect::<Vec<_>>();
+
+        assert!(!keys.is_empty());
+        assert_eq!(keys, expected_keys);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn max_messages_per_feed_evicts_oldest() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_max_messages_per_feed.sqlite3";
+        let db =
+            SqliteSsbDb::new(db_path, "./test_vecs/piet.offset").with_max_messages_per_feed(100);
+        db.update_indexes_from_offset_file().unwrap();
+
+        let seqs = db
+            .get_entries_newer_than_sequence(&author, 0, None, false, true)
+            .unwrap()
+            .iter()
+            .map(|entry| serde_json::from_slice::<SsbValue>(entry).unwrap().sequence)
+            .collect::<Vec<_>>();
+
+        assert_eq!(seqs, (5907..=6006).collect::<Vec<_>>());
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+        assert!(db.get_entry_by_seq(&author, 5906).unwrap().is_none());
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn max_messages_per_feed_boundaries() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let entries = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.data)
+            .take(10)
+            .collect::<Vec<_>>();
+        let kept = |max| {
+            let offset_path = "/tmp/test_max_messages_per_feed_boundaries.offset";
+            let db_path = "/tmp/test_max_messages_per_feed_boundaries.sqlite3";
+            let db = SqliteSsbDb::new(db_path, offset_path).with_max_messages_per_feed(max);
+            db.append_batch(&author, &entries).unwrap();
+            let kept = db
+                .get_entries_newer_than_sequence(&author, 0, None, false, true)
+                .unwrap()
+                .len();
+
+            drop(db);
+            std::fs::remove_file(db_path).unwrap();
+            std::fs::remove_file(offset_path).unwrap();
+            kept
+        };
+
+        // 0 means no limit rather than evicting everything.
+        assert_eq!(kept(0), 10);
+        assert_eq!(kept(1), 1);
+        assert_eq!(kept(10), 10);
+    }
+    #[test]
+    fn find_unindexed_feeds_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let other_str = "@AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.ed25519";
+        let other = Multikey::from_legacy(other_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_find_unindexed_feeds.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        assert!(db.find_unindexed_feeds().unwrap().is_empty());
+
+        // The log still has piet's feed, but the indexes no longer know it by that id.
+        db.remap_author(author_str, &other).unwrap();
+        assert_eq!(db.find_unindexed_feeds().unwrap(), vec![author]);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn key_lookups_match_the_whole_legacy_encoding() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_key_lookups_legacy_encoding.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let entry = db.get_entry_by_seq(&author, 1).unwrap().unwrap();
+        let key_str = serde_json::from_slice::<SsbMessage>(&entry).unwrap().key;
+        let key = Multihash::from_legacy(key_str.as_bytes()).unwrap().0;
+
+        // The key round trips through the keys table unchanged.
+        assert_eq!(key.to_legacy_string(), key_str);
+        assert_eq!(db.get_entry_by_key(&key).unwrap(), entry);
+
+        // The same digest as a different kind of multihash is a different key.
+        let blob = match key {
+            Multihash::Message(digest) => Multihash::Blob(digest),
+            Multihash::Blob(_) => panic!("expected a message key"),
+        };
+        assert!(db.get_entry_by_key(&blob).is_err());
+        assert_eq!(db.get_seq_by_key(&blob).unwrap(), None);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn have_sequences_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let missing_author_str = "@AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.ed25519";
+        let missing_author = Multikey::from_legacy(missing_author_str.as_bytes())
+            .unwrap()
+            .0;
+
+        let db_path = "/tmp/test_have_sequences.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(
+            db.have_sequences(&author, 6004..=6008).unwrap(),
+            vec![true, true, true, false, false]
+        );
+        assert_eq!(
+            db.have_sequences(&author, -1..=1).unwrap(),
+            vec![false, false, true]
+        );
+        assert_eq!(
+            db.have_sequences(&missing_author, 1..=2).unwrap(),
+            vec![false, false]
+        );
+        #[allow(clippy::reversed_empty_ranges)]
+        let empty = 2..=1;
+        assert!(db.have_sequences(&author, empty).unwrap().is_empty());
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn use_index_at_switches_without_deleting() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+
+        let db_path = "/tmp/test_use_index_at.sqlite3";
+        let standby_path = "/tmp/test_use_index_at_standby.sqlite3";
+        let empty_path = "/tmp/test_use_index_at_empty.sqlite3";
+
+        let standby = SqliteSsbDb::new(standby_path, offset_log_path);
+        standby.update_indexes_from_offset_file().unwrap();
+        drop(standby);
+
+        let mut db = SqliteSsbDb::new(db_path, offset_log_path);
+        db.update_indexes_from_offset_file().unwrap();
+
+        db.use_index_at(standby_path).unwrap();
+        assert!(std::path::Path::new(db_path).exists());
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+
+        // A fresh index is caught up with the log.
+        db.use_index_at(empty_path).unwrap();
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+        assert!(std::path::Path::new(standby_path).exists());
+
+        drop(db);
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(standby_path).unwrap();
+        std::fs::remove_file(empty_path).unwrap();
+    }
+    #[test]
+    fn stream_entries_by_type_works() {
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let expected_keys = log
+            .iter()
+            .flat_map(|entry| serde_json::from_slice::<SsbMessage>(&entry.data))
+            .filter(|msg| msg.value.content_type() == Some("vote"))
+            .map(|msg| msg.key)
+            .collect::<Vec<_>>();
+
+        let db_path = "/tmp/test_stream_entries_by_type.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_log_path);
+        db.update_indexes_from_offset_file().unwrap();
+
+        let keys = db
+            .stream_entries_by_type("vote")
+            .unwrap()
+            .map(|entry| {
+                serde_json::from_slice::<SsbMessage>(&entry.unwrap())
+                    .unwrap()
+                    .key
+            })
+            .collect::<Vec<_>>();
+
+        assert!(!keys.is_empty());
+        assert_eq!(keys, expected_keys);
+        assert_eq!(db.stream_entries_by_type("contuct").unwrap().count(), 0);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn feed_message_rate_works() {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let spammer_str = "@AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.ed25519";
+        let spammer = Multikey::from_legacy(spammer_str.as_bytes()).unwrap().0;
+
+        let offset_path = "/tmp/test_feed_message_rate.offset";
+        let db_path = "/tmp/test_feed_message_rate.sqlite3";
+        std::fs::copy("./test_vecs/piet.offset", offset_path).unwrap();
+        let db = SqliteSsbDb::new(db_path, offset_path);
+
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as f64;
+        let hour_ms = 60.0 * 60.0 * 1000.0;
+        let entries = (1..=3)
+            .map(|seq| {
+                serde_json::to_vec(&serde_json::json!({
+                    "key": format!("%{:042}0=.sha256", seq),
+                    "value": {
+                        "author": spammer_str,
+                        "sequence": seq,
+                        "timestamp": now_ms,
+                        "content": { "type": "post", "text": "spam" }
+                    },
+                    "timestamp": now_ms - (seq as f64 - 1.0) * hour_ms,
+                }))
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        db.append_batch(&spammer, &entries).unwrap();
+
+        // Received 0, 1 and 2 hours ago.
+        assert_eq!(
+            db.feed_message_rate(&spammer, 30.0 * 60.0 * 1000.0)
+                .unwrap(),
+            1
+        );
+        assert_eq!(db.feed_message_rate(&spammer, 1.5 * hour_ms).unwrap(), 2);
+        assert_eq!(db.feed_message_rate(&spammer, 24.0 * hour_ms).unwrap(), 3);
+
+        // Piet's messages were all received years ago.
+        assert_eq!(db.feed_message_rate(&author, 24.0 * hour_ms).unwrap(), 0);
+        assert_eq!(db.feed_message_rate(&author, now_ms).unwrap(), 6006);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn replace_feed_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let offset_path = "/tmp/test_replace_feed.offset";
+        let db_path = "/tmp/test_replace_feed.sqlite3";
+        std::fs::copy("./test_vecs/piet.offset", offset_path).unwrap();
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.update_indexes_from_offset_file().unwrap();
+
+        let replacement = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .take(100)
+            .map(|entry| entry.data)
+            .collect::<Vec<_>>();
+
+        db.replace_feed(&author, &replacement).unwrap();
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(100));
+        assert!(db.is_feed_complete(&author).unwrap());
+        assert_eq!(
+            db.get_entry_by_seq(&author, 100).unwrap().unwrap(),
+            replacement[99]
+        );
+
+        // The old entries are zeroed out, so only the new feed comes back when the indexes are
+        // rebuilt.
+        let stats = db.rebuild_indexes().unwrap();
+        assert_eq!(stats.indexed, 100);
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(100));
+        assert!(db.is_feed_complete(&author).unwrap());
+        assert_eq!(
+            db.get_entry_by_seq(&author, 1).unwrap().unwrap(),
+            replacement[0]
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn replace_feed_keeps_to_max_messages_per_feed() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let replacement = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .take(100)
+            .map(|entry| entry.data)
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_replace_feed_max_messages.offset";
+        let db_path = "/tmp/test_replace_feed_max_messages.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path).with_max_messages_per_feed(10);
+        db.append_batch(&author, &replacement[..20]).unwrap();
+
+        db.replace_feed(&author, &replacement).unwrap();
+        let seqs = db
+            .get_entries_newer_than_sequence(&author, 0, None, false, true)
+            .unwrap()
+            .iter()
+            .map(|entry| serde_json::from_slice::<SsbValue>(entry).unwrap().sequence)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, (91..=100).collect::<Vec<_>>());
+        // Indexing carries on after the new entries.
+        let last_offset = OffsetLog::<u32>::new(offset_path)
+            .unwrap()
+            .iter()
+            .last()
+            .map(|entry| entry.offset);
+        assert_eq!(db.latest_indexed_offset().unwrap(), last_offset);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn failed_replace_feed_keeps_old_feed() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let other_author_str = format!("@{:042}0=.ed25519", 1);
+        let other_author = Multikey::from_legacy(other_author_str.as_bytes())
+            .unwrap()
+            .0;
+        let taken_key_str = format!("%{:042}0=.sha256", 1);
+        let taken_key = Multihash::from_legacy(taken_key_str.as_bytes()).unwrap().0;
+
+        let offset_path = "/tmp/test_failed_replace_feed.offset";
+        let db_path = "/tmp/test_failed_replace_feed.sqlite3";
+        std::fs::copy("./test_vecs/piet.offset", offset_path).unwrap();
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.update_indexes_from_offset_file().unwrap();
+        let other_entry = serde_json::to_vec(&serde_json::json!({
+            "key": taken_key_str,
+            "value": { "author": other_author_str, "sequence": 1, "content": { "type": "post" } }
+        }))
+        .unwrap();
+        db.append(&other_author, other_entry.clone()).unwrap();
+        let original = db.get_entry_by_seq(&author, 10).unwrap().unwrap();
+
+        // A message by someone else is rejected before anything is appended.
+        let log_end = db.offset_log_len().unwrap();
+        match db.replace_feed(&author, &[other_entry]) {
+            Err(Error::MessageNotInFeed { index, .. }) => assert_eq!(index, 0),
+            res => panic!("expected MessageNotInFeed, got {:?}", res),
+        }
+        assert_eq!(db.offset_log_len().unwrap(), log_end);
+
+        // The last message's key is already another feed's, so indexing the replacement fails.
+        let mut replacement = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .take(10)
+            .map(|entry| entry.data)
+            .collect::<Vec<_>>();
+        replacement.push(
+            serde_json::to_vec(&serde_json::json!({
+                "key": taken_key_str,
+                "value": { "author": author_str, "sequence": 11, "content": { "type": "post" } }
+            }))
+            .unwrap(),
+        );
+        assert!(db.replace_feed(&author, &replacement).is_err());
+
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+        assert_eq!(db.get_entry_by_seq(&author, 10).unwrap().unwrap(), original);
+
+        // The failed replacement's entries aren't picked up later, either.
+        let stats = db.rebuild_indexes().unwrap();
+        assert_eq!(stats.indexed, 6007);
+        assert_eq!(stats.skipped, 11);
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+        assert_eq!(db.get_entry_by_seq(&author, 10).unwrap().unwrap(), original);
+        assert_eq!(
+            db.get_author_by_key(&taken_key).unwrap(),
+            Some(other_author)
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn recovers_from_injected_damage() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offsets = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.offset)
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_injected_damage.offset";
+        let db_path = "/tmp/test_injected_damage.sqlite3";
+        std::fs::copy("./test_vecs/piet.offset", offset_path).unwrap();
+        let mut db = SqliteSsbDb::new(db_path, offset_path);
+        db.set_validate_entries(true);
+        db.update_indexes_from_offset_file().unwrap();
+
+        db.inject_corruption_at(offsets[9]).unwrap();
+        match db.get_entry_by_seq(&author, 10) {
+            Err(Error::CorruptEntry { offset }) => assert_eq!(offset, offsets[9]),
+            res => panic!("expected CorruptEntry, got {:?}", res),
+        }
+
+        db.zero_entry_at(offsets[19]).unwrap();
+        let stats = db.rebuild_indexes().unwrap();
+        assert_eq!(stats.skipped, 2);
+        assert_eq!(stats.indexed, 6004);
+
+        db.desync_index().unwrap();
+        let stats = db.update_indexes_from_offset_file().unwrap();
+        assert_eq!(stats.indexed, 6004);
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn get_entries_newer_than_sequence_reports_corrupt_entries() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offsets = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.offset)
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_get_entries_corrupt.offset";
+        let db_path = "/tmp/test_get_entries_corrupt.sqlite3";
+        std::fs::copy("./test_vecs/piet.offset", offset_path).unwrap();
+        let mut db = SqliteSsbDb::new(db_path, offset_path);
+        db.set_validate_entries(true);
+        db.update_indexes_from_offset_file().unwrap();
+
+        db.inject_corruption_at(offsets[9]).unwrap();
+        [(true, false), (false, true), (true, true)]
+            .iter()
+            .for_each(|(include_keys, include_values)| {
+                match db.get_entries_newer_than_sequence(
+                    &author,
+                    5,
+                    Some(10),
+                    *include_keys,
+                    *include_values,
+                ) {
+                    Err(Error::CorruptEntry { offset }) => assert_eq!(offset, offsets[9]),
+                    res => panic!("expected CorruptEntry, got {:?}", res),
+                }
+            });
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn get_latest_by_type_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let mut expected = std::collections::HashMap::new();
+        log.iter()
+            .flat_map(|entry| serde_json::from_slice::<SsbMessage>(&entry.data))
+            .for_each(|msg| {
+                if let Some(content_type) = msg.value.content_type() {
+                    expected.insert(content_type.to_owned(), msg.value.sequence);
+                }
+            });
+
+        let db_path = "/tmp/test_get_latest_by_type.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_log_path);
+        db.update_indexes_from_offset_file().unwrap();
+
+        let latest = db
+            .get_latest_by_type(&author)
+            .unwrap()
+            .into_iter()
+            .map(|(content_type, entry)| {
+                let msg = serde_json::from_slice::<SsbMessage>(&entry).unwrap();
+                assert_eq!(msg.value.content_type(), Some(content_type.as_str()));
+                (content_type, msg.value.sequence)
+            })
+            .collect::<std::collections::HashMap<_, _>>();
+
+        assert!(latest.contains_key("post"));
+        assert_eq!(latest, expected);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn from_shared_log_sees_appends_from_writer() {
+        use std::sync::{Arc, Mutex};
+
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let entries = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.data)
+            .take(20)
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_from_shared_log.offset";
+        let writer_db_path = "/tmp/test_from_shared_log_writer.sqlite3";
+        let reader_db_path = "/tmp/test_from_shared_log_reader.sqlite3";
+        let log = Arc::new(Mutex::new(OffsetLog::<u32>::new(offset_path).unwrap()));
+        let writer = SqliteSsbDb::from_shared_log(writer_db_path, log.clone());
+        let reader = SqliteSsbDb::from_shared_log(reader_db_path, log);
+
+        writer.append_batch(&author, &entries[..10]).unwrap();
+        reader.update_indexes_from_offset_file().unwrap();
+        assert_eq!(reader.get_feed_latest_sequence(&author).unwrap(), Some(10));
+
+        writer.append_batch(&author, &entries[10..]).unwrap();
+        reader.update_indexes_from_offset_file().unwrap();
+        assert_eq!(reader.get_feed_latest_sequence(&author).unwrap(), Some(20));
+
+        match reader.handle_fork(&author, 10) {
+            Err(Error::OffsetWriteError {}) => {}
+            _ => panic!("expected an OffsetWriteError"),
+        }
+        assert_eq!(reader.get_feed_latest_sequence(&author).unwrap(), Some(20));
+
+        std::fs::remove_file(writer_db_path).unwrap();
+        std::fs::remove_file(reader_db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn disk_usage_works() {
+        let offset_log_path = "./test_vecs/piet.offset";
+        let db_path = "/tmp/test_disk_usage.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_log_path)
+            .with_wal()
+            .unwrap();
+        db.update_indexes_from_offset_file().unwrap();
+
+        let usage = db.disk_usage().unwrap();
+        let offset_log_bytes = std::fs::metadata(offset_log_path).unwrap().len();
+        assert_eq!(usage.offset_log_bytes, offset_log_bytes);
+        assert_eq!(
+            usage.sqlite_bytes,
+            std::fs::metadata(db_path).unwrap().len()
+        );
+        assert!(usage.sqlite_bytes > 0);
+        assert!(usage.wal_bytes > 0);
+
+        db.close().unwrap();
+        std::fs::remove_file(db_path).unwrap();
+        let _ = std::fs::remove_file("/tmp/test_disk_usage.sqlite3-wal");
+        let _ = std::fs::remove_file("/tmp/test_disk_usage.sqlite3-shm");
+    }
+    #[test]
+    fn get_feed_roots_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let entries = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.data)
+            .take(20)
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_get_feed_roots.offset";
+        let db_path = "/tmp/test_get_feed_roots.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+
+        // Only part of the feed, so there's no root.
+        db.append_batch(&author, &entries[10..]).unwrap();
+        assert!(db.get_feed_roots().unwrap().is_empty());
+
+        db.append_batch(&author, &entries[..10]).unwrap();
+        let roots = db
+            .get_feed_roots()
+            .unwrap()
+            .iter()
+            .map(|entry| serde_json::from_slice::<SsbMessage>(entry).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].value.author, author_str);
+        assert_eq!(roots[0].value.sequence, 1);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[cfg(feature = "watch")]
+    #[test]
+    fn start_watching_indexes_appends_from_another_writer() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let entries = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.data)
+            .take(20)
+            .collect::<Vec<_>>();
+
+        let connects = Arc::new(AtomicUsize::new(0));
+        let on_connect = {
+            let connects = connects.clone();
+            Arc::new(move |_: &diesel::SqliteConnection| {
+                connects.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+
+        let offset_path = "/tmp/test_start_watching.offset";
+        let db_path = "/tmp/test_start_watching.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path).with_on_connect(on_connect);
+        let handle = db.start_watching().unwrap();
+        // The watcher's connection is set up like ours.
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+
+        // Stands in for another process appending to the same file.
+        let mut writer = OffsetLog::<u32>::new(offset_path).unwrap();
+        entries
+            .chunks(5)
+            .for_each(|chunk| drop(writer.append_batch(chunk).unwrap()));
+
+        let started = Instant::now();
+        loop {
+            match db.get_feed_latest_sequence(&author) {
+                Ok(Some(20)) => break,
+                Ok(_) => (),
+                // Reads can find the db locked while the watcher is writing to it, so try again.
+                Err(Error::FeedNotFound { source }) if crate::db::is_busy_error(&source) => (),
+                Err(err) => panic!("reading the latest sequence failed: {}", err),
+            }
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        assert!(handle.take_errors().is_empty());
+        handle.stop().unwrap();
+        drop(db);
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn page_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let entries = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.data)
+            .take(25)
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_page.offset";
+        let db_path = "/tmp/test_page.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &entries[..10]).unwrap();
+
+        let (first, cursor) = db.page(&author, None, 8).unwrap();
+        assert_eq!(first, entries[..8].to_vec());
+
+        // The cursor survives a round trip through a client.
+        let json = serde_json::to_string(&cursor.unwrap()).unwrap();
+        let cursor = serde_json::from_str(&json).unwrap();
+
+        // Appending doesn't move the cursor.
+        db.append_batch(&author, &entries[10..]).unwrap();
+        let (second, cursor) = db.page(&author, Some(cursor), 8).unwrap();
+        assert_eq!(second, entries[8..16].to_vec());
+        let (third, cursor) = db.page(&author, cursor, 10).unwrap();
+        assert_eq!(third, entries[16..].to_vec());
+        assert_eq!(cursor, None);
+
+        // A page size of 0 still gets one message, so paging can't get stuck.
+        let (page, cursor) = db.page(&author, None, 0).unwrap();
+        assert_eq!(page, entries[..1].to_vec());
+        let (page, _) = db.page(&author, cursor, 0).unwrap();
+        assert_eq!(page, entries[1..2].to_vec());
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn append_batch_of_nothing_does_nothing() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let entries = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.data)
+            .take(10)
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_append_batch_of_nothing.offset";
+        let db_path = "/tmp/test_append_batch_of_nothing.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch_deferred(&author, &entries).unwrap();
+
+        let nothing: &[Vec<u8>] = &[];
+        db.append_batch_deferred(&author, nothing).unwrap();
+        db.append_batch(&author, nothing).unwrap();
+
+        // The deferred entries are still waiting, so the empty batch didn't update the indexes.
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), None);
+        let stats = db.update_indexes_from_offset_file().unwrap();
+        assert_eq!(stats.indexed, 10);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn get_content_by_key_works() {
+        let author_str = format!("@{:042}0=.ed25519", 1);
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let key = |seq: usize| {
+            let key_str = format!("%{:042}0=.sha256", seq);
+            Multihash::from_legacy(key_str.as_bytes()).unwrap().0
+        };
+        let entries = [
+            serde_json::json!({
+                "key": key(1).to_legacy_string(),
+                "value": {
+                    "author": author_str,
+                    "sequence": 1,
+                    "content": { "type": "post", "text": "hello" }
+                }
+            }),
+            serde_json::json!({
+                "key": key(2).to_legacy_string(),
+                "value": { "author": author_str, "sequence": 2, "content": "c2VjcmV0.box" }
+            }),
+            serde_json::json!({
+                "key": key(3).to_legacy_string(),
+                "value": { "author": author_str, "sequence": 3 }
+            }),
+        ]
+        .iter()
+        .map(|entry| serde_json::to_vec(entry).unwrap())
+        .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_get_content_by_key.offset";
+        let db_path = "/tmp/test_get_content_by_key.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &entries).unwrap();
+
+        assert_eq!(
+            db.get_content_by_key(&key(1)).unwrap(),
+            Some(serde_json::json!({ "type": "post", "text": "hello" }))
+        );
+        assert_eq!(
+            db.get_content_by_key(&key(2)).unwrap(),
+            Some(serde_json::json!("c2VjcmV0.box"))
+        );
+        match db.get_content_by_key(&key(3)) {
+            Err(Error::MissingContent { .. }) => {}
+            _ => panic!("expected a MissingContent error"),
+        }
+        assert_eq!(db.get_content_by_key(&key(4)).unwrap(), None);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn concurrent_appends_to_a_shared_log_work() {
+        use std::sync::{Arc, Mutex};
+
+        let num_feeds = 4;
+        let offset_path = "/tmp/test_concurrent_appends.offset";
+        let db_path = |writer: usize| format!("/tmp/test_concurrent_appends_{}.sqlite3", writer);
+        let log = Arc::new(Mutex::new(OffsetLog::<u32>::new(offset_path).unwrap()));
+
+        // Two writers per feed, each appending every other batch of it.
+        let writers = (0..num_feeds * 2)
+            .map(|writer| {
+                let log = log.clone();
+                let db_path = db_path(writer);
+                std::thread::spawn(move || {
+                    let feed = writer % num_feeds;
+                    let author_str = format!("@{:042}0=.ed25519", feed);
+                    let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+                    let db = SqliteSsbDb::from_shared_log(&db_path, log);
+
+                    (0..10).for_each(|batch| {
+                        let entries = (1..=5)
+                            .map(|n| {
+                                let seq = (batch * 2 + writer / num_feeds) * 5 + n;
+                                serde_json::to_vec(&serde_json::json!({
+                                    "key": format!("%{:038}{:04}0=.sha256", feed, seq),
+                                    "value": { "author": author_str, "sequence": seq }
+                                }))
+                                .unwrap()
+                            })
+                            .collect::<Vec<_>>();
+                        db.append_batch(&author, &entries).unwrap();
+                    });
+                })
+            })
+            .collect::<Vec<_>>();
+        writers
+            .into_iter()
+            .for_each(|writer| writer.join().unwrap());
+
+        let db = SqliteSsbDb::from_shared_log(db_path(0), log);
+        db.update_indexes_from_offset_file().unwrap();
+        (0..num_feeds).for_each(|feed| {
+            let author_str = format!("@{:042}0=.ed25519", feed);
+            let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+            assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(100));
+            let entries = db
+                .get_entries_newer_than_sequence(&author, 0, None, false, true)
+                .unwrap();
+            assert_eq!(entries.len(), 100);
+        });
+
+        drop(db);
+        (0..num_feeds * 2).for_each(|writer| std::fs::remove_file(db_path(writer)).unwrap());
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn is_structurally_valid_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let mut entries = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.data)
+            .take(2)
+            .collect::<Vec<_>>();
+
+        // Still parses, but the signature has gone missing.
+        let mut unsigned = serde_json::from_slice::<serde_json::Value>(&entries[1]).unwrap();
+        unsigned["value"]
+            .as_object_mut()
+            .unwrap()
+            .remove("signature");
+        entries[1] = serde_json::to_vec(&unsigned).unwrap();
+        let key = |entry: &[u8]| {
+            let key_str = serde_json::from_slice::<SsbMessage>(entry).unwrap().key;
+            Multihash::from_legacy(key_str.as_bytes()).unwrap().0
+        };
+
+        let offset_path = "/tmp/test_is_structurally_valid.offset";
+        let db_path = "/tmp/test_is_structurally_valid.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &entries).unwrap();
+
+        assert!(db.is_structurally_valid(&key(&entries[0])).unwrap());
+        assert!(!db.is_structurally_valid(&key(&entries[1])).unwrap());
+        assert!(!crate::ssb_message::is_structurally_valid(b"[1, 2, 3]"));
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn clone_offset_log_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let entries = OffsetLog::<u32>::new("./test_vecs/piet.offset")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.data)
+            .take(20)
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_clone_offset_log.offset";
+        let db_path = "/tmp/test_clone_offset_log.sqlite3";
+        let clone_path = "/tmp/test_clone_offset_log_clone.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &entries).unwrap();
+        // Zeroed entries are copied too.
+        db.handle_fork(&author, 15).unwrap();
+
+        assert_eq!(db.clone_offset_log(clone_path).unwrap(), 20);
+        assert_eq!(
+            std::fs::read(clone_path).unwrap(),
+            std::fs::read(offset_path).unwrap()
+        );
+        assert!(db.clone_offset_log(clone_path).is_err());
+        std::fs::remove_file(clone_path).unwrap();
+
+        let garbage_offset = std::fs::metadata(offset_path).unwrap().len();
+        db.append_batch_deferred(&author, &[b"not a message".to_vec()])
+            .unwrap();
+        match db.clone_offset_log(clone_path) {
+            Err(Error::CorruptEntry { offset }) => assert_eq!(offset, garbage_offset),
+            _ => panic!("expected a CorruptEntry error"),
+        }
+        assert!(!std::path::Path::new(clone_path).exists());
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn query_works() {
+        let day_ms = 24.0 * 60.0 * 60.0 * 1000.0;
+        let feed_id = |feed: usize| {
+            let feed_str = format!("@{:042}0=.ed25519", feed);
+            Multikey::from_legacy(feed_str.as_bytes()).unwrap().0
+        };
+        // Two feeds, each with a post and a vote received 10 days ago, then the same again today.
+        let entries = |feed: usize| {
+            (1..=4)
+                .map(|seq| {
+                    serde_json::to_vec(&serde_json::json!({
+                        "key": format!("%{:038}{:04}0=.sha256", feed, seq),
+                        "value": {
+                            "author": feed_id(feed).to_legacy_string(),
+                            "sequence": seq,
+                            "content": { "type": if seq % 2 == 1 { "post" } else { "vote" } }
+                        },
+                        "timestamp": if seq <= 2 { 0.0 } else { 10.0 * day_ms },
+                    }))
+                    .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let offset_path = "/tmp/test_query.offset";
+        let db_path = "/tmp/test_query.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&feed_id(1), &entries(1)).unwrap();
+        db.append_batch(&feed_id(2), &entries(2)).unwrap();
+
+        let seqs = |entries: Vec<Vec<u8>>| {
+            entries
+                .iter()
+                .map(|entry| {
+                    let msg = serde_json::from_slice::<SsbMessage>(entry).unwrap();
+                    (msg.value.author, msg.value.sequence)
+                })
+                .collect::<Vec<_>>()
+        };
+        let author = |feed: usize| feed_id(feed).to_legacy_string();
+
+        assert_eq!(db.query(None, None, None, None).unwrap().len(), 8);
+        assert_eq!(
+            seqs(db.query(Some(&feed_id(2)), None, None, None).unwrap()),
+            vec![
+                (author(2), 1),
+                (author(2), 2),
+                (author(2), 3),
+                (author(2), 4)
+            ]
+        );
+        assert_eq!(
+            seqs(
+                db.query(None, Some("vote"), Some(3.0 * day_ms), None)
+                    .unwrap()
+            ),
+            vec![(author(1), 4), (author(2), 4)]
+        );
+        assert_eq!(
+            seqs(
+                db.query(Some(&feed_id(1)), Some("post"), Some(3.0 * day_ms), None)
+                    .unwrap()
+            ),
+            vec![(author(1), 3)]
+        );
+        assert_eq!(
+            seqs(db.query(None, Some("post"), None, Some(3)).unwrap()),
+            vec![(author(1), 1), (author(1), 3), (author(2), 1)]
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn with_on_connect_works() {
+        use diesel::connection::SimpleConnection;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let connects = Arc::new(AtomicUsize::new(0));
+        let on_connect = {
+            let connects = connects.clone();
+            Arc::new(move |connection: &diesel::SqliteConnection| {
+                // The migrations have already run.
+                connection
+                    .batch_execute("PRAGMA mmap_size = 1048576; SELECT COUNT(*) FROM messages;")
+                    .unwrap();
+                connects.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+
+        let db_path = "/tmp/test_with_on_connect.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset").with_on_connect(on_connect);
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+        // Rebuilding only reconnects when it has to make a new db file.
+        db.rebuild_indexes().unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+        std::fs::remove_file(db_path).unwrap();
+        db.rebuild_indexes().unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+
+        drop(db);
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn latest_indexed_offset_works() {
+        let offset_log_path = "./test_vecs/piet.offset";
+        let last_offset = OffsetLog::<u32>::new(offset_log_path)
+            .unwrap()
+            .iter()
+            .last()
+            .unwrap()
+            .offset;
+
+        let db_path = "/tmp/test_latest_indexed_offset.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_log_path);
+        assert_eq!(db.latest_indexed_offset().unwrap(), None);
+
+        db.update_indexes_from_offset_file().unwrap();
+        assert_eq!(db.latest_indexed_offset().unwrap(), Some(last_offset));
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn latest_indexed_offset_is_exact_for_huge_offsets() {
+        use diesel::connection::{Connection, SimpleConnection};
+        use diesel::sqlite::SqliteConnection;
+
+        // 2^53 + 1, the first integer an f64 can't hold.
+        let huge_offset: u64 = 9_007_199_254_740_993;
+
+        let db_path = "/tmp/test_latest_indexed_offset_huge.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        SqliteConnection::establish(db_path)
+            .unwrap()
+            .batch_execute(&format!(
+                "UPDATE messages SET flume_seq = {} \
+                 WHERE flume_seq = (SELECT MAX(flume_seq) FROM messages)",
+                huge_offset
+            ))
+            .unwrap();
+        assert_eq!(db.latest_indexed_offset().unwrap(), Some(huge_offset));
+
+        // It's past the end of the log, so the indexes start again rather than skip ahead.
+        db.update_indexes_from_offset_file().unwrap();
+        assert!(db.latest_indexed_offset().unwrap().unwrap() < huge_offset);
+
+        drop(db);
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_links_to_any_works() {
+        let author_str = format!("@{:042}0=.ed25519", 1);
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let key_str = |seq: usize| format!("%{:042}0=.sha256", seq);
+        let key = |seq: usize| Multihash::from_legacy(key_str(seq).as_bytes()).unwrap().0;
+        let contents = [
+            serde_json::json!({ "type": "post", "text": "root" }),
+            serde_json::json!({ "type": "post", "root": key_str(1), "branch": key_str(1) }),
+            serde_json::json!({ "type": "post", "root": key_str(1), "branch": [key_str(2)] }),
+            serde_json::json!({ "type": "vote", "vote": { "link": key_str(2), "value": 1 } }),
+            // Feed ids and blobs aren't message links.
+            serde_json::json!({ "type": "post", "mentions": [author_str, "&AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA0=.sha256"] }),
+        ];
+        let entries = contents
+            .iter()
+            .enumerate()
+            .map(|(i, content)| {
+                serde_json::to_vec(&serde_json::json!({
+                    "key": key_str(i + 1),
+                    "value": { "author": author_str, "sequence": i + 1, "content": content }
+                }))
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_get_links_to_any.offset";
+        let db_path = "/tmp/test_get_links_to_any.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &entries).unwrap();
+
+        assert_eq!(
+            db.get_links_to_any(&[key(1)]).unwrap(),
+            vec![entries[1].clone(), entries[2].clone()]
+        );
+        // The second reply links to both, but is only returned once.
+        assert_eq!(
+            db.get_links_to_any(&[key(1), key(2)]).unwrap(),
+            vec![entries[1].clone(), entries[2].clone(), entries[3].clone()]
+        );
+        assert!(db.get_links_to_any(&[key(5)]).unwrap().is_empty());
+
+        // More targets than sqlite allows in one query.
+        let many_targets = (1..=2000).map(key).collect::<Vec<_>>();
+        assert_eq!(db.get_links_to_any(&many_targets).unwrap().len(), 3);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn queries_notice_a_deleted_index() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_deleted_index.sqlite3";
+        let mut db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        std::fs::remove_file(db_path).unwrap();
+        match db.get_feed_latest_sequence(&author) {
+            Err(Error::IndexUnavailable {}) => {}
+            _ => panic!("expected an IndexUnavailable error"),
+        }
+        assert!(matches!(
+            db.stream_feed_values(&author, 0),
+            Err(Error::IndexUnavailable {})
+        ));
+        assert!(matches!(
+            db.stream_entries_by_type("post"),
+            Err(Error::IndexUnavailable {})
+        ));
+        assert!(matches!(
+            db.find_unindexed_feeds(),
+            Err(Error::IndexUnavailable {})
+        ));
+
+        db.set_reindex_if_unavailable(true);
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+        assert!(std::path::Path::new(db_path).exists());
+
+        drop(db);
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_previous_entry_works() {
+        let first_key_str = "%/v5mCnV/kmnVtnF3zXtD4tbzoEQo4kRq/0d/bgxP1WI=.sha256";
+        let first_key = Multihash::from_legacy(first_key_str.as_bytes()).unwrap().0;
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_get_previous_entry.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let entry = db.get_entry_by_seq(&author, 10).unwrap().unwrap();
+        let entry: serde_json::Value = serde_json::from_slice(&entry).unwrap();
+        let key_str = entry["key"].as_str().unwrap();
+        let key = Multihash::from_legacy(key_str.as_bytes()).unwrap().0;
+
+        let previous = db.get_previous_entry(&key).unwrap().unwrap();
+        assert_eq!(Some(previous), db.get_entry_by_seq(&author, 9).unwrap());
+
+        assert_eq!(db.get_previous_entry(&first_key).unwrap(), None);
+
+        let unknown_key_str = format!("%{:042}0=.sha256", 1);
+        let unknown_key = Multihash::from_legacy(unknown_key_str.as_bytes())
+            .unwrap()
+            .0;
+        assert_eq!(db.get_previous_entry(&unknown_key).unwrap(), None);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_next_entry_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_get_next_entry.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let key_at = |seq: i32| {
+            let entry = db.get_entry_by_seq(&author, seq).unwrap().unwrap();
+            let entry: serde_json::Value = serde_json::from_slice(&entry).unwrap();
+            Multihash::from_legacy(entry["key"].as_str().unwrap().as_bytes())
+                .unwrap()
+                .0
+        };
+
+        let next = db.get_next_entry(&key_at(10)).unwrap().unwrap();
+        assert_eq!(Some(next), db.get_entry_by_seq(&author, 11).unwrap());
+
+        assert_eq!(db.get_next_entry(&key_at(6006)).unwrap(), None);
+
+        let unknown_key_str = format!("%{:042}0=.sha256", 1);
+        let unknown_key = Multihash::from_legacy(unknown_key_str.as_bytes())
+            .unwrap()
+            .0;
+        assert_eq!(db.get_next_entry(&unknown_key).unwrap(), None);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_next_entry_at_max_sequence_is_none() {
+        let author_str = format!("@{:042}0=.ed25519", 1);
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let key_str = format!("%{:042}0=.sha256", 1);
+        let key = Multihash::from_legacy(key_str.as_bytes()).unwrap().0;
+        let entry = serde_json::to_vec(&serde_json::json!({
+            "key": key_str,
+            "value": { "author": author_str, "sequence": i32::MAX, "content": { "type": "post" } }
+        }))
+        .unwrap();
+
+        let offset_path = "/tmp/test_get_next_entry_max_seq.offset";
+        let db_path = "/tmp/test_get_next_entry_max_seq.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append(&author, entry).unwrap();
+
+        assert_eq!(db.get_seq_by_key(&key).unwrap(), Some(i32::MAX));
+        assert_eq!(db.get_next_entry(&key).unwrap(), None);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn with_page_size_and_cache_size_work() {
+        use diesel::connection::{Connection, SimpleConnection};
+        use diesel::sql_types::BigInt;
+        use diesel::sqlite::SqliteConnection;
+        use diesel::RunQueryDsl;
+
+        #[derive(QueryableByName)]
+        struct PageSize {
+            #[sql_type = "BigInt"]
+            page_size: i64,
+        }
+        let page_size = |db_path: &str| {
+            let connection = SqliteConnection::establish(db_path).unwrap();
+            diesel::sql_query("PRAGMA page_size")
+                .get_result::<PageSize>(&connection)
+                .unwrap()
+                .page_size
+        };
+
+        let db_path = "/tmp/test_with_page_size.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset")
+            .with_page_size(8192)
+            .unwrap()
+            .with_cache_size(-2000)
+            .unwrap();
+        db.update_indexes_from_offset_file().unwrap();
+        assert_eq!(page_size(db_path), 8192);
+
+        // An existing db is rebuilt with the new size.
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset")
+            .with_page_size(16384)
+            .unwrap();
+        assert_eq!(page_size(db_path), 16384);
+
+        // And the size is kept when the db is created again.
+        db.rebuild_indexes().unwrap();
+        assert_eq!(page_size(db_path), 16384);
+        drop(db);
+
+        // Vacuuming fails while another connection is reading.
+        let reader = SqliteConnection::establish(db_path).unwrap();
+        reader
+            .batch_execute("BEGIN; SELECT COUNT(*) FROM messages;")
+            .unwrap();
+        match SqliteSsbDb::new(db_path, "./test_vecs/piet.offset").with_page_size(4096) {
+            Err(Error::SqliteMaintenanceError { .. }) => {}
+            _ => panic!("expected SqliteMaintenanceError"),
+        }
+        reader.batch_execute("COMMIT;").unwrap();
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn events_since_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_events_since.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let events = db.events_since(0).unwrap().collect::<Vec<_>>();
+        assert_eq!(events.len(), 6006);
+        assert!(events.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(events
+            .iter()
+            .all(|(_, _, event_author)| *event_author == author));
+
+        let (offset, key, _) = &events[5];
+        let entry = db.get_entry_by_key(key).unwrap();
+        let first = db.get_entries_at_offsets(&[*offset]).unwrap().remove(0);
+        assert_eq!(first.unwrap(), entry);
+
+        // Carrying on from a checkpoint picks up from the next message.
+        let rest = db.events_since(offset + 1).unwrap().collect::<Vec<_>>();
+        assert_eq!(rest, events[6..].to_vec());
+
+        let latest = db.latest_indexed_offset().unwrap().unwrap();
+        assert_eq!(db.events_since(latest + 1).unwrap().count(), 0);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_oldest_entry_works() {
+        let offset_path = "/tmp/test_get_oldest_entry.offset";
+        let db_path = "/tmp/test_get_oldest_entry.sqlite3";
+        let _ = std::fs::remove_file(offset_path);
+
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        assert_eq!(db.get_oldest_entry().unwrap(), None);
+        drop(db);
+        std::fs::remove_file(db_path).unwrap();
+
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+        let first = db.get_entries_at_offsets(&[0]).unwrap().remove(0).unwrap();
+        assert_eq!(db.get_oldest_entry().unwrap(), Some(first));
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn get_context_around_key_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let first_key_str = "%/v5mCnV/kmnVtnF3zXtD4tbzoEQo4kRq/0d/bgxP1WI=.sha256";
+        let first_key = Multihash::from_legacy(first_key_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_get_context_around_key.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let sequences = |entries: Vec<Vec<u8>>| {
+            entries
+                .iter()
+                .map(|entry| serde_json::from_slice::<serde_json::Value>(entry).unwrap())
+                .map(|value| value["value"]["sequence"].as_i64().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let entry = db.get_entry_by_seq(&author, 10).unwrap().unwrap();
+        let entry: serde_json::Value = serde_json::from_slice(&entry).unwrap();
+        let key = Multihash::from_legacy(entry["key"].as_str().unwrap().as_bytes())
+            .unwrap()
+            .0;
+
+        let context = db.get_context_around_key(&key, 2, 3).unwrap();
+        assert_eq!(sequences(context), vec![8, 9, 10, 11, 12, 13]);
+
+        // The window is cut off at the start of the feed.
+        let context = db.get_context_around_key(&first_key, 5, 1).unwrap();
+        assert_eq!(sequences(context), vec![1, 2]);
+
+        // A huge window is the whole feed rather than an overflow.
+        let context = db.get_context_around_key(&key, i64::MAX, i64::MAX).unwrap();
+        assert_eq!(context.len(), 6006);
+
+        let unknown_key_str = format!("%{:042}0=.sha256", 1);
+        let unknown_key = Multihash::from_legacy(unknown_key_str.as_bytes())
+            .unwrap()
+            .0;
+        assert!(db.get_context_around_key(&unknown_key, 1, 1).is_err());
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn read_chunk_size_and_commit_every_work() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let db_path = "/tmp/test_read_chunk_size_and_commit_every.sqlite3";
+
+        for (read_chunk_size, commit_every) in [(7, 1000), (1000, 7), (1, 1)].iter() {
+            let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset")
+                .with_read_chunk_size(*read_chunk_size)
+                .with_commit_every(*commit_every);
+
+            let stats = db.update_indexes_from_offset_file().unwrap();
+            assert_eq!(stats.indexed, 6006);
+            assert_eq!(stats.skipped, 0);
+            assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+            assert!(db.is_feed_complete(&author).unwrap());
+
+            drop(db);
+            std::fs::remove_file(db_path).unwrap();
+        }
+    }
+    #[test]
+    fn is_indexed_works() {
+        let offset_path = "/tmp/test_is_indexed.offset";
+        let db_path = "/tmp/test_is_indexed.sqlite3";
+        let _ = std::fs::remove_file(offset_path);
+
+        // An empty log has nothing to index.
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        assert!(db.is_indexed().unwrap());
+        drop(db);
+        std::fs::remove_file(db_path).unwrap();
+
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        assert!(!db.is_indexed().unwrap());
+        db.update_indexes_from_offset_file().unwrap();
+        assert!(db.is_indexed().unwrap());
+        drop(db);
+        std::fs::remove_file(db_path).unwrap();
+
+        // A log of only zeroed out entries has no messages, but it's still been indexed.
+        OffsetLog::<u32>::new(offset_path)
+            .unwrap()
+            .append_batch(&[vec![0; 100], vec![0; 50]])
+            .unwrap();
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        assert!(!db.is_indexed().unwrap());
+        let stats = db.update_indexes_from_offset_file().unwrap();
+        assert_eq!(stats.skipped, 2);
+        assert!(db.is_indexed().unwrap());
+        // Nothing is indexed again when the log hasn't changed.
+        let stats = db.update_indexes_from_offset_file().unwrap();
+        assert_eq!(stats, IndexStats::default());
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn get_links_to_from_feeds_works() {
+        let author_str = |n: usize| format!("@{:042}0=.ed25519", n);
+        let author = |n: usize| Multikey::from_legacy(author_str(n).as_bytes()).unwrap().0;
+        let key_str = |n: usize| format!("%{:042}0=.sha256", n);
+        let key = |n: usize| Multihash::from_legacy(key_str(n).as_bytes()).unwrap().0;
+        let entry = |n: usize, author_n: usize, content: serde_json::Value| {
+            serde_json::to_vec(&serde_json::json!({
+                "key": key_str(n),
+                "value": { "author": author_str(author_n), "sequence": 1, "content": content }
+            }))
+            .unwrap()
+        };
+
+        let root = entry(1, 1, serde_json::json!({ "type": "post", "text": "root" }));
+        let replies = (2..=4)
+            .map(|n| {
+                entry(
+                    n,
+                    n,
+                    serde_json::json!({ "type": "post", "root": key_str(1) }),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_get_links_to_from_feeds.offset";
+        let db_path = "/tmp/test_get_links_to_from_feeds.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append(&author(1), root).unwrap();
+        for (reply, n) in replies.iter().zip(2..) {
+            db.append(&author(n), reply.clone()).unwrap();
+        }
+
+        assert_eq!(
+            db.get_links_to_from_feeds(&key(1), &[author(2), author(4)])
+                .unwrap(),
+            vec![replies[0].clone(), replies[2].clone()]
+        );
+        // The root's own author didn't reply.
+        assert!(db
+            .get_links_to_from_feeds(&key(1), &[author(1)])
+            .unwrap()
+            .is_empty());
+        assert!(db.get_links_to_from_feeds(&key(1), &[]).unwrap().is_empty());
+
+        // More feeds than sqlite allows in one query.
+        let many_feeds = (1..=2000).map(author).collect::<Vec<_>>();
+        assert_eq!(
+            db.get_links_to_from_feeds(&key(1), &many_feeds).unwrap(),
+            replies
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn bytes_since_offset_works() {
+        let author_str = format!("@{:042}0=.ed25519", 1);
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let entry = |seq: usize| {
+            serde_json::to_vec(&serde_json::json!({
+                "key": format!("%{:042}0=.sha256", seq),
+                "value": { "author": author_str, "sequence": seq, "content": { "type": "post" } }
+            }))
+            .unwrap()
+        };
+
+        let offset_path = "/tmp/test_bytes_since_offset.offset";
+        let db_path = "/tmp/test_bytes_since_offset.sqlite3";
+        let _ = std::fs::remove_file(offset_path);
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        assert_eq!(db.offset_log_len().unwrap(), 0);
+
+        db.append_batch(&author, &[entry(1)]).unwrap();
+        let checkpoint = db.offset_log_len().unwrap();
+        assert_eq!(db.bytes_since_offset(checkpoint).unwrap(), 0);
+
+        db.append_batch(&author, &[entry(2), entry(3)]).unwrap();
+        let appended = std::fs::metadata(offset_path).unwrap().len() - checkpoint;
+        assert!(appended > 0);
+        assert_eq!(db.bytes_since_offset(checkpoint).unwrap(), appended);
+        assert_eq!(db.bytes_since_offset(u64::MAX).unwrap(), 0);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn read_transaction_nests_writes() {
+        let key_str = "%/v5mCnV/kmnVtnF3zXtD4tbzoEQo4kRq/0d/bgxP1WI=.sha256";
+        let key = Multihash::from_legacy(key_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_read_transaction_nests_writes.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let hidden = db
+            .read_transaction(|view| {
+                view.set_hidden(&key, true)?;
+                Ok(view.get_seq_by_key(&key)?.is_none())
+            })
+            .unwrap();
+        assert!(hidden);
+        assert_eq!(db.get_seq_by_key(&key).unwrap(), None);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn read_transaction_sees_a_snapshot() {
+        use diesel::connection::{Connection, SimpleConnection};
+        use diesel::sqlite::SqliteConnection;
+
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_read_transaction.sqlite3";
+        // Writers can only carry on during a read transaction in WAL mode.
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset")
+            .with_wal()
+            .unwrap();
+        db.update_indexes_from_offset_file().unwrap();
+
+        let writer = SqliteConnection::establish(db_path).unwrap();
+        let seqs = db
+            .read_transaction(|view| {
+                let before = view.get_feed_latest_sequence(&author)?;
+                writer
+                    .batch_execute("DELETE FROM messages WHERE seq > 6000;")
+                    .unwrap();
+                let after = view.get_feed_latest_sequence(&author)?;
+                Ok((before, after))
+            })
+            .unwrap();
+
+        assert_eq!(seqs, (Some(6006), Some(6006)));
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6000));
+
+        // An error from the closure is returned, and the transaction still ends.
+        let res: Result<(), Error> = db.read_transaction(|_| Err(Error::IndexUnavailable {}));
+        assert!(res.is_err());
+        assert!(db.read_transaction(|_| Ok(())).is_ok());
+
+        drop(writer);
+        db.close().unwrap();
+        std::fs::remove_file(db_path).unwrap();
+        let _ = std::fs::remove_file("/tmp/test_read_transaction.sqlite3-wal");
+        let _ = std::fs::remove_file("/tmp/test_read_transaction.sqlite3-shm");
+    }
+    #[test]
+    fn find_by_content_works() {
+        let author_str = format!("@{:042}0=.ed25519", 1);
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let announcement =
+            serde_json::json!({ "type": "pub", "address": { "host": "example.com" } });
+        let contents = [
+            announcement.clone(),
+            serde_json::json!({ "type": "post", "text": "hi" }),
+            announcement.clone(),
+            serde_json::json!({ "type": "pub", "address": { "host": "example.org" } }),
+            announcement.clone(),
+        ];
+        let entries = contents
+            .iter()
+            .enumerate()
+            .map(|(i, content)| {
+                serde_json::to_vec(&serde_json::json!({
+                    "key": format!("%{:042}0=.sha256", i + 1),
+                    "value": { "author": author_str, "sequence": i + 1, "content": content }
+                }))
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_find_by_content.offset";
+        let db_path = "/tmp/test_find_by_content.sqlite3";
+        let _ = std::fs::remove_file(offset_path);
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &entries).unwrap();
+
+        let all = vec![entries[0].clone(), entries[2].clone(), entries[4].clone()];
+        assert_eq!(db.find_by_content(&announcement, None).unwrap(), all);
+        assert_eq!(
+            db.find_by_content(&announcement, Some(2)).unwrap(),
+            all[..2]
+        );
+        assert!(db
+            .find_by_content(&serde_json::json!({ "type": "vote" }), None)
+            .unwrap()
+            .is_empty());
+
+        // Hidden messages aren't returned.
+        let key = Multihash::from_legacy(format!("%{:042}0=.sha256", 3).as_bytes())
+            .unwrap()
+            .0;
+        db.set_hidden(&key, true).unwrap();
+        assert_eq!(
+            db.find_by_content(&announcement, None).unwrap(),
+            vec![entries[0].clone(), entries[4].clone()]
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn migration_outcome_works() {
+        use diesel::connection::{Connection, SimpleConnection};
+        use diesel::sqlite::SqliteConnection;
+
+        let migration_count = std::fs::read_dir("./migrations").unwrap().count();
+        let db_path = "/tmp/test_migration_outcome.sqlite3";
+        let _ = std::fs::remove_file(db_path);
+
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        let outcome = db.migration_outcome().clone();
+        assert_eq!(outcome.applied.len(), migration_count);
+        assert!(outcome.applied.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(!outcome.indexes_reset);
+        db.update_indexes_from_offset_file().unwrap();
+        drop(db);
+
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        assert_eq!(db.migration_outcome(), &MigrationOutcome::default());
+        drop(db);
+
+        // Pretend the db was made before the latest migration.
+        let latest = outcome.applied.last().unwrap();
+        SqliteConnection::establish(db_path)
+            .unwrap()
+            .batch_execute(&format!(
+                "DELETE FROM __diesel_schema_migrations WHERE version = '{}';",
+                latest
+            ))
+            .unwrap();
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        assert_eq!(db.migration_outcome().applied.len(), migration_count);
+        assert!(db.migration_outcome().indexes_reset);
+        assert!(!db.is_indexed().unwrap());
+        drop(db);
+
+        let connection = SqliteConnection::establish(":memory:").unwrap();
+        let db = SqliteSsbDb::from_connection(connection, "./test_vecs/piet.offset").unwrap();
+        assert_eq!(db.migration_outcome().applied.len(), migration_count);
+        assert!(!db.migration_outcome().indexes_reset);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn local_state_survives_migration_reset() {
+        use diesel::connection::{Connection, SimpleConnection};
+        use diesel::sqlite::SqliteConnection;
+
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let meta = FeedMeta {
+            nickname: Some("piet".to_owned()),
+            priority: 1,
+            muted: true,
+        };
+
+        let key_str = "%/v5mCnV/kmnVtnF3zXtD4tbzoEQo4kRq/0d/bgxP1WI=.sha256";
+        let key = Multihash::from_legacy(key_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_local_state_survives_migration_reset.sqlite3";
+        let _ = std::fs::remove_file(db_path);
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+        db.set_feed_meta(&author, meta.clone()).unwrap();
+        db.set_hidden(&key, true).unwrap();
+        db.set_feed_received_at(&author, 0).unwrap();
+        let first = db.migration_outcome().applied[0].clone();
+        drop(db);
+
+        // Pretend the db was made before all but the first migration.
+        SqliteConnection::establish(db_path)
+            .unwrap()
+            .batch_execute(&format!(
+                "DELETE FROM __diesel_schema_migrations WHERE version != '{}';",
+                first
+            ))
+            .unwrap();
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        assert!(db.migration_outcome().indexes_reset);
+        assert_eq!(db.migration_outcome().local_state_error, None);
+        assert!(!db.is_indexed().unwrap());
+        assert_eq!(db.get_feed_meta(&author).unwrap(), Some(meta));
+
+        // Hidden flags and received times are put back as the messages are indexed again.
+        db.update_indexes_from_offset_file().unwrap();
+        assert!(db.get_entry_by_key(&key).is_err());
+        let thirty_years_ms = 30.0 * 365.0 * 24.0 * 60.0 * 60.0 * 1000.0;
+        assert_eq!(db.feed_message_rate(&author, thirty_years_ms).unwrap(), 0);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_entries_between_keys_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_get_entries_between_keys.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let key_at = |seq| {
+            let entry = db.get_entry_by_seq(&author, seq).unwrap().unwrap();
+            let value: serde_json::Value = serde_json::from_slice(&entry).unwrap();
+            Multihash::from_legacy(value["key"].as_str().unwrap().as_bytes())
+                .unwrap()
+                .0
+        };
+        let expected = (20..=25)
+            .map(|seq| db.get_entry_by_seq(&author, seq).unwrap().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            db.get_entries_between_keys(&key_at(20), &key_at(25))
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            db.get_entries_between_keys(&key_at(25), &key_at(20))
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            db.get_entries_between_keys(&key_at(20), &key_at(20))
+                .unwrap(),
+            expected[..1]
+        );
+
+        let unknown_key_str = format!("%{:042}0=.sha256", 1);
+        let unknown_key = Multihash::from_legacy(unknown_key_str.as_bytes())
+            .unwrap()
+            .0;
+        match db.get_entries_between_keys(&key_at(20), &unknown_key) {
+            Err(Error::MessageNotFound { .. }) => {}
+            _ => panic!("expected a MessageNotFound error"),
+        }
+        drop(db);
+        std::fs::remove_file(db_path).unwrap();
+
+        // Messages from two feeds.
+        let entry = |n: usize| {
+            serde_json::to_vec(&serde_json::json!({
+                "key": format!("%{:042}0=.sha256", n),
+                "value": { "author": format!("@{:042}0=.ed25519", n), "sequence": 1, "content": {} }
+            }))
+            .unwrap()
+        };
+        let offset_path = "/tmp/test_get_entries_between_keys.offset";
+        let _ = std::fs::remove_file(offset_path);
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch_deferred(&author, &[entry(1), entry(2)])
+            .unwrap();
+        db.update_indexes_from_offset_file().unwrap();
+
+        let key = |n: usize| {
+            Multihash::from_legacy(format!("%{:042}0=.sha256", n).as_bytes())
+                .unwrap()
+                .0
+        };
+        match db.get_entries_between_keys(&key(1), &key(2)) {
+            Err(Error::KeysInDifferentFeeds { .. }) => {}
+            _ => panic!("expected a KeysInDifferentFeeds error"),
+        }
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[cfg(feature = "compress-raw")]
+    #[test]
+    fn compressed_raw_reads_from_sqlite() {
+        use diesel::connection::Connection;
+        use diesel::sql_types::BigInt;
+        use diesel::sqlite::SqliteConnection;
+        use diesel::RunQueryDsl;
+
+        #[derive(QueryableByName)]
+        struct Count {
+            #[sql_type = "BigInt"]
+            count: i64,
+        }
+
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let offset_log_path = "/tmp/test_compressed_raw.offset";
+        let db_path = "/tmp/test_compressed_raw.sqlite3";
+        std::fs::copy("./test_vecs/piet.offset", offset_log_path).unwrap();
+
+        let db = SqliteSsbDb::new(db_path, offset_log_path).with_compressed_raw();
+        db.update_indexes_from_offset_file().unwrap();
+
+        let expected = db
+            .get_entries_newer_than_sequence(&author, 0, None, true, true)
+            .unwrap();
+        assert_eq!(expected.len(), 6006);
+
+        let connection = SqliteConnection::establish(db_path).unwrap();
+        let compressed = diesel::sql_query(
+            "SELECT COUNT(*) AS count FROM messages WHERE hex(substr(raw, 1, 4)) = '28B52FFD'",
+        )
+        .get_result::<Count>(&connection)
+        .unwrap();
+        assert!(compressed.count > 0);
+
+        // With the offset log gone, the entries can only come from sqlite.
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(offset_log_path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+
+        let entries = db
+            .get_entries_newer_than_sequence(&author, 0, None, true, true)
+            .unwrap();
+        assert_eq!(entries, expected);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_log_path).unwrap();
+    }
+    #[test]
+    fn set_feed_received_at_works() {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let importer_str = format!("@{:042}0=.ed25519", 1);
+        let importer = Multikey::from_legacy(importer_str.as_bytes()).unwrap().0;
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as f64;
+        let entries = (1..=4)
+            .map(|seq| {
+                serde_json::to_vec(&serde_json::json!({
+                    "key": format!("%{:042}0=.sha256", seq),
+                    "value": { "author": importer_str, "sequence": seq, "content": {} },
+                    "timestamp": now_ms,
+                }))
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_set_feed_received_at.offset";
+        let db_path = "/tmp/test_set_feed_received_at.sqlite3";
+        let _ = std::fs::remove_file(offset_path);
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&importer, &entries[..3]).unwrap();
+        let hour_ms = 60.0 * 60.0 * 1000.0;
+        assert_eq!(db.feed_message_rate(&importer, hour_ms).unwrap(), 3);
+
+        let day_ago_ms = (now_ms - 24.0 * hour_ms) as i64;
+        assert_eq!(db.set_feed_received_at(&importer, day_ago_ms).unwrap(), 3);
+        assert_eq!(db.feed_message_rate(&importer, hour_ms).unwrap(), 0);
+        assert_eq!(
+            db.feed_message_rate(&importer, 2.0 * 24.0 * hour_ms)
+                .unwrap(),
+            3
+        );
+
+        assert_eq!(db.set_feed_received_at(&author, day_ago_ms).unwrap(), 0);
+
+        // It's kept when the indexes are rebuilt, but only for the messages that were backfilled.
+        db.append(&importer, entries[3].clone()).unwrap();
+        db.rebuild_indexes().unwrap();
+        assert_eq!(db.feed_message_rate(&importer, hour_ms).unwrap(), 1);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn merge_from_works() {
+        let author_str = format!("@{:042}0=.ed25519", 1);
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let entry = |seq: usize| {
+            serde_json::to_vec(&serde_json::json!({
+                "key": format!("%{:042}0=.sha256", seq),
+                "value": { "author": author_str, "sequence": seq, "content": {} }
+            }))
+            .unwrap()
+        };
+
+        let offset_path = "/tmp/test_merge_from.offset";
+        let other_offset_path = "/tmp/test_merge_from_other.offset";
+        let db_path = "/tmp/test_merge_from.sqlite3";
+        let _ = std::fs::remove_file(offset_path);
+        let _ = std::fs::remove_file(other_offset_path);
+
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &[entry(1), entry(2), entry(3)])
+            .unwrap();
+
+        // The other log overlaps ours, and has a message twice.
+        OffsetLog::<u32>::new(other_offset_path)
+            .unwrap()
+            .append_batch(&[entry(2), entry(3), entry(4), entry(4), entry(5)])
+            .unwrap();
+
+        assert_eq!(db.merge_from(other_offset_path).unwrap(), 2);
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(5));
+        assert!(db.is_feed_complete(&author).unwrap());
+        assert_eq!(
+            OffsetLog::<u32>::new(offset_path).unwrap().iter().count(),
+            5
+        );
+
+        // Merging again adds nothing.
+        assert_eq!(db.merge_from(other_offset_path).unwrap(), 0);
+
+        match db.merge_from("/tmp/test_merge_from_missing.offset") {
+            Err(Error::OffsetMergeError { .. }) => {}
+            _ => panic!("expected an OffsetMergeError"),
+        }
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+        std::fs::remove_file(other_offset_path).unwrap();
+    }
+    #[test]
+    fn query_with_timeout_aborts_slow_queries() {
+        use std::time::Duration;
+
+        let db_path = "/tmp/test_query_with_timeout.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        match db.query_with_timeout(Duration::from_secs(0), |db| db.get_content_types()) {
+            Err(Error::Timeout {}) => {}
+            result => panic!("expected a timeout, got {:?}", result),
+        }
+
+        // The deadline is gone once the call returns.
+        let content_types = db.get_content_types().unwrap();
+        let within_timeout = db
+            .query_with_timeout(Duration::from_secs(60), |db| db.get_content_types())
+            .unwrap();
+        assert_eq!(within_timeout, content_types);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_latest_entries_for_feeds_works() {
+        let author_str = |n: usize| format!("@{:042}0=.ed25519", n);
+        let author = |n: usize| Multikey::from_legacy(author_str(n).as_bytes()).unwrap().0;
+        let key_str = |n: usize| format!("%{:042}0=.sha256", n);
+        let key = |n: usize| Multihash::from_legacy(key_str(n).as_bytes()).unwrap().0;
+        let entry = |n: usize, author_n: usize, seq: usize| {
+            serde_json::to_vec(&serde_json::json!({
+                "key": key_str(n),
+                "value": { "author": author_str(author_n), "sequence": seq, "content": { "type": "post" } }
+            }))
+            .unwrap()
+        };
+
+        let offset_path = "/tmp/test_get_latest_entries_for_feeds.offset";
+        let db_path = "/tmp/test_get_latest_entries_for_feeds.sqlite3";
+        let _ = std::fs::remove_file(offset_path);
+        let db = SqliteSsbDb::new(db_path, offset_path);
+
+        // Feed 1 has three messages and feed 2 has two. Feed 3 has none.
+        let entries = [
+            entry(1, 1, 1),
+            entry(2, 2, 1),
+            entry(3, 1, 2),
+            entry(4, 2, 2),
+            entry(5, 1, 3),
+        ];
+        db.append_batch(
+            &author(1),
+            &[entries[0].clone(), entries[2].clone(), entries[4].clone()],
+        )
+        .unwrap();
+        db.append_batch(&author(2), &[entries[1].clone(), entries[3].clone()])
+            .unwrap();
+
+        assert_eq!(
+            db.get_latest_entries_for_feeds(&[author(2), author(3), author(1)])
+                .unwrap(),
+            vec![entries[3].clone(), entries[4].clone()]
+        );
+        assert!(db.get_latest_entries_for_feeds(&[]).unwrap().is_empty());
+
+        // A hidden latest message gives way to the one before it.
+        db.set_hidden(&key(5), true).unwrap();
+        assert_eq!(
+            db.get_latest_entries_for_feeds(&[author(1)]).unwrap(),
+            vec![entries[2].clone()]
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn with_auto_index_on_read_catches_up_before_queries() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_with_auto_index_on_read.sqlite3";
+        let _ = std::fs::remove_file(db_path);
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), None);
+
+        let db = db.with_auto_index_on_read();
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+        assert_eq!(
+            db.read_transaction(|view| view.get_feed_latest_sequence(&author))
+                .unwrap(),
+            Some(6006)
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn feed_meta_survives_rebuild_indexes() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let stranger_str = format!("@{:042}0=.ed25519", 1);
+        let stranger = Multikey::from_legacy(stranger_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_feed_meta_survives_rebuild_indexes.sqlite3";
+        let _ = std::fs::remove_file(db_path);
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(db.get_feed_meta(&author).unwrap(), None);
+
+        let meta = FeedMeta {
+            nickname: Some("piet".to_owned()),
+            priority: 10,
+            muted: false,
+        };
+        db.set_feed_meta(&author, meta.clone()).unwrap();
+        // Feeds we have no messages for can have metadata too.
+        let muted = FeedMeta {
+            muted: true,
+            ..FeedMeta::default()
+        };
+        db.set_feed_meta(&stranger, muted.clone()).unwrap();
+
+        assert_eq!(db.get_feed_meta(&author).unwrap(), Some(meta.clone()));
+        assert_eq!(db.get_feed_meta(&stranger).unwrap(), Some(muted.clone()));
+
+        db.rebuild_indexes().unwrap();
+
+        assert_eq!(db.get_feed_meta(&author).unwrap(), Some(meta));
+        assert_eq!(db.get_feed_meta(&stranger).unwrap(), Some(muted));
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(6006));
+
+        // Setting it again replaces it.
+        db.set_feed_meta(&author, FeedMeta::default()).unwrap();
+        assert_eq!(
+            db.get_feed_meta(&author).unwrap(),
+            Some(FeedMeta::default())
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_author_ids_works() {
+        let author_str = |n: usize| format!("@{:042}0=.ed25519", n);
+        let author = |n: usize| Multikey::from_legacy(author_str(n).as_bytes()).unwrap().0;
+        let entry = |n: usize| {
+            serde_json::to_vec(&serde_json::json!({
+                "key": format!("%{:042}0=.sha256", n),
+                "value": { "author": author_str(n), "sequence": 1, "content": { "type": "post" } }
+            }))
+            .unwrap()
+        };
+
+        let offset_path = "/tmp/test_get_author_ids.offset";
+        let db_path = "/tmp/test_get_author_ids.sqlite3";
+        let _ = std::fs::remove_file(offset_path);
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append(&author(1), entry(1)).unwrap();
+        db.append(&author(2), entry(2)).unwrap();
+
+        let ids = db
+            .get_author_ids(&[author(2), author(3), author(1)])
+            .unwrap();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[0].0, author(2));
+        assert_eq!(ids[1], (author(3), None));
+        assert_eq!(ids[2].0, author(1));
+        let (id_1, id_2) = (ids[2].1.unwrap(), ids[0].1.unwrap());
+        assert_ne!(id_1, id_2);
+
+        // More feeds than sqlite allows in one query.
+        let many_feeds = (1..=2000).map(author).collect::<Vec<_>>();
+        let ids = db.get_author_ids(&many_feeds).unwrap();
+        assert_eq!(ids.len(), 2000);
+        assert_eq!(ids[0].1, Some(id_1));
+        assert_eq!(ids[1].1, Some(id_2));
+        assert!(ids[2..].iter().all(|(_, id)| id.is_none()));
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn import_jsonl_lenient_reports_bad_lines() {
+        use crate::ImportReport;
+
+        let author_str = format!("@{:042}0=.ed25519", 1);
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let entry = |seq: usize, author: &str| {
+            serde_json::to_string(&serde_json::json!({
+                "key": format!("%{:042}0=.sha256", seq),
+                "value": { "author": author, "sequence": seq, "content": { "type": "post" } }
+            }))
+            .unwrap()
+        };
+
+        let lines = [
+            entry(1, &author_str),
+            "{\"key\": \"truncated".to_owned(),
+            "".to_owned(),
+            entry(2, &author_str),
+            entry(1, &author_str),
+            entry(3, "@not-a-feed"),
+        ];
+        let jsonl = lines.join("\n");
+
+        let offset_path = "/tmp/test_import_jsonl_lenient.offset";
+        let db_path = "/tmp/test_import_jsonl_lenient.sqlite3";
+        let _ = std::fs::remove_file(offset_path);
+        let db = SqliteSsbDb::new(db_path, offset_path);
+
+        let report = db.import_jsonl_lenient(&mut jsonl.as_bytes()).unwrap();
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(
+            report
+                .errors
+                .iter()
+                .map(|(line_no, _)| *line_no)
+                .collect::<Vec<_>>(),
+            vec![2, 6]
+        );
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(2));
+        assert_eq!(
+            db.get_entry_by_seq(&author, 2).unwrap(),
+            Some(lines[3].clone().into_bytes())
+        );
+
+        // Importing it again adds nothing.
+        let report = db.import_jsonl_lenient(&mut jsonl.as_bytes()).unwrap();
+        assert_eq!(report.imported, 0);
+        assert_eq!(report.skipped, 3);
+        assert_eq!(report.errors.len(), 2);
+
+        let report = db.import_jsonl_lenient(&mut "".as_bytes()).unwrap();
+        assert_eq!(report, ImportReport::default());
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn get_entries_at_offsets_works() {
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+        let entries = log.iter().take(3).collect::<Vec<_>>();
+
+        let db_path = "/tmp/test_get_entries_at_offsets.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_log_path);
+
+        let offsets = vec![entries[2].offset, u64::MAX, entries[0].offset];
+        let res = db.get_entries_at_offsets(&offsets).unwrap();
+
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0].as_ref().unwrap(), &entries[2].data);
+        assert!(res[1].is_err());
+        assert_eq!(res[2].as_ref().unwrap(), &entries[0].data);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_feed_byte_sizes_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let entries = log.iter().map(|entry| entry.data).collect::<Vec<_>>();
+        let expected_size = entries.iter().map(|entry| entry.len() as u64).sum::<u64>();
+
+        let db_path = "/tmp/test_get_feed_byte_sizes.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_log_path);
+        db.update_indexes_from_offset_file().unwrap();
+
+        let sizes = db.get_feed_byte_sizes().unwrap();
+        assert_eq!(sizes, vec![(author, expected_size)]);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn append_batch_deferred_works() {
+        let expected_seq = 6006;
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let entries = log.iter().map(|entry| entry.data).collect::<Vec<_>>();
+
+        let db_path = "/tmp/test_append_batch_deferred.sqlite3";
+        let offset_path = "/tmp/test_append_batch_deferred.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+
+        db.append_batch_deferred(&author, &entries[..3000]).unwrap();
+        db.append_batch_deferred(&author, &entries[3000..]).unwrap();
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), None);
+
+        db.update_indexes_from_offset_file().unwrap();
+        assert_eq!(
+            db.get_feed_latest_sequence(&author).unwrap(),
+            Some(expected_seq)
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn get_author_by_key_works() {
+        let key_str = "%/v5mCnV/kmnVtnF3zXtD4tbzoEQo4kRq/0d/bgxP1WI=.sha256";
+        let key = Multihash::from_legacy(key_str.as_bytes()).unwrap().0;
+        let missing_key_str = "%AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.sha256";
+        let missing_key = Multihash::from_legacy(missing_key_str.as_bytes())
+            .unwrap()
+            .0;
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_get_author_by_key.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(db.get_author_by_key(&key).unwrap(), Some(author));
+        assert_eq!(db.get_author_by_key(&missing_key).unwrap(), None);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn append_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let db_path = "/tmp/test_append.sqlite3";
+        let offset_path = "/tmp/test_append.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+
+        log.iter()
+            .take(3)
+            .for_each(|entry| db.append(&author, entry.data).unwrap());
+
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(3));
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn get_content_types_works() {
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let mut expected = std::collections::HashMap::new();
+        log.iter()
+            .flat_map(|entry| serde_json::from_slice::<SsbMessage>(&entry.data))
+            .filter_map(|msg| msg.value.content_type().map(|t| t.to_owned()))
+            .for_each(|content_type| *expected.entry(content_type).or_insert(0) += 1);
+
+        let db_path = "/tmp/test_get_content_types.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_log_path);
+        db.update_indexes_from_offset_file().unwrap();
+
+        let content_types = db.get_content_types().unwrap();
+
+        assert_eq!(content_types.len(), expected.len());
+        content_types
+            .iter()
+            .for_each(|(content_type, count)| assert_eq!(expected[content_type], *count));
+        assert!(content_types.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_content_types_counts_each_type() {
+        let author_str = format!("@{:042}0=.ed25519", 1);
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let entries = ["post", "vote", "post", "contact", "vote", "post"]
+            .iter()
+            .enumerate()
+            .map(|(i, content_type)| {
+                serde_json::to_vec(&serde_json::json!({
+                    "key": format!("%{:042}0=.sha256", i + 1),
+                    "value": {
+                        "author": author_str,
+                        "sequence": i + 1,
+                        "content": { "type": content_type }
+                    }
+                }))
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let offset_path = "/tmp/test_get_content_types_counts.offset";
+        let db_path = "/tmp/test_get_content_types_counts.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &entries).unwrap();
+
+        assert_eq!(
+            db.get_content_types().unwrap(),
+            vec![
+                ("post".to_owned(), 3),
+                ("vote".to_owned(), 2),
+                ("contact".to_owned(), 1)
+            ]
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn close_checkpoints_the_wal() {
+        use diesel::connection::{Connection, SimpleConnection};
+        use diesel::sqlite::SqliteConnection;
+
+        let db_path = "/tmp/test_close_checkpoints.sqlite3";
+        let wal_path = "/tmp/test_close_checkpoints.sqlite3-wal";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset")
+            .with_wal()
+            .unwrap();
+        // Keep another connection open so closing the db doesn't remove the WAL itself.
+        let observer = SqliteConnection::establish(db_path).unwrap();
+        observer
+            .batch_execute("SELECT COUNT(*) FROM sqlite_master;")
+            .unwrap();
+
+        db.update_indexes_from_offset_file().unwrap();
+        assert!(std::fs::metadata(wal_path).unwrap().len() > 0);
+
+        db.close().unwrap();
+        assert_eq!(std::fs::metadata(wal_path).unwrap().len(), 0);
+
+        drop(observer);
+        std::fs::remove_file(db_path).unwrap();
+        let _ = std::fs::remove_file(wal_path);
+        let _ = std::fs::remove_file("/tmp/test_close_checkpoints.sqlite3-shm");
+    }
+    #[test]
+    fn from_connection_works() {
+        use diesel::connection::{Connection, SimpleConnection};
+        use diesel::sqlite::SqliteConnection;
+
+        let expected_seq = 6006;
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_from_connection.sqlite3";
+        let connection = SqliteConnection::establish(db_path).unwrap();
+        connection
+            .batch_execute(
+                "CREATE TABLE my_app (id INTEGER PRIMARY KEY); INSERT INTO my_app VALUES (1);",
+            )
+            .unwrap();
+
+        let db = SqliteSsbDb::from_connection(connection, "./test_vecs/piet.offset").unwrap();
+        db.update_indexes_from_offset_file().unwrap();
+        assert_eq!(
+            db.get_feed_latest_sequence(&author).unwrap(),
+            Some(expected_seq)
+        );
+
+        db.rebuild_indexes().unwrap();
+        assert_eq!(
+            db.get_feed_latest_sequence(&author).unwrap(),
+            Some(expected_seq)
+        );
+        drop(db);
+
+        let connection = SqliteConnection::establish(db_path).unwrap();
+        assert!(connection.batch_execute("SELECT id FROM my_app;").is_ok());
+        drop(connection);
+        std::fs::remove_file(db_path).unwrap();
+
+        // A connection that can't be migrated is an error, not a panic.
+        SqliteConnection::establish(db_path)
+            .unwrap()
+            .batch_execute("CREATE TABLE my_app (id INTEGER PRIMARY KEY);")
+            .unwrap();
+        let read_only = SqliteConnection::establish(&format!("file:{}?mode=ro", db_path)).unwrap();
+        match SqliteSsbDb::from_connection(read_only, "./test_vecs/piet.offset") {
+            Err(Error::SqliteMigrationError {}) => {}
+            _ => panic!("expected SqliteMigrationError"),
+        }
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn busy_retries_wait_for_lock() {
+        use diesel::connection::{Connection, SimpleConnection};
+        use diesel::sqlite::SqliteConnection;
+        use std::sync::mpsc::channel;
+        use std::thread;
+        use std::time::Duration;
+
+        let expected_seq = 6006;
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_busy_retries.sqlite3";
+        let mut db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+
+        let lock_db = |hold_for| {
+            let (locked_tx, locked_rx) = channel();
+            let handle = thread::spawn(move || {
+                let connection = SqliteConnection::establish(db_path).unwrap();
+                connection.batch_execute("BEGIN IMMEDIATE;").unwrap();
+                locked_tx.send(()).unwrap();
+                thread::sleep(hold_for);
+                connection.batch_execute("COMMIT;").unwrap();
+            });
+            locked_rx.recv().unwrap();
+            handle
+        };
+
+        db.set_busy_retries(0);
+        let handle = lock_db(Duration::from_millis(100));
+        assert!(db.update_indexes_from_offset_file().is_err());
+        handle.join().unwrap();
+
+        db.set_busy_retries(20);
+        let handle = lock_db(Duration::from_millis(100));
+        db.update_indexes_from_offset_file().unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(
+            db.get_feed_latest_sequence(&author).unwrap(),
+            Some(expected_seq)
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn get_feed_chain_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_get_feed_chain.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let chain = db.get_feed_chain(&author).unwrap();
+
+        assert_eq!(chain.len(), 6006);
+        assert_eq!(chain[0].0, 1);
+        assert_eq!(chain[0].1, None);
+        assert!(chain
+            .windows(2)
+            .all(|pair| pair[1].0 == pair[0].0 + 1 && pair[1].1.as_ref() == Some(&pair[0].2)));
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn set_hidden_works() {
+        let key_str = "%/v5mCnV/kmnVtnF3zXtD4tbzoEQo4kRq/0d/bgxP1WI=.sha256";
+        let key = Multihash::from_legacy(key_str.as_bytes()).unwrap().0;
+        let missing_key_str = "%AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.sha256";
+        let missing_key = Multihash::from_legacy(missing_key_str.as_bytes())
+            .unwrap()
+            .0;
+
+        let db_path = "/tmp/test_set_hidden.sqlite3";
+        let mut db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let author = db.get_author_by_key(&key).unwrap().unwrap();
+        let seq = db.get_seq_by_key(&key).unwrap().unwrap();
+
+        db.set_hidden(&key, true).unwrap();
+        assert!(db.get_entry_by_key(&key).is_err());
+        assert_eq!(db.get_author_by_key(&key).unwrap(), None);
+        assert_eq!(db.get_seq_by_key(&key).unwrap(), None);
+        assert_eq!(db.get_next_entry(&key).unwrap(), None);
+        // Still replicated, so peers don't see a gap in the feed.
+        let entries = db
+            .create_history_stream(&author, seq - 1, Some(1), true, false)
+            .unwrap();
+        assert_eq!(entries, vec![key_str.as_bytes().to_vec()]);
+        assert!(db
+            .missing_keys(std::slice::from_ref(&key))
+            .unwrap()
+            .is_empty());
+
+        db.set_include_hidden(true);
+        assert!(db.get_entry_by_key(&key).is_ok());
+        assert_eq!(db.get_seq_by_key(&key).unwrap(), Some(seq));
+        db.set_include_hidden(false);
+
+        // It's kept when the indexes are rebuilt, eg. by maintenance.
+        db.rebuild_indexes().unwrap();
+        assert!(db.get_entry_by_key(&key).is_err());
+
+        db.set_hidden(&key, false).unwrap();
+        assert!(db.get_entry_by_key(&key).is_ok());
+        db.rebuild_indexes().unwrap();
+        assert!(db.get_entry_by_key(&key).is_ok());
+
+        assert!(db.set_hidden(&missing_key, true).is_err());
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn maintenance_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let mut entries = log
+            .iter()
+            .map(|entry| entry.data)
+            .take(10)
+            .collect::<Vec<_>>();
+        entries.insert(5, vec![0; 100]);
+
+        let db_path = "/tmp/test_maintenance.sqlite3";
+        let offset_path = "/tmp/test_maintenance.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &entries).unwrap();
+        let hidden = serde_json::from_slice::<SsbMessage>(&entries[0])
+            .unwrap()
+            .key;
+        let hidden = Multihash::from_legacy(hidden.as_bytes()).unwrap().0;
+        db.set_hidden(&hidden, true).unwrap();
+
+        let report = db.maintenance().unwrap();
+
+        assert_eq!(report.entries_removed, 1);
+        assert!(report.offset_log_bytes_reclaimed >= 100);
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(10));
+        assert!(db.get_entry_by_seq(&author, 6).unwrap().is_some());
+        // Reindexing the compacted log keeps the local state.
+        assert_eq!(db.get_entry_by_seq(&author, 1).unwrap(), None);
+        assert_eq!(
+            OffsetLog::<u32>::new(offset_path).unwrap().iter().count(),
+            10
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn sequence_lookups_use_author_seq_index() {
+        use diesel::connection::Connection;
+        use diesel::sql_types::Text;
+        use diesel::sqlite::SqliteConnection;
+        use diesel::RunQueryDsl;
+
+        #[derive(QueryableByName)]
+        struct QueryPlan {
+            #[sql_type = "Text"]
+            detail: String,
+        }
+
+        let db_path = "/tmp/test_sequence_lookups_use_index.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let connection = SqliteConnection::establish(db_path).unwrap();
+        let plan = diesel::sql_query(
+            "EXPLAIN QUERY PLAN SELECT messages.flume_seq FROM authors
+            INNER JOIN messages ON messages.author_id = authors.id
+            WHERE messages.seq = 10 AND authors.author = 'author'",
+        )
+        .load::<QueryPlan>(&connection)
+        .unwrap();
+
+        assert!(plan
+            .iter()
+            .any(|row| row.detail.contains("messages_author_id_seq_index")));
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    #[ignore]
+    fn bench_sequence_lookup_on_large_feed() {
+        use std::time::Instant;
+
+        let num_messages = 100_000;
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let entries = (1..=num_messages)
+            .map(|seq| {
+                serde_json::to_vec(&serde_json::json!({
+                    // Ends in `0` so the key is canonical base64.
+                    "key": format!("%{:042}0=.sha256", seq),
+                    "value": {
+                        "author": author_str,
+                        "sequence": seq,
+                        "content": { "type": "post", "text": "hello" }
+                    }
+                }))
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let db_path = "/tmp/bench_sequence_lookup.sqlite3";
+        let offset_path = "/tmp/bench_sequence_lookup.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &entries).unwrap();
+
+        let lookups = 1000;
+        let started = Instant::now();
+        (0..lookups).for_each(|i| {
+            let seq = (i * 97) % num_messages + 1;
+            assert!(db.get_entry_by_seq(&author, seq).unwrap().is_some());
+        });
+        let per_lookup = started.elapsed() / lookups as u32;
+
+        // Timings depend on the machine, so they're reported rather than checked.
+        eprintln!("get_entry_by_seq: {:?} per lookup", per_lookup);
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn stream_feed_values_matches_get_entries_newer_than_sequence() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_stream_feed_values.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let expected = db
+            .get_entries_newer_than_sequence(&author, 5900, None, false, true)
+            .unwrap();
+        let streamed = db
+            .stream_feed_values(&author, 5900)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(streamed.len(), 106);
+        assert_eq!(streamed, expected);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn stream_values_newer_than_sequence_matches_stream_feed_values() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_stream_values_newer_than_sequence.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let expected = db
+            .stream_feed_values(&author, 5900)
+            .unwrap()
+            .map(|value| serde_json::from_slice::<serde_json::Value>(&value.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        let streamed = db
+            .stream_values_newer_than_sequence(&author, 5900)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(streamed.len(), 106);
+        assert_eq!(streamed[0]["sequence"], 5901);
+        assert_eq!(streamed, expected);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn handle_fork_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let entries = log
+            .iter()
+            .map(|entry| entry.data)
+            .take(10)
+            .collect::<Vec<_>>();
+        let mut fork = serde_json::from_slice::<serde_json::Value>(&entries[5]).unwrap();
+        fork["key"] = serde_json::Value::String(
+            "%AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.sha256".into(),
+        );
+        let fork = serde_json::to_vec(&fork).unwrap();
+
+        let db_path = "/tmp/test_handle_fork.sqlite3";
+        let offset_path = "/tmp/test_handle_fork.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+
+        db.append_batch(&author, &entries).unwrap();
+        assert_eq!(db.find_feed_fork(&author).unwrap(), None);
+
+        db.append(&author, fork).unwrap();
+        assert_eq!(db.find_feed_fork(&author).unwrap(), Some(6));
+
+        let mut removed = Vec::new();
+        let count = db
+            .handle_fork_with(&author, 5, |entry| removed.push(entry.to_vec()))
+            .unwrap();
+
+        assert_eq!(count, 6);
+        assert_eq!(removed.len(), 6);
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(5));
+        assert_eq!(db.find_feed_fork(&author).unwrap(), None);
+
+        db.rebuild_indexes().unwrap();
+        assert_eq!(db.get_feed_latest_sequence(&author).unwrap(), Some(5));
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn from_gzipped_offset_log_works() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let expected_seq = 6006;
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let gzipped_path = "/tmp/test_from_gzipped_offset_log.offset.gz";
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder
+            .write_all(&std::fs::read("./test_vecs/piet.offset").unwrap())
+            .unwrap();
+        std::fs::write(gzipped_path, encoder.finish().unwrap()).unwrap();
+
+        let db_path = "/tmp/test_from_gzipped_offset_log.sqlite3";
+        let db = SqliteSsbDb::from_gzipped_offset_log(db_path, gzipped_path).unwrap();
+        db.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(
+            db.get_feed_latest_sequence(&author).unwrap(),
+            Some(expected_seq)
+        );
+
+        let not_gzipped = SqliteSsbDb::from_gzipped_offset_log(db_path, "./test_vecs/piet.offset");
+        assert!(matches!(
+            not_gzipped,
+            Err(Error::OffsetDecompressError { .. })
+        ));
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(gzipped_path).unwrap();
+    }
+    #[test]
+    fn get_seq_by_key_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let missing_key_str = "%AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.sha256";
+        let missing_key = Multihash::from_legacy(missing_key_str.as_bytes())
+            .unwrap()
+            .0;
+
+        let db_path = "/tmp/test_get_seq_by_key.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let entry = db.get_entry_by_seq(&author, 42).unwrap().unwrap();
+        let key_str = serde_json::from_slice::<SsbMessage>(&entry).unwrap().key;
+        let key = Multihash::from_legacy(key_str.as_bytes()).unwrap().0;
+
+        assert_eq!(db.get_seq_by_key(&key).unwrap(), Some(42));
+        assert_eq!(db.get_seq_by_key(&missing_key).unwrap(), None);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn diff_frontier_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let unknown_str = "@AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.ed25519";
+        let unknown = Multikey::from_legacy(unknown_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_diff_frontier.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(
+            db.diff_frontier(&[(author.clone(), 6006), (unknown.clone(), 0)])
+                .unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            db.diff_frontier(&[(author.clone(), 6010), (unknown.clone(), 3)])
+                .unwrap(),
+            vec![(author, 6006), (unknown, 0)]
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn common_frontier_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let unknown_str = "@AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=.ed25519";
+        let unknown = Multikey::from_legacy(unknown_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_common_frontier.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(
+            db.common_frontier(&[(unknown.clone(), 3), (author.clone(), 6010)])
+                .unwrap(),
+            vec![(unknown.clone(), 0), (author.clone(), 6006)]
+        );
+        assert_eq!(
+            db.common_frontier(&[(author.clone(), 6006), (author.clone(), 10)])
+                .unwrap(),
+            vec![(author.clone(), 6006), (author.clone(), 10)]
+        );
+        assert_eq!(
+            db.common_frontier(&[(author.clone(), 0), (author.clone(), -1)])
+                .unwrap(),
+            vec![(author.clone(), 0), (author, 0)]
+        );
+        assert!(db.common_frontier(&[]).unwrap().is_empty());
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn with_shared_cache_shares_in_memory_db() {
+        let expected_seq = 6006;
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let writer = SqliteSsbDb::new(":memory:", "./test_vecs/piet.offset")
+            .with_shared_cache()
+            .unwrap();
+        let reader = SqliteSsbDb::new(":memory:", "./test_vecs/piet.offset")
+            .with_shared_cache()
+            .unwrap();
+        writer.update_indexes_from_offset_file().unwrap();
+
+        assert_eq!(
+            reader.get_feed_latest_sequence(&author).unwrap(),
+            Some(expected_seq)
+        );
+    }
+    #[test]
+    fn get_feed_entries_by_type_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let expected_count = log
+            .iter()
+            .flat_map(|entry| serde_json::from_slice::<SsbMessage>(&entry.data))
+            .filter(|msg| msg.value.content_type() == Some("post"))
+            .count();
+
+        let db_path = "/tmp/test_get_feed_entries_by_type.sqlite3";
+        let db = SqliteSsbDb::new(db_path, offset_log_path);
+        db.update_indexes_from_offset_file().unwrap();
+
+        let posts = db
+            .get_feed_entries_by_type(&author, "post", None)
+            .unwrap()
+            .iter()
+            .map(|entry| serde_json::from_slice::<SsbMessage>(entry).unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(expected_count > 0);
+        assert_eq!(posts.len(), expected_count);
+        assert!(posts
+            .iter()
+            .all(|msg| msg.value.content_type() == Some("post")));
+        assert!(posts
+            .windows(2)
+            .all(|pair| pair[0].value.sequence > pair[1].value.sequence));
+
+        let limited = db
+            .get_feed_entries_by_type(&author, "post", Some(3))
+            .unwrap();
+        assert_eq!(limited.len(), 3);
+
+        let unlimited = db
+            .get_feed_entries_by_type(&author, "post", Some(-1))
+            .unwrap();
+        assert_eq!(unlimited.len(), expected_count);
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn common_content_type_maps_to_strings() {
+        let known = [
+            CommonContentType::Post,
+            CommonContentType::Contact,
+            CommonContentType::Vote,
+            CommonContentType::About,
+            CommonContentType::Pub,
+        ];
+        known.iter().for_each(|content_type| {
+            assert_eq!(
+                &CommonContentType::from(content_type.as_str()),
+                content_type
+            )
+        });
+
+        assert_eq!(CommonContentType::Contact.as_str(), "contact");
+        assert_eq!(
+            CommonContentType::from("git-update"),
+            CommonContentType::Other("git-update".to_owned())
+        );
+        assert_eq!(CommonContentType::from("git-update").as_str(), "git-update");
+    }
+    #[test]
+    fn get_feed_entries_by_common_type_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_get_feed_entries_by_common_type.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let posts = db
+            .get_feed_entries_by_common_type(&author, &CommonContentType::Post, None)
+            .unwrap();
+        assert!(!posts.is_empty());
+        assert_eq!(
+            posts,
+            db.get_feed_entries_by_type(&author, "post", None).unwrap()
+        );
+
+        // The db only has the one feed, so every post in it is one of the feed's.
+        let all_posts = db
+            .get_entries_by_common_type(&CommonContentType::Post)
+            .unwrap();
+        assert_eq!(all_posts, db.get_entries_by_type("post").unwrap());
+        assert_eq!(all_posts.len(), posts.len());
+        assert!(db.get_entries_by_type("contuct").unwrap().is_empty());
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn rebuild_indexes_reports_skipped_entries() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let mut entries = log
+            .iter()
+            .map(|entry| entry.data)
+            .take(10)
+            .collect::<Vec<_>>();
+        entries.insert(3, vec![0; 50]);
+        entries.insert(7, vec![0; 50]);
+
+        let db_path = "/tmp/test_rebuild_indexes_stats.sqlite3";
+        let offset_path = "/tmp/test_rebuild_indexes_stats.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &entries).unwrap();
+
+        let stats = db.rebuild_indexes().unwrap();
+        assert_eq!(
+            stats,
+            IndexStats {
+                indexed: 10,
+                skipped: 2
+            }
+        );
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn feed_handle_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+
+        let db_path = "/tmp/test_feed_handle.sqlite3";
+        let db = SqliteSsbDb::new(db_path, "./test_vecs/piet.offset");
+        db.update_indexes_from_offset_file().unwrap();
+
+        let feed = db.feed(&author);
+
+        assert_eq!(feed.feed_id(), &author);
+        assert_eq!(feed.latest_seq().unwrap(), Some(6006));
+        assert!(feed.entry_at(100).unwrap().is_some());
+        assert_eq!(
+            feed.entries_newer_than(6000, None, true, true)
+                .unwrap()
+                .len(),
+            6
+        );
+        assert_eq!(feed.keys().unwrap().len(), 6006);
+        assert!(feed.is_complete().unwrap());
+
+        std::fs::remove_file(db_path).unwrap();
+    }
+    #[test]
+    fn validate_entries_reports_corrupt_entry() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let mut entries = log
+            .iter()
+            .map(|entry| entry.data)
+            .take(3)
+            .collect::<Vec<_>>();
+        entries.push(vec![0; 50]);
+
+        let db_path = "/tmp/test_validate_entries.sqlite3";
+        let offset_path = "/tmp/test_validate_entries.offset";
+        let mut db = SqliteSsbDb::new(db_path, offset_path);
+        db.append_batch(&author, &entries).unwrap();
+
+        let offsets = OffsetLog::<u32>::new(offset_path)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.offset)
+            .collect::<Vec<_>>();
+
+        let res = db.get_entries_at_offsets(&offsets).unwrap();
+        assert!(res.iter().all(|entry| entry.is_ok()));
+
+        db.set_validate_entries(true);
+        let res = db.get_entries_at_offsets(&offsets).unwrap();
+        assert!(res[..3].iter().all(|entry| entry.is_ok()));
+        match &res[3] {
+            Err(Error::CorruptEntry { offset }) => assert_eq!(*offset, offsets[3]),
+            res => panic!("expected a corrupt entry, got {:?}", res),
+        }
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+    }
+    #[test]
+    fn with_mirror_works() {
+        let author_str = "@U5GvOKP/YUza9k53DSXxT0mk3PIrnyAmessvNfZl5E0=.ed25519";
+        let author = Multikey::from_legacy(author_str.as_bytes()).unwrap().0;
+        let offset_log_path = "./test_vecs/piet.offset";
+        let log = OffsetLog::<u32>::new(offset_log_path).unwrap();
+
+        let entries = log
+            .iter()
+            .map(|entry| entry.data)
+            .take(20)
+            .collect::<Vec<_>>();
+
+        let db_path = "/tmp/test_with_mirror.sqlite3";
+        let offset_path = "/tmp/test_with_mirror.offset";
+        let mirror_path = "/tmp/test_with_mirror_mirror.offset";
+        let db = SqliteSsbDb::new(db_path, offset_path)
+            .with_mirror(mirror_path)
+            .unwrap();
+
+        db.append_batch(&author, &entries[..10]).unwrap();
+        db.append_batch(&author, &entries[10..]).unwrap();
+
+        let mirrored = OffsetLog::<u32>::new(mirror_path)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.data)
+            .collect::<Vec<_>>();
+        assert_eq!(mirrored, entries);
+        drop(db);
+
+        // A mirror that can't be opened is an error, not a panic.
+        let db = SqliteSsbDb::new(db_path, offset_path);
+        match db.with_mirror("/nonexistent/test_with_mirror_mirror.offset") {
+            Err(Error::OffsetWriteError {}) => {}
+            _ => panic!("expected OffsetWriteError"),
+        }
+
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(offset_path).unwrap();
+        std::fs::remove_file(mirror_path).unwrap();
     }
 }
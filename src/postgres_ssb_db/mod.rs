@@ -0,0 +1,246 @@
+//! An [SsbDb] that keeps its indexes in Postgres rather than sqlite.
+//!
+//! Only available with the `postgres` feature.
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use flumedb::offset_log::OffsetLog;
+use flumedb::{FlumeLog, IterAtOffset};
+use snafu::ResultExt;
+use ssb_multiformats::multihash::Multihash;
+use ssb_multiformats::multikey::Multikey;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::db;
+use crate::error::*;
+use crate::sqlite_ssb_db::{open_offset_log, select_entry_parts};
+use crate::ssb_message::{SerdeJsonParser, SsbMessageParser};
+use crate::{FlumeSequence, IndexStats, SsbDb};
+
+use db::postgres::{
+    append_item, clear_indexes, find_feed_flume_seqs_newer_than, find_feed_latest_seq,
+    find_indexed_offset, find_message_flume_seq_by_author_and_sequence,
+    find_message_flume_seq_by_key, set_indexed_offset,
+};
+
+embed_migrations!("migrations_postgres");
+
+const INDEX_CHUNK_SIZE: usize = 10000;
+
+/// Like [SqliteSsbDb](crate::SqliteSsbDb), but with the indexes in a Postgres db, eg. for a large
+/// pub that wants its indexes where other tools can get at them. The offset log is still the
+/// source of truth, so the indexes can be dropped and rebuilt from it.
+///
+/// Only the [SsbDb] trait is implemented. The extra queries and local state of
+/// [SqliteSsbDb](crate::SqliteSsbDb), eg. hidden messages, need its sqlite indexes.
+pub struct PostgresSsbDb {
+    connection: RefCell<PgConnection>,
+    offset_log: Arc<Mutex<OffsetLog<u32>>>,
+    parser: Box<dyn SsbMessageParser>,
+}
+
+impl PostgresSsbDb {
+    /// Open the offset log at `offset_log_path`, with its indexes in the Postgres db at
+    /// `database_url`, eg. `postgres://localhost/ssb`. The migrations in `migrations_postgres` are
+    /// run on the db.
+    ///
+    /// Nothing is indexed until you call [PostgresSsbDb::update_indexes_from_offset_file]. Fails
+    /// with [Error::PostgresConnectError] if the db can't be connected to.
+    pub fn new<S: AsRef<str>>(database_url: S, offset_log_path: S) -> Result<PostgresSsbDb> {
+        PostgresSsbDb::new_with_parser(database_url, offset_log_path, SerdeJsonParser)
+    }
+
+    /// Like [PostgresSsbDb::new], but uses `parser` to parse messages when indexing.
+    pub fn new_with_parser<S: AsRef<str>, P: 'static + SsbMessageParser>(
+        database_url: S,
+        offset_log_path: S,
+        parser: P,
+    ) -> Result<PostgresSsbDb> {
+        let connection = PgConnection::establish(database_url.as_ref())
+            .map_err(|_| Error::PostgresConnectError {})?;
+        embedded_migrations::run(&connection).map_err(|_| Error::PostgresMigrationError {})?;
+
+        Ok(PostgresSsbDb {
+            connection: RefCell::new(connection),
+            offset_log: open_offset_log(offset_log_path.as_ref()),
+            parser: Box::new(parser),
+        })
+    }
+
+    /// Index any entries in the offset log that haven't been indexed yet.
+    ///
+    /// Returns how many entries were indexed, and how many were skipped because they aren't
+    /// messages. Like [SqliteSsbDb::update_indexes_from_offset_file](crate::SqliteSsbDb::update_indexes_from_offset_file),
+    /// a message whose key is already indexed for a different message is skipped too, then this
+    /// fails with [Error::KeyCollision] once the rest are indexed.
+    ///
+    /// If the indexes refer to entries past the end of the offset log, eg. because the log was
+    /// swapped for a shorter one, the indexes are cleared and the whole log is reindexed.
+    pub fn update_indexes_from_offset_file(&self) -> Result<IndexStats> {
+        let connection = self.connection.borrow();
+        let offset_log = self.offset_log();
+
+        let mut last_indexed =
+            find_indexed_offset(&connection).context(UnableToGetLatestSequence)?;
+        if let Some(offset) = last_indexed {
+            if offset >= offset_log.end() {
+                clear_indexes(&connection).context(PostgresClearError)?;
+                last_indexed = None;
+            }
+        }
+
+        let mut stats = IndexStats::default();
+        let mut collision = None;
+
+        loop {
+            let (commit_stats, last_offset, commit_collision) = connection
+                .transaction::<_, db::AppendError, _>(|| {
+                    self.index_entries_after(&connection, &offset_log, last_indexed)
+                })
+                .map_err(|_| Error::PostgresAppendError {})?;
+
+            stats.indexed += commit_stats.indexed;
+            stats.skipped += commit_stats.skipped;
+            collision = collision.or(commit_collision);
+
+            match last_offset {
+                Some(last_offset) => last_indexed = Some(last_offset),
+                None => break,
+            }
+        }
+
+        match collision {
+            Some(key) => Err(Error::KeyCollision { key }),
+            None => Ok(stats),
+        }
+    }
+
+    // Index up to `INDEX_CHUNK_SIZE` entries after the `after` offset (or from the start of the
+    // log if `None`). Returns the offset of the last entry, and the key of the first entry skipped
+    // because its key belongs to a different message.
+    fn index_entries_after(
+        &self,
+        connection: &PgConnection,
+        offset_log: &OffsetLog<u32>,
+        after: Option<FlumeSequence>,
+    ) -> std::result::Result<(IndexStats, Option<FlumeSequence>, Option<String>), db::AppendError>
+    {
+        let (starting_offset, num_to_skip) = match after {
+            Some(after) => (after, 1),
+            None => (0, 0),
+        };
+
+        let mut stats = IndexStats::default();
+        let mut last_offset = None;
+        let mut collision = None;
+
+        for log_entry in offset_log
+            .iter_at_offset(starting_offset)
+            .skip(num_to_skip)
+            .take(INDEX_CHUNK_SIZE)
+        {
+            let indexed = match append_item(
+                connection,
+                self.parser.as_ref(),
+                log_entry.offset,
+                &log_entry.data,
+            ) {
+                Err(db::AppendError::KeyCollision { key }) => {
+                    collision.get_or_insert(key);
+                    false
+                }
+                indexed => indexed?,
+            };
+            if indexed {
+                stats.indexed += 1;
+            } else {
+                stats.skipped += 1;
+            }
+            last_offset = Some(log_entry.offset);
+        }
+
+        // Skipped entries aren't in the messages table, so remember we got past them.
+        if let Some(last_offset) = last_offset {
+            set_indexed_offset(connection, last_offset)?;
+        }
+
+        Ok((stats, last_offset, collision))
+    }
+
+    fn get_entry(&self, offset: FlumeSequence) -> Result<Vec<u8>> {
+        self.offset_log()
+            .get(offset)
+            .map_err(|_| Error::OffsetGetError {})
+    }
+
+    // A panic while appending can't leave the log half-written, so carry on past a poisoned lock.
+    fn offset_log(&self) -> MutexGuard<'_, OffsetLog<u32>> {
+        self.offset_log
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl SsbDb for PostgresSsbDb {
+    fn append_batch<T: AsRef<[u8]>>(&self, _: &Multikey, messages: &[T]) -> Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        self.offset_log()
+            .append_batch(messages)
+            .map_err(|_| Error::OffsetAppendError {})?;
+
+        self.update_indexes_from_offset_file().map(|_| ())
+    }
+    fn get_entry_by_key(&self, message_key: &Multihash) -> Result<Vec<u8>> {
+        let flume_seq = find_message_flume_seq_by_key(
+            &self.connection.borrow(),
+            &message_key.to_legacy_string(),
+        )
+        .context(MessageNotFound)?;
+        self.get_entry(flume_seq)
+    }
+    fn get_entry_by_seq(&self, feed_id: &Multikey, sequence: i32) -> Result<Option<Vec<u8>>> {
+        find_message_flume_seq_by_author_and_sequence(
+            &self.connection.borrow(),
+            &feed_id.to_legacy_string(),
+            sequence,
+        )
+        .context(MessageNotFound)?
+        .map(|flume_seq| self.get_entry(flume_seq))
+        .transpose()
+    }
+    fn get_feed_latest_sequence(&self, feed_id: &Multikey) -> Result<Option<i32>> {
+        find_feed_latest_seq(&self.connection.borrow(), &feed_id.to_legacy_string())
+            .context(FeedNotFound)
+    }
+    fn get_entries_newer_than_sequence(
+        &self,
+        feed_id: &Multikey,
+        sequence: i32,
+        limit: Option<i64>,
+        include_keys: bool,
+        include_values: bool,
+    ) -> Result<Vec<Vec<u8>>> {
+        if !include_keys && !include_values {
+            return Err(Error::IncludeKeysIncludeValuesBothFalse {});
+        }
+        let seqs = find_feed_flume_seqs_newer_than(
+            &self.connection.borrow(),
+            &feed_id.to_legacy_string(),
+            sequence,
+            limit,
+        )
+        .context(FeedNotFound)?;
+
+        seqs.iter()
+            .map(|seq| {
+                select_entry_parts(*seq, self.get_entry(*seq)?, include_keys, include_values)
+            })
+            .collect()
+    }
+    fn rebuild_indexes(&self) -> Result<IndexStats> {
+        clear_indexes(&self.connection.borrow()).context(PostgresClearError)?;
+        self.update_indexes_from_offset_file()
+    }
+}
@@ -0,0 +1,44 @@
+//! Per-feed locks, so writes to the same feed happen one at a time while writes to different
+//! feeds can go ahead in parallel.
+use std::collections::BTreeSet;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+// The feeds being written to right now. Feeds are keyed by the offset log they're written to, so
+// dbs sharing a log (see `SqliteSsbDb::from_shared_log`) share locks too.
+static LOCKED_FEEDS: Mutex<BTreeSet<(usize, String)>> = Mutex::new(BTreeSet::new());
+static FEED_UNLOCKED: Condvar = Condvar::new();
+
+/// Holds the lock on a feed until it's dropped.
+pub(super) struct FeedLock {
+    key: (usize, String),
+}
+
+/// Block until no one else is writing to `feed` in the offset log identified by `log_id`, then
+/// lock it.
+pub(super) fn lock_feed(log_id: usize, feed: String) -> FeedLock {
+    let key = (log_id, feed);
+    let mut locked = locked_feeds();
+    while locked.contains(&key) {
+        locked = FEED_UNLOCKED
+            .wait(locked)
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+    locked.insert(key.clone());
+
+    FeedLock { key }
+}
+
+impl Drop for FeedLock {
+    fn drop(&mut self) {
+        locked_feeds().remove(&self.key);
+        FEED_UNLOCKED.notify_all();
+    }
+}
+
+// Nothing panics while the set is locked, but carry on past a poisoned lock anyway rather than
+// stopping every write.
+fn locked_feeds() -> MutexGuard<'static, BTreeSet<(usize, String)>> {
+    LOCKED_FEEDS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -1,140 +1,2365 @@
 use flumedb::offset_log::OffsetLog;
 use flumedb::{FlumeLog, IterAtOffset};
 
+use diesel::connection::{SimpleConnection, TransactionManager};
 use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
 use diesel_migrations::any_pending_migrations;
+use flate2::read::GzDecoder;
+#[cfg(feature = "async")]
+use futures::stream::{Stream, StreamExt};
 use itertools::Itertools;
 use snafu::{OptionExt, ResultExt};
 use ssb_legacy_msg_data;
 use ssb_legacy_msg_data::value::Value;
 use ssb_multiformats::multihash::Multihash;
 use ssb_multiformats::multikey::Multikey;
-use std::cell::RefCell;
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::io::{BufRead, Seek, SeekFrom, Write};
+use std::ops::{Deref, RangeInclusive};
+use std::path::Path;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod feed_locks;
+mod query_timeout;
+#[cfg(feature = "test-util")]
+mod test_util;
+#[cfg(feature = "watch")]
+mod watch;
+
+#[cfg(feature = "watch")]
+pub use watch::WatchHandle;
 
 use crate::db;
 use crate::error::*;
-use crate::ssb_message::SsbMessage;
-use crate::SsbDb;
+use crate::ssb_message::{CommonContentType, SerdeJsonParser, SsbMessage, SsbMessageParser};
+use crate::{Cursor, FeedMeta, FlumeSequence, IndexStats, SsbDb};
+use feed_locks::{lock_feed, FeedLock};
+use query_timeout::QueryDeadline;
 
 use db::{
-    append_item, find_feed_flume_seqs_newer_than, find_feed_latest_seq,
-    find_message_flume_seq_by_author_and_sequence, find_message_flume_seq_by_key, get_latest,
+    append_item, clear_indexes, count_feed_messages_received_since, decode_raw,
+    delete_feed_messages_newer_than, delete_feed_messages_over_limit, delete_orphans,
+    find_all_authors, find_author_id, find_author_ids, find_content_type_counts,
+    find_feed_byte_lens, find_feed_chain, find_feed_first_flume_seq, find_feed_flume_seqs_after,
+    find_feed_flume_seqs_between, find_feed_flume_seqs_by_type, find_feed_flume_seqs_newer_than,
+    find_feed_is_complete, find_feed_latest_flume_seq_by_type, find_feed_latest_seq,
+    find_feed_mention_flume_seqs, find_feed_meta, find_feed_seqs_between,
+    find_feeds_latest_flume_seqs, find_feeds_latest_seqs, find_flume_seq_at_index,
+    find_flume_seqs_by_type, find_flume_seqs_matching, find_index_events_since,
+    find_indexed_offset, find_links_to_any_flume_seqs, find_links_to_from_authors_flume_seqs,
+    find_message_author_by_key, find_message_flume_seq_by_author_and_sequence,
+    find_message_flume_seq_by_key, find_message_is_latest_by_key, find_message_keys_present,
+    find_message_raw_by_flume_seq, find_message_seq_by_key, find_migration_versions,
+    find_root_flume_seqs, get_latest, get_oldest, hide_messages_since, override_feed_received_at,
+    override_received_at_since, replace_feed_meta, set_feed_received_at, set_indexed_offset,
+    set_key_hidden, set_message_hidden, update_author, FeedMetaRow, LocalState, RawStorage,
 };
 
+/// What [SqliteSsbDb::maintenance] did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceReport {
+    /// How many entries that weren't messages (eg. zeroed deleted entries) were removed from the
+    /// offset log.
+    pub entries_removed: u64,
+    /// How many bytes smaller the offset log is.
+    pub offset_log_bytes_reclaimed: u64,
+    /// How many bytes smaller the sqlite db is.
+    pub sqlite_bytes_reclaimed: u64,
+    /// How long the whole thing took.
+    pub duration: Duration,
+}
+
+/// How much disk space a [SqliteSsbDb] is using, from [SqliteSsbDb::disk_usage].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskUsage {
+    /// The size of the offset log.
+    pub offset_log_bytes: u64,
+    /// The size of the sqlite db file. `0` if the connection was provided by the caller.
+    pub sqlite_bytes: u64,
+    /// The size of the sqlite `-wal` and `-shm` files, if there are any.
+    pub wal_bytes: u64,
+}
+
+/// What [SqliteSsbDb::import_jsonl_lenient] did.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportReport {
+    /// How many messages were appended.
+    pub imported: u64,
+    /// How many messages we already had, so weren't appended again.
+    pub skipped: u64,
+    /// The lines that couldn't be imported, as the line number (starting at 1) and why.
+    pub errors: Vec<(usize, String)>,
+}
+
+// How to set up each sqlite connection we open.
+#[derive(Debug, Clone, Copy, Default)]
+struct ConnectionOptions {
+    shared_cache: bool,
+    page_size: Option<u32>,
+    cache_size: Option<i64>,
+    wal: bool,
+}
+
+/// What was done to the sqlite db's schema when it was opened, from
+/// [SqliteSsbDb::migration_outcome].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MigrationOutcome {
+    /// The versions of the migrations that were run, oldest first. Empty if the db was already
+    /// up to date.
+    pub applied: Vec<String>,
+    /// Whether the db was made by an older version of this crate, so its indexes were thrown away
+    /// rather than migrated. They need rebuilding with
+    /// [SqliteSsbDb::update_indexes_from_offset_file].
+    pub indexes_reset: bool,
+    /// Why the local state, eg. feed metadata set with [SqliteSsbDb::set_feed_meta], couldn't be
+    /// carried over when the indexes were reset. `None` if it was, or nothing was reset.
+    pub local_state_error: Option<String>,
+}
+
+/// A point in time view of the indexes, see [SqliteSsbDb::read_transaction].
+///
+/// It derefs to the [SqliteSsbDb], so any query can be made through it.
+pub struct ReadView<'a> {
+    db: &'a SqliteSsbDb,
+}
+
+impl Deref for ReadView<'_> {
+    type Target = SqliteSsbDb;
+
+    fn deref(&self) -> &SqliteSsbDb {
+        self.db
+    }
+}
+
+/// A hook called with each new sqlite connection, see [SqliteSsbDb::with_on_connect].
+///
+/// It's `Send + Sync` so the db can be moved to another thread, and so connections opened on a
+/// background thread, eg. by the `watch` feature, can call it too.
+pub type OnConnect = Arc<dyn Fn(&SqliteConnection) + Send + Sync>;
+
 pub struct SqliteSsbDb {
     connection: RefCell<SqliteConnection>,
-    offset_log: RefCell<OffsetLog<u32>>,
-    db_path: String,
+    offset_log: Arc<Mutex<OffsetLog<u32>>>,
+    // `None` when the log was shared with us, so we can't rewrite the file under it.
+    offset_log_path: Option<String>,
+    mirror_log: Option<RefCell<OffsetLog<u32>>>,
+    parser: Box<dyn SsbMessageParser>,
+    // `None` when the connection was provided by the caller, so we don't own the db file.
+    db_path: Option<String>,
+    remove_db_on_drop: bool,
+    remove_offset_log_on_drop: bool,
+    busy_retries: u32,
+    include_hidden: bool,
+    connection_options: ConnectionOptions,
+    validate_entries: bool,
+    store_raw: RawStorage,
+    max_messages_per_feed: Option<usize>,
+    read_chunk_size: usize,
+    commit_every: usize,
+    on_connect: Option<OnConnect>,
+    reindex_if_unavailable: bool,
+    auto_index_on_read: bool,
+    // Set while a `read_transaction` holds its snapshot, which indexing would break.
+    in_read_transaction: Cell<bool>,
+    migration_outcome: MigrationOutcome,
+    // Sqlite holds a pointer to this from the connection's progress handler, so it's boxed to keep
+    // it in place, and comes after `connection` so it's dropped after it.
+    query_deadline: Box<QueryDeadline>,
 }
 
-embed_migrations!();
+embed_migrations!();
+
+const DEFAULT_BUSY_RETRIES: u32 = 3;
+const INDEX_CHUNK_SIZE: usize = 10000;
+const BUSY_BACKOFF: Duration = Duration::from_millis(10);
+
+static TEMP_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+impl SqliteSsbDb {
+    /// Open the offset log at `offset_log_path`, with its sqlite indexes at `database_path`.
+    ///
+    /// Nothing is indexed until you call [SqliteSsbDb::update_indexes_from_offset_file], so until
+    /// then queries on a fresh db return nothing. [SqliteSsbDb::is_indexed] tells you if that's the case.
+    pub fn new<S: AsRef<str>>(database_path: S, offset_log_path: S) -> SqliteSsbDb {
+        SqliteSsbDb::new_with_parser(database_path, offset_log_path, SerdeJsonParser)
+    }
+
+    /// Like [SqliteSsbDb::new], but uses `parser` to parse messages when indexing.
+    pub fn new_with_parser<S: AsRef<str>, P: 'static + SsbMessageParser>(
+        database_path: S,
+        offset_log_path: S,
+        parser: P,
+    ) -> SqliteSsbDb {
+        let query_deadline: Box<QueryDeadline> = Box::default();
+        let (connection, migration_outcome) = setup_connection(
+            database_path.as_ref(),
+            ConnectionOptions::default(),
+            &query_deadline,
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+
+        SqliteSsbDb::from_parts(
+            connection,
+            migration_outcome,
+            query_deadline,
+            Some(database_path.as_ref().to_owned()),
+            open_offset_log(offset_log_path.as_ref()),
+            Some(offset_log_path.as_ref().to_owned()),
+            Box::new(parser),
+        )
+    }
+
+    /// Use an offset log that is shared with another [SqliteSsbDb] in the same process.
+    ///
+    /// Appends made through either handle are visible to the other straight away, without
+    /// reopening the log. Each db can live on its own thread: appends to the same feed are done one
+    /// at a time, while appends to different feeds can happen in parallel.
+    ///
+    /// Because we don't know where the shared log lives on disk, [SqliteSsbDb::maintenance],
+    /// [SqliteSsbDb::handle_fork] and [SqliteSsbDb::replace_feed] return an error instead of
+    /// rewriting the file.
+    pub fn from_shared_log<S: AsRef<str>>(
+        database_path: S,
+        offset_log: Arc<Mutex<OffsetLog<u32>>>,
+    ) -> SqliteSsbDb {
+        let query_deadline: Box<QueryDeadline> = Box::default();
+        let (connection, migration_outcome) = setup_connection(
+            database_path.as_ref(),
+            ConnectionOptions::default(),
+            &query_deadline,
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+
+        SqliteSsbDb::from_parts(
+            connection,
+            migration_outcome,
+            query_deadline,
+            Some(database_path.as_ref().to_owned()),
+            offset_log,
+            None,
+            Box::new(SerdeJsonParser),
+        )
+    }
+
+    /// Use a sqlite `connection` you have already opened for the indexes.
+    ///
+    /// This lets the indexes live alongside your own tables in the same sqlite file. Migrations
+    /// are run on the connection. Because we don't own the file, [SsbDb::rebuild_indexes] never
+    /// deletes it, even if the index tables can't be cleared.
+    ///
+    /// Fails if the migrations can't be run or the old indexes can't be cleared, eg. because the
+    /// connection is read only.
+    pub fn from_connection<S: AsRef<str>>(
+        connection: SqliteConnection,
+        offset_log_path: S,
+    ) -> Result<SqliteSsbDb> {
+        let migration_outcome = setup_shared_connection(&connection)?;
+
+        Ok(SqliteSsbDb::from_parts(
+            connection,
+            migration_outcome,
+            Box::default(),
+            None,
+            open_offset_log(offset_log_path.as_ref()),
+            Some(offset_log_path.as_ref().to_owned()),
+            Box::new(SerdeJsonParser),
+        ))
+    }
+
+    fn from_parts(
+        connection: SqliteConnection,
+        migration_outcome: MigrationOutcome,
+        query_deadline: Box<QueryDeadline>,
+        db_path: Option<String>,
+        offset_log: Arc<Mutex<OffsetLog<u32>>>,
+        offset_log_path: Option<String>,
+        parser: Box<dyn SsbMessageParser>,
+    ) -> SqliteSsbDb {
+        SqliteSsbDb {
+            connection: RefCell::new(connection),
+            offset_log,
+            offset_log_path,
+            mirror_log: None,
+            parser,
+            db_path,
+            remove_db_on_drop: false,
+            remove_offset_log_on_drop: false,
+            busy_retries: DEFAULT_BUSY_RETRIES,
+            include_hidden: false,
+            connection_options: ConnectionOptions::default(),
+            validate_entries: false,
+            store_raw: RawStorage::Off,
+            max_messages_per_feed: None,
+            read_chunk_size: INDEX_CHUNK_SIZE,
+            commit_every: INDEX_CHUNK_SIZE,
+            on_connect: None,
+            reindex_if_unavailable: false,
+            auto_index_on_read: false,
+            in_read_transaction: Cell::new(false),
+            migration_outcome,
+            query_deadline,
+        }
+    }
+
+    /// Open an offset log with a throwaway sqlite index in the system temp dir.
+    ///
+    /// The log is indexed before returning and the temp sqlite file is removed when the db is
+    /// dropped. Handy for one-off tools that just want to query an offset file.
+    pub fn with_temp_index<S: AsRef<str>>(offset_log_path: S) -> Result<SqliteSsbDb> {
+        let db_path = temp_path("sqlite3");
+
+        let mut db = SqliteSsbDb::new(db_path.as_ref(), offset_log_path.as_ref());
+        db.remove_db_on_drop = true;
+        db.update_indexes_from_offset_file()?;
+
+        Ok(db)
+    }
+
+    /// Open a gzipped offset log, eg. a `.offset.gz` you downloaded.
+    ///
+    /// The log is decompressed into a temp file which is removed when the db is dropped. Anything
+    /// you append goes to the temp file, not the gzipped log.
+    pub fn from_gzipped_offset_log<S: AsRef<str>>(
+        database_path: S,
+        gzipped_offset_log_path: S,
+    ) -> Result<SqliteSsbDb> {
+        let offset_log_path = temp_path("offset");
+
+        let decompress = || -> std::io::Result<u64> {
+            let gzipped = File::open(gzipped_offset_log_path.as_ref())?;
+            let mut offset_log = File::create(&offset_log_path)?;
+            std::io::copy(&mut GzDecoder::new(gzipped), &mut offset_log)
+        };
+        if decompress().is_err() {
+            let _ = std::fs::remove_file(&offset_log_path);
+            return Err(Error::OffsetDecompressError {
+                path: gzipped_offset_log_path.as_ref().to_owned(),
+            });
+        }
+
+        let mut db = SqliteSsbDb::new(database_path.as_ref(), &offset_log_path);
+        db.remove_offset_log_on_drop = true;
+
+        Ok(db)
+    }
+
+    /// What was done to the sqlite db's schema when it was opened, eg. to log which migrations
+    /// were run after upgrading this crate, and whether the indexes need rebuilding.
+    ///
+    /// Indexes made by an older version of this crate aren't migrated. The db file is deleted and
+    /// made again instead (or for [SqliteSsbDb::from_connection], the index tables are cleared),
+    /// since the indexes can be rebuilt from the offset log.
+    pub fn migration_outcome(&self) -> &MigrationOutcome {
+        &self.migration_outcome
+    }
+
+    /// The path of the sqlite index, or `None` if the connection was provided by the caller.
+    pub fn db_path(&self) -> Option<&str> {
+        self.db_path.as_deref()
+    }
+
+    /// Reopen the sqlite connection with `cache=shared`.
+    ///
+    /// With a shared cache, connections to the same db in this process share one cache, so they
+    /// see each other's writes. That's what lets two connections to `:memory:` use the same
+    /// in-memory db. Connections sharing a cache lock each other at the table level, so they can
+    /// get `SQLITE_LOCKED` rather than `SQLITE_BUSY`; writes retry on both (see
+    /// [SqliteSsbDb::set_busy_retries]). Sqlite recommends against mixing a shared cache with WAL
+    /// mode.
+    ///
+    /// Has no effect on a db made with [SqliteSsbDb::from_connection], which already has its
+    /// connection open. Fails if the db can't be reopened.
+    pub fn with_shared_cache(mut self) -> Result<SqliteSsbDb> {
+        if let Some(db_path) = &self.db_path {
+            self.connection_options.shared_cache = true;
+            self.reconnect(db_path)?;
+        }
+        Ok(self)
+    }
+
+    /// Set sqlite's `page_size` in bytes, a power of two between 512 and 65536. Larger pages can
+    /// speed up indexing and reads on fast disks.
+    ///
+    /// Sqlite only uses a new page size for a db that has no tables yet, so an existing db is
+    /// vacuumed to rebuild it with the new size, which can take a while on a large db. A db in WAL
+    /// mode keeps its page size. The size is set before the migrations run whenever the db is
+    /// created again, eg. when a migration resets the indexes.
+    ///
+    /// Has no effect on a db made with [SqliteSsbDb::from_connection]. Fails with
+    /// [Error::SqliteMaintenanceError] if the db can't be vacuumed, eg. because it's busy.
+    pub fn with_page_size(mut self, page_size: u32) -> Result<SqliteSsbDb> {
+        if let Some(db_path) = self.db_path.clone() {
+            self.connection_options.page_size = Some(page_size);
+            self.reconnect(&db_path)?;
+            self.connection
+                .borrow()
+                .batch_execute("VACUUM;")
+                .context(SqliteMaintenanceError)?;
+        }
+        Ok(self)
+    }
+
+    /// Set sqlite's `cache_size` for each connection. Like the pragma, a positive size is a number
+    /// of pages and a negative one is a number of KiB, eg. `-64000` for about 64MB.
+    ///
+    /// Has no effect on a db made with [SqliteSsbDb::from_connection]. Fails if the db can't be
+    /// reopened.
+    pub fn with_cache_size(mut self, cache_size: i64) -> Result<SqliteSsbDb> {
+        if let Some(db_path) = self.db_path.clone() {
+            self.connection_options.cache_size = Some(cache_size);
+            self.reconnect(&db_path)?;
+        }
+        Ok(self)
+    }
+
+    /// Reopen the sqlite db in write-ahead log mode, so readers on other connections don't block
+    /// a writer and a writer doesn't block them. Sqlite keeps the mode in the db file, so it
+    /// sticks for other connections, but it's set again whenever the db is created afresh, eg.
+    /// when a migration resets the indexes.
+    ///
+    /// The log is checkpointed into the main db file by [SqliteSsbDb::close] or when the db is
+    /// dropped. Has no effect on an in-memory db or one made with [SqliteSsbDb::from_connection].
+    /// Fails if the db can't be reopened, or with [Error::SqliteWriteError] if it can't be switched
+    /// to WAL mode, eg. because another connection has it open.
+    pub fn with_wal(mut self) -> Result<SqliteSsbDb> {
+        if let Some(db_path) = self.db_path.clone() {
+            self.connection_options.wal = true;
+            self.reconnect(&db_path)?;
+        }
+        Ok(self)
+    }
+
+    /// Call `on_connect` with the sqlite connection, eg. to set pragmas like `mmap_size` or to
+    /// register custom functions and collations.
+    ///
+    /// It's called straight away with the current connection, then again each time the db
+    /// reconnects, eg. when [SsbDb::rebuild_indexes] has to make a new db file, or in
+    /// [SqliteSsbDb::use_index_at]. It always runs
+    /// after the migrations, so the index tables exist by then.
+    pub fn with_on_connect(mut self, on_connect: OnConnect) -> SqliteSsbDb {
+        on_connect(&self.connection.borrow());
+        self.on_connect = Some(on_connect);
+        self
+    }
+
+    fn reconnect(&self, db_path: &str) -> Result<()> {
+        let connection = self.open_connection(db_path, &self.query_deadline)?;
+        self.connection.replace(connection);
+        Ok(())
+    }
+
+    // Open another connection to the db at `db_path`, set up like ours.
+    fn open_connection(
+        &self,
+        db_path: &str,
+        query_deadline: &QueryDeadline,
+    ) -> Result<SqliteConnection> {
+        let (connection, _) = setup_connection(db_path, self.connection_options, query_deadline)?;
+        if let Some(on_connect) = &self.on_connect {
+            on_connect(&connection);
+        }
+        Ok(connection)
+    }
+
+    /// Set whether entries read from the offset log are checked to be messages before they're
+    /// returned. Defaults to `false`.
+    ///
+    /// If the indexes and the offset log ever disagree, a read could return something that isn't
+    /// a message. With validation on you get an [Error::CorruptEntry] with the offset instead.
+    pub fn set_validate_entries(&mut self, validate_entries: bool) {
+        self.validate_entries = validate_entries;
+    }
+
+    fn get_entry(&self, offset: FlumeSequence) -> Result<Vec<u8>> {
+        // Anything indexed before raw storage was turned on, or that sqlite can't give us, is read
+        // from the offset log, which is always the source of truth.
+        let raw = if self.store_raw != RawStorage::Off {
+            find_message_raw_by_flume_seq(&self.connection.borrow(), offset)
+                .unwrap_or(None)
+                .and_then(decode_raw)
+        } else {
+            None
+        };
+
+        let entry = match raw {
+            Some(entry) => entry,
+            None => self
+                .offset_log()
+                .get(offset)
+                .map_err(|_| Error::OffsetGetError {})?,
+        };
+
+        if self.validate_entries && self.parser.parse_message(&entry).is_none() {
+            return Err(Error::CorruptEntry { offset });
+        }
+
+        Ok(entry)
+    }
+
+    /// Also append everything to a second offset log at `mirror_path`, eg. as a warm standby.
+    ///
+    /// Appends go to the main offset log first. If appending to the mirror fails you get an
+    /// [Error::MirrorAppendError], but the main offset log and indexes are still consistent. Only
+    /// appends are mirrored; compacting or repairing forks only changes the main offset log.
+    ///
+    /// Fails with [Error::OffsetWriteError] if the mirror can't be opened.
+    pub fn with_mirror<S: AsRef<str>>(mut self, mirror_path: S) -> Result<SqliteSsbDb> {
+        let mirror_log =
+            OffsetLog::new(mirror_path.as_ref()).map_err(|_| Error::OffsetWriteError {})?;
+        self.mirror_log = Some(RefCell::new(mirror_log));
+        Ok(self)
+    }
+
+    /// Also store the raw bytes of each message in sqlite when indexing, and read entries from
+    /// there instead of the offset log.
+    ///
+    /// This trades disk space for read speed, eg. for a read-heavy pub where seeking the offset log
+    /// dominates. The offset log is still the source of truth: indexes are always rebuilt from it,
+    /// and entries indexed before this was turned on are still read from it.
+    pub fn with_store_raw(mut self) -> SqliteSsbDb {
+        self.store_raw = RawStorage::Plain;
+        self
+    }
+
+    /// Catch the indexes up with the offset log before each query, so queries see every message
+    /// in the log without you calling [SqliteSsbDb::update_indexes_from_offset_file] first. Eg. for
+    /// a simple app that doesn't want to manage indexing itself.
+    ///
+    /// Indexing carries on from the last indexed entry, so when the indexes are already up to date
+    /// each query only pays for a check of the log's length. Queries made in a
+    /// [SqliteSsbDb::read_transaction] aren't caught up, so they keep agreeing with each other.
+    pub fn with_auto_index_on_read(mut self) -> SqliteSsbDb {
+        self.auto_index_on_read = true;
+        self
+    }
+
+    /// Like [SqliteSsbDb::with_store_raw], but the bytes are zstd compressed to save disk space.
+    /// Only available with the `compress-raw` feature.
+    ///
+    /// Each message is compressed on its own, and kept uncompressed if that's smaller. Reads tell
+    /// the two apart and decompress as needed. A build without the feature can still open the db,
+    /// it just reads compressed entries from the offset log.
+    #[cfg(feature = "compress-raw")]
+    pub fn with_compressed_raw(mut self) -> SqliteSsbDb {
+        self.store_raw = RawStorage::Compressed;
+        self
+    }
+
+    /// Only keep the `max` most recent messages of each feed in the indexes, eg. to bound the size
+    /// of the sqlite db on a space constrained device.
+    ///
+    /// Whenever indexing takes a feed over `max` messages, its oldest messages are removed from
+    /// the indexes. They're still in the offset log until it's compacted, but won't be returned by
+    /// any queries. What's kept is the last `max` sequences of the feed, so a feed with gaps keeps
+    /// fewer than `max` messages.
+    ///
+    /// A `max` of `0` means no limit, since a feed always keeps the message that was just indexed.
+    pub fn with_max_messages_per_feed(mut self, max: usize) -> SqliteSsbDb {
+        self.max_messages_per_feed = Some(max).filter(|max| *max > 0);
+        self
+    }
+
+    /// Read `size` entries at a time from the offset log when indexing. Defaults to 10000.
+    ///
+    /// Each chunk is read into memory before it's indexed, so smaller chunks use less memory. This
+    /// is independent of how often indexing commits, see [SqliteSsbDb::with_commit_every].
+    pub fn with_read_chunk_size(mut self, size: usize) -> SqliteSsbDb {
+        self.read_chunk_size = size.max(1);
+        self
+    }
+
+    /// Commit the sqlite transaction every `count` entries when indexing. Defaults to 10000.
+    ///
+    /// Fewer, larger commits are faster on slow storage. Smaller commits mean less work is lost
+    /// and redone if indexing is interrupted or has to retry because sqlite is busy.
+    /// [SqliteSsbDb::update_indexes_cooperatively] yields after each commit.
+    pub fn with_commit_every(mut self, count: usize) -> SqliteSsbDb {
+        self.commit_every = count.max(1);
+        self
+    }
+
+    /// Close the db, checkpointing the sqlite write-ahead log into the main db file first.
+    ///
+    /// Dropping the db does the same, but can't tell you if the checkpoint failed. A large
+    /// un-checkpointed WAL slows down opening the db next time. Does nothing extra if the db
+    /// isn't in WAL mode (see [SqliteSsbDb::with_wal]).
+    pub fn close(self) -> Result<()> {
+        self.checkpoint()
+    }
+
+    fn checkpoint(&self) -> Result<()> {
+        self.connection
+            .borrow()
+            .batch_execute("PRAGMA wal_checkpoint(TRUNCATE);")
+            .context(SqliteCheckpointError)
+    }
+
+    /// Switch to the sqlite index at `new_db_path`, eg. a hot standby or a pre-built index,
+    /// without deleting the current one.
+    ///
+    /// Migrations are run on the new index, then it's caught up with the offset log like
+    /// [SqliteSsbDb::update_indexes_from_offset_file] does, including reindexing if it refers to
+    /// entries past the end of the log. The current index file is left alone, unless it was a
+    /// throwaway made by [SqliteSsbDb::with_temp_index].
+    pub fn use_index_at(&mut self, new_db_path: &str) -> Result<()> {
+        self.reconnect(new_db_path)?;
+
+        let old_db_path = self.db_path.replace(new_db_path.to_owned());
+        if let (true, Some(old_db_path)) = (self.remove_db_on_drop, old_db_path) {
+            let _ = std::fs::remove_file(old_db_path);
+        }
+        self.remove_db_on_drop = false;
+
+        self.update_indexes_from_offset_file().map(|_| ())
+    }
+
+    /// Set how many times to retry writing to sqlite when it's busy or locked by another
+    /// connection, before giving up with an error. Defaults to 3.
+    ///
+    /// Each retry waits a little longer than the last.
+    pub fn set_busy_retries(&mut self, retries: u32) {
+        self.busy_retries = retries;
+    }
+
+    /// Set whether messages hidden with [SqliteSsbDb::set_hidden] are returned by reads from this db.
+    /// Defaults to `false`.
+    ///
+    /// This applies to every query that returns messages or looks them up by key, eg.
+    /// [SsbDb::get_entry_by_key], [SsbDb::get_seq_by_key] and [SsbDb::get_next_entry]. A hidden
+    /// message is still stored, so checks of what the db holds, like
+    /// [SsbDb::get_feed_latest_sequence], [SsbDb::missing_keys] and [SsbDb::have_sequences],
+    /// still count it.
+    ///
+    /// Hidden messages are still replicated whatever this is set to, so
+    /// [SsbDb::get_entries_newer_than_sequence], [SsbDb::create_history_stream] and
+    /// [SqliteSsbDb::stream_feed_values] always return them.
+    pub fn set_include_hidden(&mut self, include_hidden: bool) {
+        self.include_hidden = include_hidden;
+    }
+
+    /// Set whether to rebuild the indexes when a query finds the sqlite file has gone, eg. deleted
+    /// by a cleanup tool while the offset log is still there. Defaults to `false`, where queries
+    /// fail with [Error::IndexUnavailable] instead.
+    ///
+    /// The rebuild happens in the query that noticed, so that query can take a while.
+    pub fn set_reindex_if_unavailable(&mut self, reindex_if_unavailable: bool) {
+        self.reindex_if_unavailable = reindex_if_unavailable;
+    }
+
+    /// Clean up the db. Meant to be run occasionally, eg. from a cron job.
+    ///
+    /// In order, this:
+    /// - compacts the offset log, dropping entries that aren't messages (eg. zeroed out deleted
+    ///   entries). The compacted log is written to a new file.
+    /// - clears the indexes, then swaps the compacted log in for the old one and indexes it,
+    ///   because compacting changes the offsets. Like [SsbDb::rebuild_indexes], local state such as
+    ///   feed metadata, hidden flags and received times set with [SqliteSsbDb::set_feed_received_at] is
+    ///   kept.
+    /// - deletes authors and keys that no messages refer to.
+    /// - vacuums the sqlite db.
+    ///
+    /// The indexes are cleared before the logs are swapped, so if indexing the compacted log fails
+    /// part way the indexes are behind the log rather than pointing at the wrong entries.
+    /// [SqliteSsbDb::update_indexes_from_offset_file] carries on from there.
+    ///
+    /// Compacting changes the offsets of entries, so any offsets you have stored are invalid
+    /// afterwards.
+    pub fn maintenance(&self) -> Result<MaintenanceReport> {
+        let started = Instant::now();
+        let offset_log_size_before = self.offset_log().end();
+        let sqlite_size_before = self.sqlite_size();
+
+        let (compacted_path, entries_removed) = self.write_compacted_offset_log()?;
+        if let Err(err) = clear_indexes(&self.connection.borrow()) {
+            let _ = std::fs::remove_file(&compacted_path);
+            return Err(err).context(SqliteClearError);
+        }
+        self.swap_offset_log(&compacted_path)?;
+        self.update_indexes_from_offset_file()?;
+
+        let connection = self.connection.borrow();
+        delete_orphans(&connection).context(SqliteMaintenanceError)?;
+        connection
+            .batch_execute("VACUUM;")
+            .context(SqliteMaintenanceError)?;
+
+        Ok(MaintenanceReport {
+            entries_removed,
+            offset_log_bytes_reclaimed: offset_log_size_before
+                .saturating_sub(self.offset_log().end()),
+            sqlite_bytes_reclaimed: sqlite_size_before.saturating_sub(self.sqlite_size()),
+            duration: started.elapsed(),
+        })
+    }
+
+    // Write the messages in the offset log to a new file next to it. Returns its path, and how
+    // many entries were left out because they aren't messages.
+    fn write_compacted_offset_log(&self) -> Result<(String, u64)> {
+        let offset_log_path = self
+            .offset_log_path
+            .as_ref()
+            .ok_or(Error::OffsetCompactError {})?;
+        let compacted_path = format!("{}.compacting", offset_log_path);
+        let _ = std::fs::remove_file(&compacted_path);
+
+        let mut compacted =
+            OffsetLog::<u32>::new(&compacted_path).map_err(|_| Error::OffsetCompactError {})?;
+        let mut entries_removed = 0;
+
+        self.offset_log()
+            .iter()
+            .chunks(10000)
+            .into_iter()
+            .try_for_each(|chunk| {
+                let messages = chunk
+                    .filter(|entry| {
+                        let is_message = self.parser.parse_message(&entry.data).is_some();
+                        if !is_message {
+                            entries_removed += 1;
+                        }
+                        is_message
+                    })
+                    .map(|entry| entry.data)
+                    .collect::<Vec<_>>();
+
+                compacted
+                    .append_batch(&messages)
+                    .map(|_| ())
+                    .map_err(|_| Error::OffsetCompactError {})
+            })?;
+
+        Ok((compacted_path, entries_removed))
+    }
+
+    // Replace the offset log with the one at `compacted_path`.
+    fn swap_offset_log(&self, compacted_path: &str) -> Result<()> {
+        let offset_log_path = self.writable_offset_log_path()?;
+        std::fs::rename(compacted_path, offset_log_path)
+            .map_err(|_| Error::OffsetCompactError {})?;
+        let offset_log =
+            OffsetLog::new(offset_log_path).map_err(|_| Error::OffsetCompactError {})?;
+        *self.offset_log() = offset_log;
+
+        Ok(())
+    }
+
+    // A panic while appending can't leave the log half-written, so carry on past a poisoned lock.
+    fn offset_log(&self) -> MutexGuard<'_, OffsetLog<u32>> {
+        self.offset_log
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Call `f` with a [ReadView] that sees the indexes as they were when it started, so several
+    /// queries made through it agree with each other while other connections keep indexing. Eg.
+    /// for a report made from a number of queries.
+    ///
+    /// A sqlite read transaction is held open until `f` returns. In WAL mode (see
+    /// [SqliteSsbDb::with_wal]) other connections can carry on writing meanwhile, otherwise they
+    /// have to wait for it (see [SqliteSsbDb::set_busy_retries]).
+    ///
+    /// Writes made through the view, eg. [SqliteSsbDb::set_hidden], run as savepoints inside the
+    /// transaction and are committed when `f` returns. If another connection has written since the
+    /// snapshot was taken, sqlite can't turn it into a write transaction, so they fail as busy.
+    /// Reads through the view don't catch up with the offset log, even with
+    /// [SqliteSsbDb::with_auto_index_on_read]. Don't call [SsbDb::rebuild_indexes] or
+    /// [SqliteSsbDb::maintenance] from `f`: they reopen the sqlite db, which ends the transaction.
+    pub fn read_transaction<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&ReadView) -> Result<R>,
+    {
+        {
+            let connection = self.index()?;
+            let transaction_manager = connection.transaction_manager();
+            transaction_manager
+                .begin_transaction(&*connection)
+                .context(SqliteReadTransactionError)?;
+
+            // Sqlite takes the snapshot at the first read, not when the transaction begins.
+            if let Err(source) = get_latest(&connection) {
+                let _ = transaction_manager.rollback_transaction(&*connection);
+                return Err(source).context(SqliteReadTransactionError);
+            }
+        }
+
+        self.in_read_transaction.set(true);
+        let result = f(&ReadView { db: self });
+        self.in_read_transaction.set(false);
+        let connection = self.connection.borrow();
+        let transaction_manager = connection.transaction_manager();
+        let end = transaction_manager
+            .commit_transaction(&*connection)
+            .context(SqliteReadTransactionError);
+        if end.is_err() {
+            let _ = transaction_manager.rollback_transaction(&*connection);
+        }
+
+        let value = result?;
+        end.map(|_| value)
+    }
+
+    /// Call `f`, aborting any query it makes through this db that's still running `timeout` after
+    /// `f` was called, with [Error::Timeout]. Eg. to stop one expensive request from holding up
+    /// everyone else.
+    ///
+    /// Sqlite checks the deadline every thousand or so steps of a query, so it's overshot by a
+    /// little. `f` itself isn't interrupted, only the queries it makes. Queries on a connection
+    /// given to [SqliteSsbDb::from_connection] can't be aborted, so they always run to the end.
+    pub fn query_with_timeout<F, R>(&self, timeout: Duration, f: F) -> Result<R>
+    where
+        F: FnOnce(&SqliteSsbDb) -> Result<R>,
+    {
+        self.query_deadline.start(timeout);
+        let result = f(self);
+
+        if self.query_deadline.finish() {
+            return Err(Error::Timeout {});
+        }
+        result
+    }
+
+    /// The length of the offset log in bytes, which is also the offset the next entry will be
+    /// appended at.
+    ///
+    /// Read from the file, so it includes entries appended by another process since the log was
+    /// opened. When the offset log is shared (see [SqliteSsbDb::from_shared_log]) it's taken from
+    /// the log itself.
+    pub fn offset_log_len(&self) -> Result<u64> {
+        match &self.offset_log_path {
+            Some(offset_log_path) => file_size(offset_log_path),
+            None => Ok(self.offset_log().end()),
+        }
+    }
+
+    /// How many bytes have been appended to the offset log after `offset`, eg. to report how much
+    /// was downloaded since a checkpoint. Offsets are byte positions in the log, so this is the
+    /// length of the log minus `offset`, or `0` if `offset` is past the end.
+    pub fn bytes_since_offset(&self, offset: FlumeSequence) -> Result<u64> {
+        Ok(self.offset_log_len()?.saturating_sub(offset))
+    }
+
+    /// How much disk space the offset log and the sqlite db are using.
+    ///
+    /// When the offset log is shared (see [SqliteSsbDb::from_shared_log]) its size is taken from
+    /// the log itself rather than the file.
+    pub fn disk_usage(&self) -> Result<DiskUsage> {
+        let offset_log_bytes = self.offset_log_len()?;
+
+        let (sqlite_bytes, wal_bytes) = match &self.db_path {
+            Some(db_path) => {
+                let wal_bytes = ["-wal", "-shm"]
+                    .iter()
+                    .map(|suffix| format!("{}{}", db_path, suffix))
+                    .filter(|path| Path::new(path).exists())
+                    .map(|path| file_size(&path))
+                    .sum::<Result<u64>>()?;
+                (file_size(db_path)?, wal_bytes)
+            }
+            None => (0, 0),
+        };
+
+        Ok(DiskUsage {
+            offset_log_bytes,
+            sqlite_bytes,
+            wal_bytes,
+        })
+    }
+
+    // Serialize writes to a feed across threads, including other dbs sharing our offset log.
+    fn lock_feed(&self, feed_id: &Multikey) -> FeedLock {
+        let log_id = Arc::as_ptr(&self.offset_log) as usize;
+        lock_feed(log_id, feed_id.to_legacy_string())
+    }
+
+    // The author and sequence of the message with the given key, or a MessageNotFound error.
+    fn find_author_and_seq(&self, key: &str) -> Result<(String, i32)> {
+        let connection = self.index()?;
+        let author = find_message_author_by_key(&connection, key, self.include_hidden)
+            .context(MessageNotFound)?;
+        let seq = find_message_seq_by_key(&connection, key, self.include_hidden)
+            .context(MessageNotFound)?;
+        match (author, seq) {
+            (Some(author), Some(seq)) => Ok((author, seq)),
+            _ => Err(db::Error::NotFound).context(MessageNotFound),
+        }
+    }
+
+    // The connection to query the indexes with, once we know they're still there.
+    fn index(&self) -> Result<Ref<'_, SqliteConnection>> {
+        if !self.index_is_available() {
+            if !self.reindex_if_unavailable {
+                return Err(Error::IndexUnavailable {});
+            }
+            self.rebuild_indexes()?;
+        }
+        if self.auto_index_on_read && !self.in_read_transaction.get() {
+            match self.update_indexes_from_offset_file() {
+                // The colliding entries were skipped, so the indexes are still fine to read.
+                Err(Error::KeyCollision { .. }) => (),
+                res => {
+                    res?;
+                }
+            }
+        }
+        Ok(self.connection.borrow())
+    }
+
+    // Sqlite carries on using a db file that's deleted while it's open, so check it's still there.
+    fn index_is_available(&self) -> bool {
+        match &self.db_path {
+            // An in-memory db has no file to lose.
+            Some(db_path) if db_path != ":memory:" => Path::new(db_path).exists(),
+            _ => true,
+        }
+    }
+
+    fn sqlite_size(&self) -> u64 {
+        self.db_path
+            .as_ref()
+            .and_then(|db_path| std::fs::metadata(db_path).ok())
+            .map(|metadata| metadata.len())
+            .unwrap_or(0)
+    }
+
+    /// Remove every message in the given feed with a sequence greater than `keep_until_seq`.
+    ///
+    /// Use this to repair a forked feed (see [SsbDb::find_feed_fork]), passing the last sequence
+    /// before the fork. Returns how many messages were removed. To see the removed entries, use
+    /// [SqliteSsbDb::handle_fork_with].
+    ///
+    /// The messages are removed from the indexes in one transaction, while holding the feed's
+    /// write lock. Only then are the removed entries zeroed out in the offset log, so they don't
+    /// come back when the indexes are rebuilt.
+    pub fn handle_fork(&self, feed_id: &Multikey, keep_until_seq: i32) -> Result<usize> {
+        self.handle_fork_with(feed_id, keep_until_seq, |_| {})
+    }
+
+    /// Like [SqliteSsbDb::handle_fork], but `on_removed` is called with each removed entry before
+    /// it's zeroed out, eg. to keep the conflicting messages as evidence.
+    pub fn handle_fork_with<F: FnMut(&[u8])>(
+        &self,
+        feed_id: &Multikey,
+        keep_until_seq: i32,
+        mut on_removed: F,
+    ) -> Result<usize> {
+        // Fail before touching the indexes if we can't zero out the removed entries.
+        self.writable_offset_log_path()?;
+        let _lock = self.lock_feed(feed_id);
+        let author = feed_id.to_legacy_string();
+
+        let connection = self.index()?;
+        let seqs = connection
+            .transaction::<_, db::Error, _>(|| {
+                let seqs = find_feed_flume_seqs_newer_than(
+                    &connection,
+                    &author,
+                    keep_until_seq,
+                    None,
+                    true,
+                )?;
+                delete_feed_messages_newer_than(&connection, &author, keep_until_seq)?;
+                Ok(seqs)
+            })
+            .context(SqliteDeleteError)?;
+        drop(connection);
+
+        let removed = seqs
+            .into_iter()
+            .map(|seq| {
+                let entry = self.get_entry(seq)?;
+                on_removed(&entry);
+                Ok((seq, entry.len()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.zero_offset_log_entries(&removed)?;
+
+        Ok(removed.len())
+    }
+
+    /// Change how an author is stored in the indexes, from `old` to `new`. Returns how many
+    /// authors were updated, so `0` if `old` isn't in the db.
+    ///
+    /// This is for careful data migrations, eg. normalising feed ids, not routine use. It's an
+    /// error if `new` is already a different author, because that would merge two feeds. Only the
+    /// indexes change, not the offset log, so [SsbDb::rebuild_indexes] undoes the remap.
+    pub fn remap_author(&self, old: &str, new: &Multikey) -> Result<usize> {
+        let connection = self.connection.borrow();
+        let new = new.to_legacy_string();
+
+        // `None` means `new` is already a different author, so nothing was updated.
+        let updated = connection
+            .transaction::<_, db::Error, _>(|| {
+                let new_id = find_author_id(&connection, &new)?;
+                let old_id = find_author_id(&connection, old)?;
+
+                if new_id.is_some() && new_id != old_id {
+                    return Ok(None);
+                }
+
+                update_author(&connection, old, &new).map(Some)
+            })
+            .context(SqliteRemapAuthorError)?;
+
+        updated.ok_or(Error::AuthorAlreadyExists { author: new })
+    }
+
+    /// Find the feeds that have messages in the offset log but aren't in the indexes, eg. to
+    /// spot the indexes drifting from the log.
+    ///
+    /// This is expensive: it reads and parses every entry in the offset log.
+    pub fn find_unindexed_feeds(&self) -> Result<Vec<Multikey>> {
+        let indexed = find_all_authors(&*self.index()?)
+            .context(FeedNotFound)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        let unindexed = self
+            .offset_log()
+            .iter()
+            .filter_map(|entry| self.parser.parse_message(&entry.data))
+            .map(|message| message.value.author)
+            .filter(|author| !indexed.contains(author))
+            .unique()
+            .filter_map(|author| parse_author(&author).ok())
+            .collect();
+
+        Ok(unindexed)
+    }
+
+    /// Write every message we have in the given feed to a new offset log at `out_path`, in
+    /// sequence order. Returns how many messages were written.
+    ///
+    /// The new log is standalone, so it can be shared with a peer who indexes it themselves.
+    /// It's an error if a file already exists at `out_path`.
+    pub fn export_feed_offset_log(&self, feed_id: &Multikey, out_path: &str) -> Result<u64> {
+        let export_error = || Error::OffsetExportError {
+            path: out_path.to_owned(),
+        };
+
+        let seqs = find_feed_flume_seqs_newer_than(
+            &*self.index()?,
+            &feed_id.to_legacy_string(),
+            0,
+            None,
+            true,
+        )
+        .context(FeedNotFound)?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(out_path)
+            .map_err(|_| export_error())?;
+        let mut exported = OffsetLog::<u32>::from_file(file).map_err(|_| export_error())?;
+
+        seqs.chunks(10000).try_for_each(|chunk| {
+            let entries = chunk
+                .iter()
+                .map(|seq| self.get_entry(*seq))
+                .collect::<Result<Vec<_>>>()?;
+
+            exported
+                .append_batch(&entries)
+                .map(|_| ())
+                .map_err(|_| export_error())
+        })?;
+
+        Ok(seqs.len() as u64)
+    }
+
+    /// Append the messages from the offset log at `other_offset_log_path` that we don't already
+    /// have, then index them. Returns how many messages were added. Eg. to combine the logs
+    /// collected on two devices.
+    ///
+    /// Messages are matched by key, so merging overlapping histories doesn't store anything twice.
+    /// Entries in the other log that aren't messages are skipped. It's read
+    /// [SqliteSsbDb::with_read_chunk_size] entries at a time, and each feed's new messages in a
+    /// chunk are appended and indexed like [SsbDb::append_batch], holding that feed's lock.
+    pub fn merge_from(&self, other_offset_log_path: &str) -> Result<u64> {
+        let other = OffsetLog::<u32>::open_read_only(other_offset_log_path).map_err(|_| {
+            Error::OffsetMergeError {
+                path: other_offset_log_path.to_owned(),
+            }
+        })?;
+
+        // Anything already in our log has to be indexed to be recognised.
+        self.update_indexes_from_offset_file()?;
+
+        let mut merged_keys = HashSet::new();
+        for chunk in &other.iter().chunks(self.read_chunk_size) {
+            let messages = chunk
+                .filter_map(|entry| {
+                    let message = self.parser.parse_message(&entry.data)?;
+                    parse_author(&message.value.author).ok()?;
+                    Some((message.key, message.value.author, entry.data))
+                })
+                .collect::<Vec<_>>();
+
+            let keys = messages
+                .iter()
+                .map(|(key, _, _)| key.clone())
+                .collect::<Vec<_>>();
+            let present = find_message_keys_present(&*self.index()?, &keys)
+                .context(MessageNotFound)?
+                .into_iter()
+                .collect::<HashSet<_>>();
+
+            // The other log can have the same message more than once, so skip the ones we've merged.
+            let new_messages = messages
+                .into_iter()
+                .filter(|(key, _, _)| !present.contains(key) && merged_keys.insert(key.clone()))
+                .map(|(_, author, data)| (author, data))
+                .into_group_map();
+
+            new_messages
+                .into_iter()
+                .try_for_each(|(author, messages)| {
+                    self.append_batch(&parse_author(&author)?, &messages)
+                })?;
+        }
+
+        Ok(merged_keys.len() as u64)
+    }
+
+    /// Append each line of `reader` as a message, carrying on past lines that can't be imported
+    /// and reporting why. Eg. to salvage what's good from a messy export.
+    ///
+    /// Each line is a whole message, like the entries given to [SsbDb::append_batch], with its
+    /// author taken from the message. Blank lines are ignored, and messages we already have are
+    /// skipped, so importing the same file twice is harmless. Only failing to read from `reader`
+    /// stops the import.
+    pub fn import_jsonl_lenient<R: BufRead>(&self, reader: &mut R) -> Result<ImportReport> {
+        let mut report = ImportReport::default();
+        let mut line = Vec::new();
+        let mut line_no = 0;
+
+        loop {
+            line.clear();
+            let read = reader
+                .read_until(b'\n', &mut line)
+                .map_err(|_| Error::ImportReadError { line: line_no + 1 })?;
+            if read == 0 {
+                return Ok(report);
+            }
+            line_no += 1;
+
+            let trimmed = line.trim_ascii();
+            if trimmed.is_empty() {
+                continue;
+            }
+            match self.import_line(trimmed.to_vec()) {
+                Ok(true) => report.imported += 1,
+                Ok(false) => report.skipped += 1,
+                Err(reason) => report.errors.push((line_no, reason)),
+            }
+        }
+    }
+
+    // Append one line of an import, returning whether it was new, or why it couldn't be appended.
+    fn import_line(&self, line: Vec<u8>) -> std::result::Result<bool, String> {
+        let message = self
+            .parser
+            .parse_message(&line)
+            .ok_or_else(|| "not an ssb message".to_owned())?;
+        let feed_id = parse_author(&message.value.author).map_err(|err| err.to_string())?;
+
+        let present = find_message_keys_present(
+            &*self.index().map_err(|err| err.to_string())?,
+            &[message.key],
+        )
+        .map_err(|err| err.to_string())?;
+        if !present.is_empty() {
+            return Ok(false);
+        }
+
+        self.append(&feed_id, line)
+            .map(|_| true)
+            .map_err(|err| err.to_string())
+    }
+
+    /// Copy the whole offset log to a new file at `out_path`, eg. for a backup. Returns how many
+    /// entries were copied.
+    ///
+    /// Unlike copying the file, every entry is checked as it's copied. Entries that were zeroed
+    /// out (eg. by [SqliteSsbDb::handle_fork]) are copied as they are, so offsets in the copy match
+    /// the original. Any other entry that isn't a message fails with [Error::CorruptEntry], giving
+    /// its offset, and the partial copy is removed. It's an error if a file already exists at
+    /// `out_path`.
+    pub fn clone_offset_log(&self, out_path: &str) -> Result<u64> {
+        let export_error = || Error::OffsetExportError {
+            path: out_path.to_owned(),
+        };
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(out_path)
+            .map_err(|_| export_error())?;
+        let mut cloned = OffsetLog::<u32>::from_file(file).map_err(|_| export_error())?;
+        let mut copied = 0;
+
+        let result = self
+            .offset_log()
+            .iter()
+            .chunks(INDEX_CHUNK_SIZE)
+            .into_iter()
+            .try_for_each(|chunk| {
+                let entries = chunk
+                    .map(|entry| {
+                        let is_zeroed = entry.data.iter().all(|byte| *byte == 0);
+                        if is_zeroed || self.parser.parse_message(&entry.data).is_some() {
+                            Ok(entry.data)
+                        } else {
+                            Err(Error::CorruptEntry {
+                                offset: entry.offset,
+                            })
+                        }
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                copied += entries.len() as u64;
+                cloned
+                    .append_batch(&entries)
+                    .map(|_| ())
+                    .map_err(|_| export_error())
+            });
+
+        if result.is_err() {
+            drop(cloned);
+            let _ = std::fs::remove_file(out_path);
+        }
+        result.map(|_| copied)
+    }
+
+    // We can only write to the offset log directly when we know where it is on disk.
+    fn writable_offset_log_path(&self) -> Result<&str> {
+        self.offset_log_path
+            .as_deref()
+            .ok_or(Error::OffsetWriteError {})
+    }
+
+    fn zero_offset_log_entries(&self, entries: &[(FlumeSequence, usize)]) -> Result<()> {
+        self.fill_offset_log_entries(entries, 0)
+    }
+
+    // Overwrite the data of each `(offset, len)` entry with `byte`, leaving the framing intact.
+    fn fill_offset_log_entries(&self, entries: &[(FlumeSequence, usize)], byte: u8) -> Result<()> {
+        let offset_log_path = self.writable_offset_log_path()?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(offset_log_path)
+            .map_err(|_| Error::OffsetWriteError {})?;
+
+        entries.iter().try_for_each(|(offset, len)| {
+            // Each entry is framed by a u32 length before the data.
+            file.seek(SeekFrom::Start(offset + 4))
+                .and_then(|_| file.write_all(&vec![byte; *len]))
+                .map_err(|_| Error::OffsetWriteError {})
+        })
+    }
+
+    /// Lazily get the values of all the entries for the given `feed_id`, with a sequence larger
+    /// than `sequence`.
+    ///
+    /// Like [SsbDb::get_entries_newer_than_sequence] with only `include_values`, but each value is
+    /// read from the offset log as the iterator is advanced, instead of all at once. The values
+    /// are byte-exact so their signatures still verify.
+    pub fn stream_feed_values(
+        &self,
+        feed_id: &Multikey,
+        sequence: i32,
+    ) -> Result<impl Iterator<Item = Result<Vec<u8>>> + '_> {
+        let seqs = find_feed_flume_seqs_newer_than(
+            &*self.index()?,
+            &feed_id.to_legacy_string(),
+            sequence,
+            None,
+            true,
+        )
+        .context(FeedNotFound)?;
+
+        Ok(seqs.into_iter().map(move |seq| {
+            let msg = self.get_entry(seq)?;
+            let legacy_value = ssb_legacy_msg_data::json::from_slice(&msg)
+                .map_err(|_| Error::ErrorParsingAsLegacyValue {})?;
+            legacy_value_to_vec(seq, &legacy_value)
+        }))
+    }
+
+    /// Lazily get the parsed `value` of each entry for the given `feed_id` with a sequence larger
+    /// than `sequence`.
+    ///
+    /// Like [SqliteSsbDb::stream_feed_values], but skips re-encoding each value as bytes, for
+    /// callers that deserialize them straight away. The parsed values aren't byte-exact, so their
+    /// signatures can't be checked. Since it isn't for replication, hidden messages are skipped
+    /// unless [SqliteSsbDb::set_include_hidden] is set.
+    pub fn stream_values_newer_than_sequence(
+        &self,
+        feed_id: &Multikey,
+        sequence: i32,
+    ) -> Result<impl Iterator<Item = Result<serde_json::Value>> + '_> {
+        let seqs = find_feed_flume_seqs_newer_than(
+            &*self.index()?,
+            &feed_id.to_legacy_string(),
+            sequence,
+            None,
+            self.include_hidden,
+        )
+        .context(FeedNotFound)?;
+
+        Ok(seqs.into_iter().map(move |seq| {
+            let msg = self.get_entry(seq)?;
+            match serde_json::from_slice::<serde_json::Value>(&msg) {
+                Ok(serde_json::Value::Object(mut msg)) => {
+                    msg.remove("value").context(ErrorParsingAsLegacyValue)
+                }
+                _ => Err(Error::ErrorParsingAsLegacyValue {}),
+            }
+        }))
+    }
+
+    /// Lazily get every entry with the given content `type`, from all feeds, in the order they
+    /// were appended.
+    ///
+    /// Only the offsets are read up front. Each entry is read from the offset log as the iterator
+    /// is advanced, so this is fine for types with a huge number of messages, eg. every post in
+    /// the db.
+    pub fn stream_entries_by_type(
+        &self,
+        content_type: &str,
+    ) -> Result<impl Iterator<Item = Result<Vec<u8>>> + '_> {
+        let seqs = find_flume_seqs_by_type(&*self.index()?, content_type, self.include_hidden)
+            .context(UnableToQueryContentTypes)?;
+
+        Ok(seqs.into_iter().map(move |seq| self.get_entry(seq)))
+    }
+
+    /// Get the id each of the given `feeds` has in the sqlite `authors` table, in the same order as
+    /// `feeds`, or `None` for feeds we don't know. Eg. to build your own tables alongside the
+    /// indexes (see [SqliteSsbDb::from_connection]) with foreign keys to the authors.
+    ///
+    /// The ids are an implementation detail of the schema, so they can change between versions of
+    /// this crate. They're also given out again when the indexes are rebuilt, so tables referring
+    /// to them have to be rebuilt too.
+    pub fn get_author_ids(&self, feeds: &[Multikey]) -> Result<Vec<(Multikey, Option<i32>)>> {
+        let authors = feeds
+            .iter()
+            .map(|feed_id| feed_id.to_legacy_string())
+            .collect::<Vec<_>>();
+
+        let ids: HashMap<String, i32> = find_author_ids(&*self.index()?, &authors)
+            .context(FeedNotFound)?
+            .into_iter()
+            .collect();
+
+        Ok(feeds
+            .iter()
+            .zip(authors.iter())
+            .map(|(feed_id, author)| (feed_id.clone(), ids.get(author).copied()))
+            .collect())
+    }
+
+    /// Get the offset, key and author of every message indexed at or after `offset`, in the order
+    /// they were appended.
+    ///
+    /// Only the sqlite indexes are read, not the messages, so this is a cheap way for something
+    /// outside the db to keep its own indexes up to date. Checkpoint the last offset you've handled
+    /// and pass one more than it next time. Pass `0` to start from the beginning.
+    pub fn events_since(
+        &self,
+        offset: FlumeSequence,
+    ) -> Result<impl Iterator<Item = (FlumeSequence, Multihash, Multikey)>> {
+        let events = find_index_events_since(&*self.index()?, offset, self.include_hidden)
+            .context(MessageNotFound)?
+            .into_iter()
+            .map(|(offset, key, author)| Ok((offset, parse_key(&key)?, parse_author(&author)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(events.into_iter())
+    }
+
+    /// Find the messages whose `content` is exactly `content`, in the order they were appended,
+    /// stopping after `limit` of them if it's `Some`. Eg. to find a known pub announcement.
+    ///
+    /// There's no index on content, so this parses every entry in the offset log: it's O(n) in the
+    /// size of the log and meant for debugging, not the hot path. Messages that haven't been
+    /// indexed yet aren't returned.
+    pub fn find_by_content(
+        &self,
+        content: &serde_json::Value,
+        limit: Option<i64>,
+    ) -> Result<Vec<Vec<u8>>> {
+        let limit = limit.map_or(usize::MAX, |limit| limit.max(0) as usize);
+        let connection = self.index()?;
+
+        let mut found = Vec::new();
+        for entry in self.offset_log().iter() {
+            if found.len() >= limit {
+                break;
+            }
+            let message = match self.parser.parse_message(&entry.data) {
+                Some(message) if message.value.content == *content => message,
+                _ => continue,
+            };
+
+            // Only a handful of entries match, so checking each in the indexes is cheap.
+            let indexed =
+                find_message_flume_seq_by_key(&connection, &message.key, self.include_hidden)
+                    .optional()
+                    .context(MessageNotFound)?;
+            if indexed == Some(entry.offset) {
+                found.push(entry.data);
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Append a batch of valid ssb messages to the offset log without indexing them.
+    ///
+    /// The messages won't show up in queries until the next call to
+    /// [SqliteSsbDb::update_indexes_from_offset_file] (or [SsbDb::append_batch], which updates the
+    /// indexes too). Useful for ingesting lots of batches quickly and indexing later. An empty
+    /// batch does nothing.
+    pub fn append_batch_deferred<T: AsRef<[u8]>>(
+        &self,
+        _: &Multikey,
+        messages: &[T],
+    ) -> Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        self.append_to_offset_log(messages)?;
+
+        // The primary log is the source of truth, so it's already written by the time the mirror
+        // can fail.
+        self.append_to_mirror_log(messages)
+    }
+
+    // Returns the offset of each appended message.
+    fn append_to_offset_log<T: AsRef<[u8]>>(&self, messages: &[T]) -> Result<Vec<FlumeSequence>> {
+        self.offset_log()
+            .append_batch(messages)
+            .map_err(|_| Error::OffsetAppendError {})
+    }
+
+    fn append_to_mirror_log<T: AsRef<[u8]>>(&self, messages: &[T]) -> Result<()> {
+        match &self.mirror_log {
+            Some(mirror_log) => mirror_log
+                .borrow_mut()
+                .append_batch(messages)
+                .map(|_| ())
+                .map_err(|_| Error::MirrorAppendError {}),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace every message we have in the given feed with `messages`, eg. after downloading the
+    /// whole feed again because the copy we had was corrupt.
+    ///
+    /// It's an error if any of `messages` isn't a message by `feed_id`, and nothing is appended.
+    /// The new messages are appended to the offset log, then the feed's old messages are removed
+    /// from the indexes and the new ones indexed in one transaction, so readers never see a half
+    /// replaced feed. If that fails, the new entries are zeroed out in the offset log so they're
+    /// never indexed, and the old feed is left as it was.
+    ///
+    /// Once the new feed is indexed, the old entries are zeroed out in the offset log like
+    /// [SqliteSsbDb::handle_fork] does, so [SsbDb::rebuild_indexes] only finds the new feed.
+    pub fn replace_feed<T: AsRef<[u8]>>(&self, feed_id: &Multikey, messages: &[T]) -> Result<()> {
+        let author = feed_id.to_legacy_string();
+        if let Some(index) = messages.iter().position(|message| {
+            self.parser
+                .parse_message(message.as_ref())
+                .is_none_or(|message| message.value.author != author)
+        }) {
+            return Err(Error::MessageNotInFeed {
+                index,
+                feed_id: author,
+            });
+        }
+        // Fail before appending anything if we can't zero out the new entries.
+        self.writable_offset_log_path()?;
+        let _lock = self.lock_feed(feed_id);
+
+        // Index anything already waiting in the log, so the only entries left unindexed are the
+        // new ones.
+        drop(self.index()?);
+        self.update_indexes_from_offset_file()?;
+
+        // Hold the log until the new entries are indexed or zeroed, so no db sharing it can index
+        // them in the meantime.
+        let mut offset_log = self.offset_log();
+        let offsets = offset_log
+            .append_batch(messages)
+            .map_err(|_| Error::OffsetAppendError {})?;
+
+        let connection = self.connection.borrow();
+        let replaced = retry_on_busy(self.busy_retries, || {
+            connection.transaction::<_, db::AppendError, _>(|| {
+                let old = find_feed_flume_seqs_newer_than(&connection, &author, 0, None, true)?;
+                delete_feed_messages_newer_than(&connection, &author, 0)?;
+                offsets
+                    .iter()
+                    .zip(messages)
+                    .try_for_each(|(offset, message)| {
+                        append_item(
+                            &connection,
+                            self.parser.as_ref(),
+                            *offset,
+                            message.as_ref(),
+                            self.store_raw,
+                        )
+                        .map(|_| ())
+                    })?;
+                if let (Some(first_offset), Some(last_offset)) = (offsets.first(), offsets.last()) {
+                    self.finish_indexing(&connection, *first_offset, *last_offset)?;
+                }
+                Ok(old)
+            })
+        })
+        .map_err(|err| match err {
+            db::AppendError::KeyCollision { key } => Error::KeyCollision { key },
+            db::AppendError::Sqlite { .. } => Error::SqliteAppendError {},
+        });
+        drop(connection);
+
+        let replaced = match replaced {
+            Ok(replaced) => replaced,
+            Err(err) => {
+                let appended = offsets
+                    .iter()
+                    .zip(messages)
+                    .map(|(offset, message)| (*offset, message.as_ref().len()))
+                    .collect::<Vec<_>>();
+                self.zero_offset_log_entries(&appended)?;
+                return Err(err);
+            }
+        };
+
+        // Zero out the old entries like handle_fork does, so rebuilding the indexes doesn't bring
+        // them back alongside the new ones.
+        let replaced = replaced
+            .into_iter()
+            .map(|seq| {
+                offset_log
+                    .get(seq)
+                    .map(|entry| (seq, entry.len()))
+                    .map_err(|_| Error::OffsetGetError {})
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.zero_offset_log_entries(&replaced)?;
+        drop(offset_log);
+
+        self.append_to_mirror_log(messages)
+    }
+
+    /// Append every message from `stream`, eg. a muxrpc `createHistoryStream` source, without
+    /// buffering the whole stream in memory.
+    ///
+    /// Messages are appended `batch_size` at a time, and each batch is indexed before the next is
+    /// read, so appended messages show up in queries while the stream is still going. Returns how
+    /// many messages were appended. If appending a batch fails, the batches before it stay
+    /// appended.
+    #[cfg(feature = "async")]
+    pub async fn append_stream<S: Stream<Item = Vec<u8>> + Unpin>(
+        &self,
+        feed_id: &Multikey,
+        stream: S,
+        batch_size: usize,
+    ) -> Result<u64> {
+        let mut batches = stream.chunks(batch_size.max(1));
+        let mut appended = 0;
+
+        while let Some(batch) = batches.next().await {
+            self.append_batch(feed_id, &batch)?;
+            appended += batch.len() as u64;
+        }
+
+        Ok(appended)
+    }
+
+    /// Index any entries in the offset log that haven't been indexed yet.
+    ///
+    /// Returns how many entries were indexed, and how many were skipped because they aren't
+    /// messages (eg. zeroed out deleted entries) or their key or author doesn't parse.
+    ///
+    /// A message whose key is already indexed for a different message is skipped too, so it
+    /// doesn't stop the rest of the log from being indexed. Once the rest are indexed this fails
+    /// with [Error::KeyCollision], giving the key.
+    ///
+    /// If the indexes refer to entries past the end of the offset log, eg. because the log was
+    /// swapped for a shorter one, the indexes are cleared and the whole log is reindexed.
+    pub fn update_indexes_from_offset_file(&self) -> Result<IndexStats> {
+        self.index_offset_file_chunks(None)
+    }
+
+    /// Like [SqliteSsbDb::update_indexes_from_offset_file], but yields to the executor after
+    /// each commit (see [SqliteSsbDb::with_commit_every]).
+    ///
+    /// Catching up a large log can take a while, so on a single threaded runtime this stops
+    /// indexing from starving other tasks.
+    #[cfg(feature = "async")]
+    pub async fn update_indexes_cooperatively(&self) -> Result<IndexStats> {
+        let mut stats = IndexStats::default();
 
-impl SqliteSsbDb {
-    pub fn new<S: AsRef<str>>(database_path: S, offset_log_path: S) -> SqliteSsbDb {
-        let connection = setup_connection(database_path.as_ref());
+        loop {
+            let commit_stats = self.index_offset_file_chunks(Some(1))?;
+            stats.indexed += commit_stats.indexed;
+            stats.skipped += commit_stats.skipped;
 
-        let offset_log = match OffsetLog::new(&offset_log_path.as_ref()) {
-            Ok(log) => log,
-            Err(_) => {
-                panic!("failed to open offset log at {}", offset_log_path.as_ref());
+            // A short commit means we've reached the end of the log.
+            if commit_stats.indexed + commit_stats.skipped < self.commit_every as u64 {
+                return Ok(stats);
             }
-        };
-        SqliteSsbDb {
-            connection: RefCell::new(connection),
-            offset_log: RefCell::new(offset_log),
-            db_path: database_path.as_ref().to_owned(),
+
+            YieldNow(false).await;
         }
     }
 
-    pub fn update_indexes_from_offset_file(&self) -> Result<()> {
+    // Index at most `max_commits` transactions of entries, or the rest of the log if `None`.
+    fn index_offset_file_chunks(&self, max_commits: Option<usize>) -> Result<IndexStats> {
         //We're using Max of flume_seq.
         //When the db is empty, we'll get None.
         //When there is one item in the db, we'll get 0 (it's the first seq number you get)
         //When there's more than one you'll get some >0 number
+        //It stays an integer all the way through, offsets past 2^53 don't fit in an f64.
 
         let connection = self.connection.borrow_mut();
-        let offset_log = self.offset_log.borrow();
+        let offset_log = self.offset_log();
+
+        let max_seq = find_indexed_offset(&connection).context(UnableToGetLatestSequence)?;
+
+        // If the latest indexed entry is past the end of the offset log then the log has been
+        // truncated or replaced since it was indexed, and the indexes point at the wrong entries.
+        // Start again from the beginning of the log.
+        let max_seq = match max_seq {
+            Some(max_seq) if max_seq >= offset_log.end() => {
+                clear_indexes(&connection).context(SqliteClearError)?;
+                None
+            }
+            max_seq => max_seq,
+        };
+
+        let mut stats = IndexStats::default();
+        let mut last_indexed = max_seq;
+        let mut collision = None;
+
+        for _ in 0..max_commits.unwrap_or(usize::MAX) {
+            // If sqlite is busy, the whole transaction is retried by reading its entries again.
+            let (commit_stats, last_offset, commit_collision) =
+                retry_on_busy(self.busy_retries, || {
+                    connection.transaction::<_, db::AppendError, _>(|| {
+                        self.index_entries_after(&connection, &offset_log, last_indexed)
+                    })
+                })
+                .map_err(|_| Error::SqliteAppendError {})?;
 
-        let max_seq = get_latest(&connection)
-            .context(UnableToGetLatestSequence)?
-            .map(|val| val as u64);
+            stats.indexed += commit_stats.indexed;
+            stats.skipped += commit_stats.skipped;
+            collision = collision.or(commit_collision);
 
-        let num_to_skip: usize = match max_seq {
-            None => 0,
-            _ => 1,
+            match last_offset {
+                Some(last_offset) => last_indexed = Some(last_offset),
+                None => break,
+            }
+        }
+
+        // The colliding entries were skipped so they don't hold up the rest of the log, but the
+        // caller should still hear about them.
+        match collision {
+            Some(key) => Err(Error::KeyCollision { key }),
+            None => Ok(stats),
+        }
+    }
+
+    // Index up to `commit_every` entries after the `after` offset (or from the start of the log if
+    // `None`), reading `read_chunk_size` of them at a time. Returns the offset of the last entry,
+    // and the key of the first entry skipped because its key belongs to a different message.
+    fn index_entries_after(
+        &self,
+        connection: &SqliteConnection,
+        offset_log: &OffsetLog<u32>,
+        after: Option<FlumeSequence>,
+    ) -> std::result::Result<(IndexStats, Option<FlumeSequence>, Option<String>), db::AppendError>
+    {
+        let (starting_offset, num_to_skip) = match after {
+            Some(after) => (after, 1),
+            None => (0, 0),
         };
 
-        let starting_offset = max_seq.unwrap_or(0);
+        let mut stats = IndexStats::default();
+        let mut first_offset = None;
+        let mut last_offset = None;
+        let mut collision = None;
 
-        offset_log
+        for chunk in &offset_log
             .iter_at_offset(starting_offset)
             .skip(num_to_skip)
-            .chunks(10000)
-            .into_iter()
-            .map(|chunk| {
-                connection
-                    .transaction::<_, db::Error, _>(|| {
-                        chunk
-                            .map(|log_entry| {
-                                append_item(&connection, log_entry.offset, &log_entry.data)?;
+            .take(self.commit_every)
+            .chunks(self.read_chunk_size)
+        {
+            for log_entry in chunk.collect::<Vec<_>>() {
+                let indexed = match append_item(
+                    connection,
+                    self.parser.as_ref(),
+                    log_entry.offset,
+                    &log_entry.data,
+                    self.store_raw,
+                ) {
+                    // Nothing was written for it, so skip it like any other entry we can't index,
+                    // rather than failing every later attempt to index past it.
+                    Err(db::AppendError::KeyCollision { key }) => {
+                        collision.get_or_insert(key);
+                        false
+                    }
+                    indexed => indexed?,
+                };
+                if indexed {
+                    stats.indexed += 1;
+                } else {
+                    stats.skipped += 1;
+                }
+                first_offset.get_or_insert(log_entry.offset);
+                last_offset = Some(log_entry.offset);
+            }
+        }
 
-                                Ok(())
-                            })
-                            .collect::<std::result::Result<(), db::Error>>()
-                    })
-                    .map_err(|_| Error::SqliteAppendError {})
-                    .and_then(|_| Ok(()))
+        if let (Some(first_offset), Some(last_offset)) = (first_offset, last_offset) {
+            self.finish_indexing(connection, first_offset, last_offset)?;
+        }
+
+        Ok((stats, last_offset, collision))
+    }
+
+    // Once the entries from `first_offset` to `last_offset` have been indexed, in the same
+    // transaction, bring the rest of the indexes up to date with them.
+    fn finish_indexing(
+        &self,
+        connection: &SqliteConnection,
+        first_offset: FlumeSequence,
+        last_offset: FlumeSequence,
+    ) -> std::result::Result<(), db::Error> {
+        // Hidden flags and received times that were set aren't in the log, so put them back on
+        // the messages we indexed.
+        hide_messages_since(connection, first_offset)?;
+        override_received_at_since(connection, first_offset)?;
+        if let Some(max) = self.max_messages_per_feed {
+            delete_feed_messages_over_limit(connection, max, first_offset)?;
+        }
+        // Skipped entries aren't in the messages table, so remember we got past them.
+        set_indexed_offset(connection, last_offset)
+    }
+
+    /// Hide or unhide a message by its ssb message key.
+    ///
+    /// Hidden messages are still replicated, so they're still returned by
+    /// [SsbDb::get_entries_newer_than_sequence] and [SsbDb::create_history_stream] and count
+    /// towards the feed's latest sequence, but other reads skip them, eg.
+    /// [SsbDb::get_entry_by_key], [SsbDb::get_entry_by_seq] or [SsbDb::get_feed_first_entry].
+    ///
+    /// Hiding a message is local state rather than part of the indexes, so it's kept when they're
+    /// rebuilt.
+    pub fn set_hidden(&self, message_key: &Multihash, hidden: bool) -> Result<()> {
+        let key = message_key.to_legacy_string();
+        let connection = self.index()?;
+        let updated = connection
+            .transaction::<_, db::Error, _>(|| {
+                let updated = set_message_hidden(&connection, &key, hidden)?;
+                // Remember it outside the indexes too, so it's put back when they're rebuilt.
+                if updated > 0 {
+                    set_key_hidden(&connection, &key, hidden)?;
+                }
+                Ok(updated)
+            })
+            .context(MessageNotFound)?;
+
+        match updated {
+            0 => Err(db::Error::NotFound).context(MessageNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// Set when every message in the given feed was received to `ts_ms` milliseconds since the
+    /// epoch, eg. to correct a feed imported in bulk that looks like it all arrived at once.
+    /// Returns how many messages were updated, `0` if we don't have the feed.
+    ///
+    /// Messages appended to the feed afterwards aren't affected. Like hidden flags, this is local
+    /// state, so it's kept when the indexes are rebuilt.
+    pub fn set_feed_received_at(&self, feed_id: &Multikey, ts_ms: i64) -> Result<usize> {
+        let author = feed_id.to_legacy_string();
+        let connection = self.index()?;
+        connection
+            .transaction::<_, db::Error, _>(|| {
+                // Remember it outside the indexes too, so it's put back when they're rebuilt.
+                override_feed_received_at(&connection, &author, ts_ms as f64)?;
+                set_feed_received_at(&connection, &author, ts_ms as f64)
             })
+            .context(SqliteWriteError)
+    }
+
+    /// Set the local metadata for the given feed, replacing any it had. The feed doesn't have to
+    /// have any messages yet.
+    pub fn set_feed_meta(&self, feed_id: &Multikey, meta: FeedMeta) -> Result<()> {
+        let row = FeedMetaRow {
+            author: feed_id.to_legacy_string(),
+            nickname: meta.nickname,
+            priority: meta.priority,
+            muted: meta.muted,
+        };
+        replace_feed_meta(&*self.index()?, &[row])
+            .map(|_| ())
+            .context(SqliteWriteError)
+    }
+
+    /// Get the local metadata for the given feed, or `None` if it hasn't been set.
+    pub fn get_feed_meta(&self, feed_id: &Multikey) -> Result<Option<FeedMeta>> {
+        let row =
+            find_feed_meta(&*self.index()?, &feed_id.to_legacy_string()).context(FeedNotFound)?;
+
+        Ok(row.map(|row| FeedMeta {
+            nickname: row.nickname,
+            priority: row.priority,
+            muted: row.muted,
+        }))
+    }
+
+    /// Get the entries at each of the given flume `offsets`, in the same order as `offsets`.
+    ///
+    /// Entries are read from the offset log in ascending offset order to minimise seeking. Each
+    /// entry is its own `Result` so one bad offset doesn't fail the whole batch.
+    pub fn get_entries_at_offsets(
+        &self,
+        offsets: &[FlumeSequence],
+    ) -> Result<Vec<Result<Vec<u8>>>> {
+        let mut entries = offsets
+            .iter()
+            .enumerate()
+            .sorted_by_key(|(_, offset)| **offset)
+            .map(|(index, offset)| (index, self.get_entry(*offset)))
+            .collect::<Vec<_>>();
+
+        entries.sort_by_key(|(index, _)| *index);
+
+        Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// Get the offset of the last entry that has been indexed, or `None` if nothing has been
+    /// indexed yet. Eg. to checkpoint how far through the log the indexes are.
+    pub fn latest_indexed_offset(&self) -> Result<Option<FlumeSequence>> {
+        find_indexed_offset(&*self.index()?).context(UnableToGetLatestSequence)
+    }
+
+    /// Check whether the indexes have been built, ie. something has been indexed, or there's
+    /// nothing in the offset log to index.
+    ///
+    /// Until the indexes are updated, queries return nothing as if the db were empty. This tells
+    /// the two apart, eg. to catch forgetting to index at startup.
+    pub fn is_indexed(&self) -> Result<bool> {
+        // Go by how far indexing got rather than by the messages, so a log of only skipped
+        // entries counts as indexed.
+        let indexed = find_indexed_offset(&*self.index()?).context(UnableToGetLatestSequence)?;
+        Ok(indexed.is_some() || self.offset_log().end() == 0)
+    }
+
+    /// Get the entry with the smallest offset, or `None` if the db is empty. Eg. to find where the
+    /// retained history starts, which isn't offset 0 once the log has been compacted.
+    pub fn get_oldest_entry(&self) -> Result<Option<Vec<u8>>> {
+        get_oldest(&*self.index()?)
+            .context(MessageNotFound)?
+            .map(|flume_seq| self.get_entry(flume_seq))
+            .transpose()
+    }
+
+    /// Get the offset of the `n`th message in the offset log (counting from 0), in the order they
+    /// were appended.
+    ///
+    /// Useful for splitting the log into ranges to scan in parallel. Returns `None` if there are
+    /// `n` or fewer messages.
+    pub fn offset_at_index(&self, n: u64) -> Result<Option<FlumeSequence>> {
+        find_flume_seq_at_index(&*self.index()?, n).context(MessageNotFound)
+    }
+
+    /// Get every entry with the given content `type`, from all feeds, in the order they were
+    /// appended.
+    ///
+    /// See [SqliteSsbDb::stream_entries_by_type] for types with too many messages to hold at once.
+    pub fn get_entries_by_type(&self, content_type: &str) -> Result<Vec<Vec<u8>>> {
+        self.stream_entries_by_type(content_type)?.collect()
+    }
+
+    /// Like [SqliteSsbDb::get_entries_by_type], but with a [CommonContentType] instead of a string.
+    pub fn get_entries_by_common_type(
+        &self,
+        content_type: &CommonContentType,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.get_entries_by_type(content_type.as_str())
+    }
+
+    /// Get every message that mentions the given feed anywhere in its content, in the order
+    /// they were appended. Eg. for "someone mentioned you" notifications.
+    pub fn get_mentions_of(&self, feed_id: &Multikey) -> Result<Vec<Vec<u8>>> {
+        find_feed_mention_flume_seqs(
+            &*self.index()?,
+            &feed_id.to_legacy_string(),
+            self.include_hidden,
+        )
+        .context(MessageNotFound)?
+        .into_iter()
+        .map(|seq| self.get_entry(seq))
+        .collect()
+    }
+
+    /// Get every message that links to any of the `targets` anywhere in its content (eg. replies
+    /// to them), in the order they were appended.
+    ///
+    /// Useful for loading a whole thread in a few rounds rather than a query per message: start
+    /// with the root, then ask for links to all the replies found so far.
+    pub fn get_links_to_any(&self, targets: &[Multihash]) -> Result<Vec<Vec<u8>>> {
+        let targets = targets
+            .iter()
+            .map(|target| target.to_legacy_string())
+            .collect::<Vec<_>>();
+
+        find_links_to_any_flume_seqs(&*self.index()?, &targets, self.include_hidden)
+            .context(MessageNotFound)?
+            .into_iter()
+            .map(|seq| self.get_entry(seq))
+            .collect()
+    }
+
+    /// Get the messages by any of the given `feeds` that link to `target`, in the order they were
+    /// appended. Eg. the replies to a post from feeds you follow, hiding replies from strangers.
+    pub fn get_links_to_from_feeds(
+        &self,
+        target: &Multihash,
+        feeds: &[Multikey],
+    ) -> Result<Vec<Vec<u8>>> {
+        let authors = feeds
+            .iter()
+            .map(|feed_id| feed_id.to_legacy_string())
+            .collect::<Vec<_>>();
+
+        find_links_to_from_authors_flume_seqs(
+            &*self.index()?,
+            &target.to_legacy_string(),
+            &authors,
+            self.include_hidden,
+        )
+        .context(MessageNotFound)?
+        .into_iter()
+        .map(|seq| self.get_entry(seq))
+        .collect()
+    }
+
+    /// Count how many messages the given feed has that were received in the last `window_ms`
+    /// milliseconds, eg. to spot feeds that are spamming.
+    ///
+    /// Uses when each message was received, or the timestamp the author claims if we don't know
+    /// that.
+    pub fn feed_message_rate(&self, feed_id: &Multikey, window_ms: f64) -> Result<i64> {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_millis() as f64)
+            .unwrap_or(0.0);
+
+        count_feed_messages_received_since(
+            &*self.index()?,
+            &feed_id.to_legacy_string(),
+            now_ms - window_ms,
+        )
+        .context(FeedNotFound)
+    }
+
+    /// Get the first message (sequence 1) of every feed we have it for, in the order they were
+    /// appended. Useful for finding which feeds we only have part of.
+    pub fn get_feed_roots(&self) -> Result<Vec<Vec<u8>>> {
+        find_root_flume_seqs(&*self.index()?, self.include_hidden)
+            .context(MessageNotFound)?
+            .into_iter()
+            .map(|seq| self.get_entry(seq))
+            .collect()
+    }
+
+    /// Get the messages matching every filter that's `Some`, in the order they were appended. Eg.
+    /// the posts by a feed in the last week.
+    ///
+    /// `since_ms` is in ms since the unix epoch, and uses when each message was received, or the
+    /// timestamp the author claims if we don't know that. Like
+    /// [SsbDb::get_entries_newer_than_sequence], a negative `limit` means no limit.
+    pub fn query(
+        &self,
+        feed_id: Option<&Multikey>,
+        content_type: Option<&str>,
+        since_ms: Option<f64>,
+        limit: Option<i64>,
+    ) -> Result<Vec<Vec<u8>>> {
+        let author = feed_id.map(|feed_id| feed_id.to_legacy_string());
+
+        find_flume_seqs_matching(
+            &*self.index()?,
+            author.as_deref(),
+            content_type,
+            since_ms,
+            limit,
+            self.include_hidden,
+        )
+        .context(MessageNotFound)?
+        .into_iter()
+        .map(|seq| self.get_entry(seq))
+        .collect()
+    }
+
+    /// Get up to `page_size` messages from the given feed, in the order they were appended,
+    /// starting after `cursor` (or from the start of the feed if it's `None`).
+    ///
+    /// Also returns a cursor for the next page, or `None` if this was the last one. Unlike an
+    /// offset, a cursor still points at the same place after more messages are appended, eg. for
+    /// infinite scrolling.
+    ///
+    /// A `page_size` below 1 is treated as 1, so paging always makes progress.
+    pub fn page(
+        &self,
+        feed_id: &Multikey,
+        cursor: Option<Cursor>,
+        page_size: i64,
+    ) -> Result<(Vec<Vec<u8>>, Option<Cursor>)> {
+        // An empty page would never get any further through the feed.
+        let page_size = page_size.max(1);
+        // Ask for one extra so we know whether there's another page.
+        let mut seqs = find_feed_flume_seqs_after(
+            &*self.index()?,
+            &feed_id.to_legacy_string(),
+            cursor.map(|cursor| cursor.0),
+            page_size.saturating_add(1),
+            self.include_hidden,
+        )
+        .context(FeedNotFound)?;
+
+        let next = if seqs.len() as i64 > page_size {
+            seqs.truncate(page_size as usize);
+            seqs.last().map(|seq| Cursor(*seq))
+        } else {
+            None
+        };
+
+        let entries = seqs
+            .into_iter()
+            .map(|seq| self.get_entry(seq))
+            .collect::<Result<_>>()?;
+
+        Ok((entries, next))
+    }
+
+    /// Get each distinct message content `type` in the db, with how many messages have it. Eg. for
+    /// a dashboard showing the db is mostly posts and votes.
+    ///
+    /// Sorted from most to least common. Messages without a content type (eg. private messages)
+    /// aren't counted rather than being put in a bucket of their own.
+    #[doc(alias = "message_type_histogram")]
+    pub fn get_content_types(&self) -> Result<Vec<(String, i64)>> {
+        let content_types = find_content_type_counts(&*self.index()?)
+            .context(UnableToQueryContentTypes)?
+            .into_iter()
+            .map(|content_type| (content_type.content_type, content_type.count))
+            .collect();
+
+        Ok(content_types)
+    }
+
+    /// Get the number of bytes each feed takes up in the offset log.
+    pub fn get_feed_byte_sizes(&self) -> Result<Vec<(Multikey, u64)>> {
+        find_feed_byte_lens(&*self.index()?)
+            .context(FeedNotFound)?
+            .into_iter()
+            .map(|feed| Ok((parse_author(&feed.author)?, feed.byte_len as u64)))
             .collect()
     }
 }
 
 impl SsbDb for SqliteSsbDb {
-    fn append_batch<T: AsRef<[u8]>>(&self, _: &Multikey, messages: &[T]) -> Result<()> {
-        // First, append the messages to flume
-        self.offset_log
-            .borrow_mut()
-            .append_batch(messages)
-            .map_err(|_| Error::OffsetAppendError {})?;
+    fn append_batch<T: AsRef<[u8]>>(&self, feed_id: &Multikey, messages: &[T]) -> Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        // Appending and indexing a feed's messages happen together, so concurrent batches for one
+        // feed can't interleave.
+        let _lock = self.lock_feed(feed_id);
 
-        self.update_indexes_from_offset_file()
+        // First, append the messages to flume
+        match self.append_batch_deferred(feed_id, messages) {
+            // The main offset log was appended to, so index it before reporting the mirror error.
+            Err(Error::MirrorAppendError {}) => {
+                self.update_indexes_from_offset_file()?;
+                Err(Error::MirrorAppendError {})
+            }
+            res => {
+                res?;
+                self.update_indexes_from_offset_file().map(|_| ())
+            }
+        }
     }
-    fn get_entry_by_key<'a>(&'a self, message_key: &Multihash) -> Result<Vec<u8>> {
+    fn get_entry_by_key(&self, message_key: &Multihash) -> Result<Vec<u8>> {
         let flume_seq = find_message_flume_seq_by_key(
-            &self.connection.borrow(),
+            &*self.index()?,
             &message_key.to_legacy_string(),
+            self.include_hidden,
         )
         .context(MessageNotFound)?;
-        self.offset_log
-            .borrow()
-            .get(flume_seq)
-            .map_err(|_| Error::OffsetGetError {})
+        self.get_entry(flume_seq)
+    }
+    fn get_content_by_key(&self, message_key: &Multihash) -> Result<Option<serde_json::Value>> {
+        let key = message_key.to_legacy_string();
+        let flume_seq = find_message_flume_seq_by_key(&*self.index()?, &key, self.include_hidden)
+            .optional()
+            .context(MessageNotFound)?;
+
+        let flume_seq = match flume_seq {
+            Some(flume_seq) => flume_seq,
+            None => return Ok(None),
+        };
+        let message = self
+            .parser
+            .parse_message(&self.get_entry(flume_seq)?)
+            .context(CorruptEntry { offset: flume_seq })?;
+
+        match message.value.content {
+            serde_json::Value::Null => Err(Error::MissingContent { key }),
+            content => Ok(Some(content)),
+        }
+    }
+    fn common_frontier(&self, theirs: &[(Multikey, i32)]) -> Result<Vec<(Multikey, i32)>> {
+        let authors = theirs
+            .iter()
+            .map(|(feed_id, _)| feed_id.to_legacy_string())
+            .collect::<Vec<_>>();
+
+        let ours: HashMap<String, i32> = find_feeds_latest_seqs(&*self.index()?, &authors)
+            .context(FeedNotFound)?
+            .into_iter()
+            .collect();
+
+        Ok(theirs
+            .iter()
+            .zip(authors.iter())
+            .map(|((feed_id, their_seq), author)| {
+                let our_seq = ours.get(author).copied().unwrap_or(0);
+                // Feeds start at 1, so a negative sequence means they have none of it either.
+                (feed_id.clone(), our_seq.min(*their_seq).max(0))
+            })
+            .collect())
+    }
+    fn get_author_by_key(&self, message_key: &Multihash) -> Result<Option<Multikey>> {
+        find_message_author_by_key(
+            &*self.index()?,
+            &message_key.to_legacy_string(),
+            self.include_hidden,
+        )
+        .context(MessageNotFound)?
+        .map(|author| parse_author(&author))
+        .transpose()
+    }
+    fn get_seq_by_key(&self, message_key: &Multihash) -> Result<Option<i32>> {
+        find_message_seq_by_key(
+            &*self.index()?,
+            &message_key.to_legacy_string(),
+            self.include_hidden,
+        )
+        .context(MessageNotFound)
+    }
+    fn is_latest_in_feed(&self, message_key: &Multihash) -> Result<bool> {
+        find_message_is_latest_by_key(
+            &*self.index()?,
+            &message_key.to_legacy_string(),
+            self.include_hidden,
+        )
+        .context(MessageNotFound)
+        .map(|is_latest| is_latest.unwrap_or(false))
+    }
+    fn missing_keys(&self, candidate_keys: &[Multihash]) -> Result<Vec<Multihash>> {
+        let candidates = candidate_keys
+            .iter()
+            .map(|key| key.to_legacy_string())
+            .collect::<Vec<_>>();
+
+        let present = find_message_keys_present(&*self.index()?, &candidates)
+            .context(MessageNotFound)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        Ok(candidate_keys
+            .iter()
+            .zip(candidates.iter())
+            .filter(|(_, legacy_key)| !present.contains(*legacy_key))
+            .map(|(key, _)| key.clone())
+            .collect())
     }
+    fn have_sequences(&self, feed_id: &Multikey, range: RangeInclusive<i32>) -> Result<Vec<bool>> {
+        let have = find_feed_seqs_between(
+            &*self.index()?,
+            &feed_id.to_legacy_string(),
+            *range.start(),
+            *range.end(),
+        )
+        .context(FeedNotFound)?
+        .into_iter()
+        .collect::<HashSet<_>>();
 
+        Ok(range.map(|seq| have.contains(&seq)).collect())
+    }
     fn get_entry_by_seq(&self, feed_id: &Multikey, sequence: i32) -> Result<Option<Vec<u8>>> {
         let flume_seq = find_message_flume_seq_by_author_and_sequence(
-            &self.connection.borrow(),
+            &*self.index()?,
             &feed_id.to_legacy_string(),
             sequence,
+            self.include_hidden,
         )
         .context(MessageNotFound)?;
 
         flume_seq
-            .map(|flume_seq| {
-                self.offset_log
-                    .borrow()
-                    .get(flume_seq as u64)
-                    .map_err(|_| Error::OffsetGetError {})
-            })
+            .map(|flume_seq| self.get_entry(flume_seq as u64))
+            .transpose()
+    }
+    fn get_context_around_key(
+        &self,
+        message_key: &Multihash,
+        before: i64,
+        after: i64,
+    ) -> Result<Vec<Vec<u8>>> {
+        let (author, seq) = self.find_author_and_seq(&message_key.to_legacy_string())?;
+        let seq = i64::from(seq);
+
+        let start = seq.saturating_sub(before.max(0)).max(1) as i32;
+        let end = seq.saturating_add(after.max(0)).min(i64::from(i32::MAX)) as i32;
+        find_feed_flume_seqs_between(&*self.index()?, &author, start, end, self.include_hidden)
+            .context(FeedNotFound)?
+            .into_iter()
+            .map(|flume_seq| self.get_entry(flume_seq))
+            .collect()
+    }
+    fn get_entries_between_keys(
+        &self,
+        start_key: &Multihash,
+        end_key: &Multihash,
+    ) -> Result<Vec<Vec<u8>>> {
+        let start_key = start_key.to_legacy_string();
+        let end_key = end_key.to_legacy_string();
+        let (author, start_seq) = self.find_author_and_seq(&start_key)?;
+        let (end_author, end_seq) = self.find_author_and_seq(&end_key)?;
+        if author != end_author {
+            return Err(Error::KeysInDifferentFeeds { start_key, end_key });
+        }
+
+        find_feed_flume_seqs_between(
+            &*self.index()?,
+            &author,
+            start_seq.min(end_seq),
+            start_seq.max(end_seq),
+            self.include_hidden,
+        )
+        .context(FeedNotFound)?
+        .into_iter()
+        .map(|flume_seq| self.get_entry(flume_seq))
+        .collect()
+    }
+    fn get_feed_first_entry(&self, feed_id: &Multikey) -> Result<Option<Vec<u8>>> {
+        let flume_seq = find_feed_first_flume_seq(
+            &*self.index()?,
+            &feed_id.to_legacy_string(),
+            self.include_hidden,
+        )
+        .context(FeedNotFound)?;
+
+        flume_seq
+            .map(|flume_seq| self.get_entry(flume_seq as u64))
             .transpose()
     }
+    fn get_feed_entries_by_type(
+        &self,
+        feed_id: &Multikey,
+        content_type: &str,
+        limit: Option<i64>,
+    ) -> Result<Vec<Vec<u8>>> {
+        find_feed_flume_seqs_by_type(
+            &*self.index()?,
+            &feed_id.to_legacy_string(),
+            content_type,
+            limit,
+            self.include_hidden,
+        )
+        .context(FeedNotFound)?
+        .iter()
+        .map(|seq| self.get_entry(*seq))
+        .collect()
+    }
+    fn get_latest_by_type(&self, feed_id: &Multikey) -> Result<HashMap<String, Vec<u8>>> {
+        find_feed_latest_flume_seq_by_type(
+            &*self.index()?,
+            &feed_id.to_legacy_string(),
+            self.include_hidden,
+        )
+        .context(UnableToQueryContentTypes)?
+        .into_iter()
+        .map(|latest| {
+            let entry = self.get_entry(latest.flume_seq as FlumeSequence)?;
+            Ok((latest.content_type, entry))
+        })
+        .collect()
+    }
+    fn get_latest_entries_for_feeds(&self, feeds: &[Multikey]) -> Result<Vec<Vec<u8>>> {
+        let authors = feeds
+            .iter()
+            .map(|feed_id| feed_id.to_legacy_string())
+            .collect::<Vec<_>>();
+
+        let latest: HashMap<String, i64> =
+            find_feeds_latest_flume_seqs(&*self.index()?, &authors, self.include_hidden)
+                .context(FeedNotFound)?
+                .into_iter()
+                .collect();
+
+        let offsets = authors
+            .iter()
+            .filter_map(|author| latest.get(author))
+            .map(|flume_seq| *flume_seq as FlumeSequence)
+            .collect::<Vec<_>>();
+
+        self.get_entries_at_offsets(&offsets)?.into_iter().collect()
+    }
     fn get_feed_latest_sequence(&self, feed_id: &Multikey) -> Result<Option<i32>> {
-        find_feed_latest_seq(&self.connection.borrow(), &feed_id.to_legacy_string())
-            .context(FeedNotFound)
+        find_feed_latest_seq(&*self.index()?, &feed_id.to_legacy_string()).context(FeedNotFound)
+    }
+    fn get_feed_chain(
+        &self,
+        feed_id: &Multikey,
+    ) -> Result<Vec<(i32, Option<Multihash>, Multihash)>> {
+        find_feed_chain(&*self.index()?, &feed_id.to_legacy_string())
+            .context(FeedNotFound)?
+            .into_iter()
+            .map(|link| {
+                let previous = link.previous.as_deref().map(parse_key).transpose()?;
+                Ok((link.seq, previous, parse_key(&link.key)?))
+            })
+            .collect()
     }
-    fn get_entries_newer_than_sequence<'a>(
-        &'a self,
+    fn is_feed_complete(&self, feed_id: &Multikey) -> Result<bool> {
+        find_feed_is_complete(&*self.index()?, &feed_id.to_legacy_string()).context(FeedNotFound)
+    }
+    fn get_entries_newer_than_sequence(
+        &self,
         feed_id: &Multikey,
         sequence: i32,
         limit: Option<i64>,
@@ -142,86 +2367,289 @@ impl SsbDb for SqliteSsbDb {
         include_values: bool,
     ) -> Result<Vec<Vec<u8>>> {
         let seqs = find_feed_flume_seqs_newer_than(
-            &self.connection.borrow(),
+            &*self.index()?,
             &feed_id.to_legacy_string(),
             sequence,
             limit,
+            true,
         )
         .context(FeedNotFound)?;
 
-        match (include_keys, include_values) {
-            (false, false) => Err(Error::IncludeKeysIncludeValuesBothFalse {}),
-            (true, false) => seqs
-                .iter()
-                .flat_map(|seq| {
-                    self.offset_log
-                        .borrow()
-                        .get(*seq)
-                        .map_err(|_| Error::OffsetGetError {})
-                })
-                .flat_map(|msg| serde_json::from_slice::<SsbMessage>(&msg))
-                .map(|msg| Ok(msg.key.into_bytes()))
-                .collect(),
-            (false, true) => {
-                seqs.iter()
-                    .flat_map(|seq| {
-                        self.offset_log
-                            .borrow()
-                            .get(*seq)
-                            .map_err(|_| Error::OffsetGetError {})
-                    })
-                    .flat_map(|msg| {
-                        //If we're going to use Serde to pluck out the value we have to use
-                        //ssb-legacy-data Value so that when we convert it back to a string, the
-                        //ordering is still intact.
-                        //If we don't do that then we would return a message that would fail
-                        //verification
-                        ssb_legacy_msg_data::json::from_slice(&msg)
-                    })
-                    .map(|legacy_value| {
-                        if let Value::Object(legacy_val) = legacy_value {
-                            let val = legacy_val.get("value").context(ErrorParsingAsLegacyValue)?;
-                            ssb_legacy_msg_data::json::to_vec(&val, false)
-                                .map_err(|_| Error::EncodingValueAsVecError {})
-                        } else {
-                            Err(Error::ErrorParsingAsLegacyValue {})
-                        }
-                    })
-                    .collect()
-            }
-            (true, true) => seqs
-                .iter()
-                .map(|seq| {
-                    self.offset_log
-                        .borrow()
-                        .get(*seq)
-                        .map_err(|_| Error::OffsetGetError {})
-                })
-                .collect(),
+        if !include_keys && !include_values {
+            return Err(Error::IncludeKeysIncludeValuesBothFalse {});
         }
+        seqs.iter()
+            .map(|seq| {
+                select_entry_parts(*seq, self.get_entry(*seq)?, include_keys, include_values)
+            })
+            .collect()
     }
-    fn rebuild_indexes(&self) -> Result<()> {
-        std::fs::remove_file(&self.db_path).unwrap();
-        self.connection.replace(setup_connection(&self.db_path));
+    fn rebuild_indexes(&self) -> Result<IndexStats> {
+        match &self.db_path {
+            // It may already be gone, eg. cleaned up by another tool, leaving nothing to keep.
+            Some(db_path) if !Path::new(db_path).exists() => self.reconnect(db_path)?,
+            Some(db_path) => {
+                // Clear the indexes in place, so the local state is kept. If the db is too damaged
+                // for that, start again with a new file, keeping whatever local state we can read.
+                let connection = self.connection.borrow();
+                if clear_indexes(&connection).is_err() {
+                    let local_state = LocalState::save(&connection).unwrap_or_default();
+                    drop(connection);
+                    std::fs::remove_file(db_path).map_err(|_| Error::SqliteResetError {
+                        path: db_path.to_owned(),
+                    })?;
+                    self.reconnect(db_path)?;
+                    local_state
+                        .restore(&self.connection.borrow())
+                        .context(LocalStateRestoreError)?;
+                }
+            }
+            None => clear_indexes(&self.connection.borrow()).context(SqliteClearError)?,
+        }
         self.update_indexes_from_offset_file()
     }
 }
-fn setup_connection(database_path: &str) -> SqliteConnection {
-    let database_url = to_sqlite_uri(database_path, "rwc");
-    let connection = SqliteConnection::establish(&database_url)
-        .expect(&format!("Error connecting to {}", database_url));
 
-    if let Err(_) = any_pending_migrations(&connection) {
-        embedded_migrations::run(&connection).unwrap();
+impl Drop for SqliteSsbDb {
+    fn drop(&mut self) {
+        // Best effort, use `close` to find out if it worked.
+        let _ = self.checkpoint();
+        if let (true, Some(db_path)) = (self.remove_db_on_drop, &self.db_path) {
+            let _ = std::fs::remove_file(db_path);
+        }
+        if let (true, Some(offset_log_path)) =
+            (self.remove_offset_log_on_drop, &self.offset_log_path)
+        {
+            let _ = std::fs::remove_file(offset_log_path);
+        }
+    }
+}
+
+fn file_size(path: &str) -> Result<u64> {
+    std::fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .map_err(|_| Error::DiskUsageError {
+            path: path.to_owned(),
+        })
+}
+
+pub(crate) fn open_offset_log(offset_log_path: &str) -> Arc<Mutex<OffsetLog<u32>>> {
+    match OffsetLog::new(offset_log_path) {
+        Ok(log) => Arc::new(Mutex::new(log)),
+        Err(_) => {
+            panic!("failed to open offset log at {}", offset_log_path);
+        }
+    }
+}
+
+fn temp_path(extension: &str) -> String {
+    std::env::temp_dir()
+        .join(format!(
+            "ssb-db-{}-{}.{}",
+            std::process::id(),
+            TEMP_FILE_COUNT.fetch_add(1, Ordering::SeqCst),
+            extension
+        ))
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn setup_connection(
+    database_path: &str,
+    options: ConnectionOptions,
+    query_deadline: &QueryDeadline,
+) -> Result<(SqliteConnection, MigrationOutcome)> {
+    let database_url = to_sqlite_uri(database_path, "rwc", options.shared_cache);
+    let establish = || -> Result<SqliteConnection> {
+        let connection = query_timeout::establish(&database_url, query_deadline).map_err(|_| {
+            Error::SqliteConnectError {
+                path: database_path.to_owned(),
+            }
+        })?;
+
+        // The page size has to be set before the migrations create any tables, or it's ignored.
+        let mut pragmas = String::new();
+        if let Some(page_size) = options.page_size {
+            pragmas.push_str(&format!("PRAGMA page_size = {};", page_size));
+        }
+        if let Some(cache_size) = options.cache_size {
+            pragmas.push_str(&format!("PRAGMA cache_size = {};", cache_size));
+        }
+        if options.wal {
+            pragmas.push_str("PRAGMA journal_mode = WAL;");
+        }
+        connection
+            .batch_execute(&pragmas)
+            .context(SqliteWriteError)?;
+
+        Ok(connection)
+    };
+    let mut connection = establish()?;
+    let mut versions_before = find_migration_versions(&connection);
+    let mut indexes_reset = false;
+    let mut local_state_error = None;
+
+    if any_pending_migrations(&connection).is_err() {
+        embedded_migrations::run(&connection).map_err(|_| Error::SqliteMigrationError {})?;
     }
 
     if let Ok(true) = any_pending_migrations(&connection) {
-        std::fs::remove_file(&database_path).unwrap();
-        embedded_migrations::run(&connection).unwrap();
+        // The indexes are derived from the offset log, so rather than migrate them we start
+        // again with a fresh db, carrying over the local state that isn't. We have to reconnect,
+        // or we'd keep using the deleted file.
+        let local_state = LocalState::save(&connection).unwrap_or_default();
+        drop(connection);
+        std::fs::remove_file(database_path).map_err(|_| Error::SqliteResetError {
+            path: database_path.to_owned(),
+        })?;
+        connection = establish()?;
+        embedded_migrations::run(&connection).map_err(|_| Error::SqliteMigrationError {})?;
+        // The indexes are usable without it, so open the db anyway and report what was lost.
+        local_state_error = local_state
+            .restore(&connection)
+            .context(LocalStateRestoreError)
+            .err()
+            .map(|err| err.to_string());
+        versions_before.clear();
+        indexes_reset = true;
+    }
+
+    let outcome = MigrationOutcome {
+        local_state_error,
+        ..migration_outcome(&connection, &versions_before, indexes_reset)
+    };
+    Ok((connection, outcome))
+}
+
+// Returns `Pending` once, so the executor can run other tasks before polling us again.
+#[cfg(feature = "async")]
+struct YieldNow(bool);
+
+#[cfg(feature = "async")]
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+fn retry_on_busy<T, F>(retries: u32, mut f: F) -> std::result::Result<T, db::AppendError>
+where
+    F: FnMut() -> std::result::Result<T, db::AppendError>,
+{
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(db::AppendError::Sqlite { ref source })
+                if db::is_busy_error(source) && attempt < retries =>
+            {
+                attempt += 1;
+                thread::sleep(BUSY_BACKOFF * attempt);
+            }
+            res => return res,
+        }
+    }
+}
+
+fn setup_shared_connection(connection: &SqliteConnection) -> Result<MigrationOutcome> {
+    if let Ok(false) = any_pending_migrations(connection) {
+        return Ok(MigrationOutcome::default());
+    }
+    let versions_before = find_migration_versions(connection);
+
+    // We can't delete a db file we don't own, so migrate it in place and start the indexes again.
+    embedded_migrations::run(connection).map_err(|_| Error::SqliteMigrationError {})?;
+    clear_indexes(connection).context(SqliteClearError)?;
+
+    // There were only indexes to throw away if an older version had made them.
+    let indexes_reset = !versions_before.is_empty();
+    Ok(migration_outcome(
+        connection,
+        &versions_before,
+        indexes_reset,
+    ))
+}
+
+fn migration_outcome(
+    connection: &SqliteConnection,
+    versions_before: &[String],
+    indexes_reset: bool,
+) -> MigrationOutcome {
+    let applied = find_migration_versions(connection)
+        .into_iter()
+        .filter(|version| !versions_before.contains(version))
+        .collect();
+
+    MigrationOutcome {
+        applied,
+        indexes_reset,
+        local_state_error: None,
+    }
+}
+
+/// Pick the key, the value or both out of the entry at `offset`, for
+/// [SsbDb::get_entries_newer_than_sequence].
+pub(crate) fn select_entry_parts(
+    offset: FlumeSequence,
+    entry: Vec<u8>,
+    include_keys: bool,
+    include_values: bool,
+) -> Result<Vec<u8>> {
+    match (include_keys, include_values) {
+        (false, false) => Err(Error::IncludeKeysIncludeValuesBothFalse {}),
+        (true, false) => serde_json::from_slice::<SsbMessage>(&entry)
+            .map(|msg| msg.key.into_bytes())
+            .map_err(|_| Error::CorruptEntry { offset }),
+        (false, true) => {
+            //If we're going to use Serde to pluck out the value we have to use
+            //ssb-legacy-data Value so that when we convert it back to a string, the
+            //ordering is still intact.
+            //If we don't do that then we would return a message that would fail
+            //verification
+            let legacy_value = ssb_legacy_msg_data::json::from_slice(&entry)
+                .map_err(|_| Error::ErrorParsingAsLegacyValue {})?;
+            legacy_value_to_vec(offset, &legacy_value)
+        }
+        (true, true) => Ok(entry),
     }
+}
+
+fn legacy_value_to_vec(offset: FlumeSequence, legacy_value: &Value) -> Result<Vec<u8>> {
+    if let Value::Object(legacy_val) = legacy_value {
+        let val = legacy_val.get("value").context(ErrorParsingAsLegacyValue)?;
+        ssb_legacy_msg_data::json::to_vec(&val, false)
+            .map_err(|_| Error::EncodingValueAsVecError { offset })
+    } else {
+        Err(Error::ErrorParsingAsLegacyValue {})
+    }
+}
 
-    connection
+pub(crate) fn parse_author(author: &str) -> Result<Multikey> {
+    Multikey::from_legacy(author.as_bytes())
+        .map(|(feed_id, _)| feed_id)
+        .map_err(|_| Error::ErrorParsingAuthor {
+            author: author.to_owned(),
+        })
 }
-fn to_sqlite_uri(path: &str, rw_mode: &str) -> String {
-    format!("file:{}?mode={}", path, rw_mode)
+
+pub(crate) fn parse_key(key: &str) -> Result<Multihash> {
+    Multihash::from_legacy(key.as_bytes())
+        .map(|(key, _)| key)
+        .map_err(|_| Error::ErrorParsingKey {
+            key: key.to_owned(),
+        })
+}
+
+fn to_sqlite_uri(path: &str, rw_mode: &str, shared_cache: bool) -> String {
+    if shared_cache {
+        format!("file:{}?mode={}&cache=shared", path, rw_mode)
+    } else {
+        format!("file:{}?mode={}", path, rw_mode)
+    }
 }
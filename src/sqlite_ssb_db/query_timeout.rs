@@ -0,0 +1,113 @@
+//! Aborting queries that run past a deadline, with sqlite's progress handler.
+//!
+//! Diesel doesn't hand out the raw sqlite handle the progress handler has to be installed on, so
+//! we install it from a sqlite auto extension, which sqlite calls with the handle of each
+//! connection as it's opened. The extension is only registered while one of our own connections
+//! is being opened, and cancelled straight after, so it isn't left hooked into every connection
+//! the process opens.
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use libsqlite3_sys as ffi;
+use std::cell::Cell;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How many sqlite virtual machine instructions run between checks of the deadline.
+const PROGRESS_INTERVAL: c_int = 1000;
+
+// Held while the extension is registered, so one of our connections being opened can't cancel it
+// while another is still being opened.
+static OPENING_LOCK: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    // The deadline for the connection being opened on this thread, if it's one of ours.
+    static OPENING: Cell<*const QueryDeadline> = const { Cell::new(ptr::null()) };
+}
+
+/// When the queries on a connection have to finish by.
+#[derive(Debug, Default)]
+pub(super) struct QueryDeadline {
+    deadline: Cell<Option<Instant>>,
+    expired: Cell<bool>,
+}
+
+impl QueryDeadline {
+    /// Abort any query still running `timeout` from now.
+    pub(super) fn start(&self, timeout: Duration) {
+        self.deadline.set(Some(Instant::now() + timeout));
+        self.expired.set(false);
+    }
+
+    /// Stop the clock, returning whether it ran out and a query was aborted.
+    pub(super) fn finish(&self) -> bool {
+        self.deadline.set(None);
+        self.expired.replace(false)
+    }
+}
+
+/// Open a connection to `database_url` whose queries are aborted once `deadline` has passed.
+///
+/// `deadline` is kept by sqlite as a raw pointer, so it must not move or be dropped before the
+/// connection is.
+pub(super) fn establish(
+    database_url: &str,
+    deadline: &QueryDeadline,
+) -> ConnectionResult<SqliteConnection> {
+    let _opening = OPENING_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Sqlite takes every entry point as a `void (*)(void)`, and calls it with the real arguments.
+    let entry_point = unsafe {
+        std::mem::transmute::<AutoExtension, unsafe extern "C" fn()>(install_progress_handler)
+    };
+
+    // A connection opened on another thread meanwhile also runs the extension, but `OPENING` is
+    // only set on this one, so it gets no handler.
+    unsafe { ffi::sqlite3_auto_extension(Some(entry_point)) };
+    OPENING.with(|opening| opening.set(deadline));
+    let connection = SqliteConnection::establish(database_url);
+    OPENING.with(|opening| opening.set(ptr::null()));
+    unsafe { sqlite3_cancel_auto_extension(Some(entry_point)) };
+
+    connection
+}
+
+type AutoExtension =
+    unsafe extern "C" fn(*mut ffi::sqlite3, *mut *mut c_char, *const c_void) -> c_int;
+
+// Part of the bundled sqlite, but libsqlite3-sys doesn't have a binding for it.
+extern "C" {
+    fn sqlite3_cancel_auto_extension(entry_point: Option<unsafe extern "C" fn()>) -> c_int;
+}
+
+// Sqlite calls this on the thread opening the connection, while `establish` is still running.
+unsafe extern "C" fn install_progress_handler(
+    db: *mut ffi::sqlite3,
+    _error_message: *mut *mut c_char,
+    _api: *const c_void,
+) -> c_int {
+    let deadline = OPENING.with(Cell::get);
+    if !deadline.is_null() {
+        ffi::sqlite3_progress_handler(
+            db,
+            PROGRESS_INTERVAL,
+            Some(check_deadline),
+            deadline as *mut c_void,
+        );
+    }
+    ffi::SQLITE_OK
+}
+
+// Returning non zero makes sqlite abort the running query with `SQLITE_INTERRUPT`.
+unsafe extern "C" fn check_deadline(deadline: *mut c_void) -> c_int {
+    let deadline = &*(deadline as *const QueryDeadline);
+    match deadline.deadline.get() {
+        Some(deadline_at) if Instant::now() >= deadline_at => {
+            deadline.expired.set(true);
+            1
+        }
+        _ => 0,
+    }
+}
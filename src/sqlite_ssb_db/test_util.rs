@@ -0,0 +1,54 @@
+//! Ways to damage a db on purpose, so you can test how your code recovers from corruption.
+//!
+//! Only available with the `test-util` feature. Never use these on a db you care about.
+use diesel::connection::SimpleConnection;
+use flumedb::FlumeLog;
+use snafu::ResultExt;
+
+use super::SqliteSsbDb;
+use crate::db::get_latest;
+use crate::error::*;
+use crate::FlumeSequence;
+
+impl SqliteSsbDb {
+    /// Overwrite the data of the entry at `offset` with garbage, leaving the offset log's framing
+    /// intact so the rest of the log can still be read.
+    ///
+    /// The indexes still point at the entry, so reads of it return garbage (or an
+    /// [Error::CorruptEntry] with [SqliteSsbDb::set_validate_entries]).
+    pub fn inject_corruption_at(&self, offset: FlumeSequence) -> Result<()> {
+        let len = self.entry_len(offset)?;
+        self.fill_offset_log_entries(&[(offset, len)], 0xff)
+    }
+
+    /// Zero out the data of the entry at `offset`, like a deleted entry. The indexes still point
+    /// at it.
+    pub fn zero_entry_at(&self, offset: FlumeSequence) -> Result<()> {
+        let len = self.entry_len(offset)?;
+        self.zero_offset_log_entries(&[(offset, len)])
+    }
+
+    /// Make the indexes disagree with the offset log, by pointing the latest indexed message past
+    /// the end of the log. This is what happens when the log is swapped for a shorter one.
+    pub fn desync_index(&self) -> Result<()> {
+        let connection = self.connection.borrow();
+        let past_end = self.offset_log().end() + 1;
+
+        match get_latest(&connection).context(UnableToGetLatestSequence)? {
+            Some(latest) => connection
+                .batch_execute(&format!(
+                    "UPDATE messages SET flume_seq = {} WHERE flume_seq = {}",
+                    past_end, latest
+                ))
+                .context(SqliteWriteError),
+            None => Ok(()),
+        }
+    }
+
+    fn entry_len(&self, offset: FlumeSequence) -> Result<usize> {
+        self.offset_log()
+            .get(offset)
+            .map(|entry| entry.len())
+            .map_err(|_| Error::OffsetGetError {})
+    }
+}
@@ -0,0 +1,157 @@
+//! Keep the indexes up to date while another process appends to the offset log.
+//!
+//! Only available with the `watch` feature.
+use flumedb::offset_log::OffsetLog;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::{MigrationOutcome, QueryDeadline, SqliteSsbDb};
+use crate::error::*;
+use crate::ssb_message::SerdeJsonParser;
+
+// Appends tend to come in bursts, so wait for the log to be quiet for this long before indexing.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(100);
+
+/// Watches the offset log until it's dropped. See [SqliteSsbDb::start_watching].
+pub struct WatchHandle {
+    watcher: Option<RecommendedWatcher>,
+    thread: Option<JoinHandle<()>>,
+    errors: Receiver<Error>,
+}
+
+impl SqliteSsbDb {
+    /// Index new entries as soon as another process appends them to the offset log, instead of
+    /// polling [SqliteSsbDb::update_indexes_from_offset_file].
+    ///
+    /// Indexing happens on a background thread with its own connection to the sqlite db, opened
+    /// here with the same options and [SqliteSsbDb::with_on_connect] hook as ours. It reopens the
+    /// offset log each time the file changes so it sees the new end, and a burst of appends is
+    /// indexed in one go once the file has been quiet for a moment. Errors don't stop the watching,
+    /// they're collected for [WatchHandle::take_errors]. Watching stops when the returned
+    /// [WatchHandle] is dropped, or with [WatchHandle::stop].
+    ///
+    /// The background thread indexes with the default parser, not one passed to
+    /// [SqliteSsbDb::new_with_parser]. Fails with [Error::WatchError] if the db wasn't opened from
+    /// paths, eg. with [SqliteSsbDb::from_connection] or [SqliteSsbDb::from_shared_log], or if
+    /// the connection can't be opened.
+    pub fn start_watching(&self) -> Result<WatchHandle> {
+        let (db_path, offset_log_path) = match (&self.db_path, &self.offset_log_path) {
+            (Some(db_path), Some(offset_log_path)) => (db_path.clone(), offset_log_path.clone()),
+            _ => return Err(Error::WatchError {}),
+        };
+        let query_deadline: Box<QueryDeadline> = Box::default();
+        let connection = self
+            .open_connection(&db_path, &query_deadline)
+            .map_err(|_| Error::WatchError {})?;
+
+        let (tx, rx) = channel();
+        let mut watcher = notify::watcher(tx, DEBOUNCE_DELAY).map_err(|_| Error::WatchError {})?;
+        watcher
+            .watch(&offset_log_path, RecursiveMode::NonRecursive)
+            .map_err(|_| Error::WatchError {})?;
+
+        let (errors_tx, errors) = channel();
+        let busy_retries = self.busy_retries;
+        let store_raw = self.store_raw;
+        let max_messages_per_feed = self.max_messages_per_feed;
+        let read_chunk_size = self.read_chunk_size;
+        let commit_every = self.commit_every;
+        let connection_options = self.connection_options;
+        let on_connect = self.on_connect.clone();
+        let thread = thread::spawn(move || {
+            let offset_log = match OffsetLog::new(&offset_log_path) {
+                Ok(offset_log) => offset_log,
+                Err(_) => return report(&errors_tx, Error::WatchError {}),
+            };
+            let mut db = SqliteSsbDb::from_parts(
+                connection,
+                MigrationOutcome::default(),
+                query_deadline,
+                Some(db_path),
+                Arc::new(Mutex::new(offset_log)),
+                Some(offset_log_path.clone()),
+                Box::new(SerdeJsonParser),
+            );
+            db.busy_retries = busy_retries;
+            db.store_raw = store_raw;
+            db.max_messages_per_feed = max_messages_per_feed;
+            db.read_chunk_size = read_chunk_size;
+            db.commit_every = commit_every;
+            db.connection_options = connection_options;
+            db.on_connect = on_connect;
+
+            while wait_for_change(&rx) {
+                match OffsetLog::new(&offset_log_path) {
+                    Ok(offset_log) => *db.offset_log() = offset_log,
+                    Err(_) => {
+                        report(&errors_tx, Error::WatchError {});
+                        continue;
+                    }
+                }
+                // Anything that fails to index is picked up on the next change.
+                if let Err(err) = db.update_indexes_from_offset_file() {
+                    report(&errors_tx, err);
+                }
+            }
+        });
+
+        Ok(WatchHandle {
+            watcher: Some(watcher),
+            thread: Some(thread),
+            errors,
+        })
+    }
+}
+
+impl WatchHandle {
+    /// Take the errors the background thread has run into since the last call, oldest first, eg.
+    /// to log them.
+    pub fn take_errors(&self) -> Vec<Error> {
+        self.errors.try_iter().collect()
+    }
+
+    /// Stop watching, and wait for the background thread to finish. Fails with
+    /// [Error::WatchError] if the thread panicked.
+    pub fn stop(mut self) -> Result<()> {
+        self.stop_thread()
+    }
+
+    fn stop_thread(&mut self) -> Result<()> {
+        // Dropping the watcher closes the channel, which ends the indexing thread.
+        self.watcher.take();
+        match self.thread.take() {
+            Some(thread) => thread.join().map_err(|_| Error::WatchError {}),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        // Best effort, use `stop` to find out if it worked.
+        let _ = self.stop_thread();
+    }
+}
+
+// Send an error back to the handle. It's fine if the handle has gone, then nobody's listening.
+fn report(errors: &Sender<Error>, err: Error) {
+    let _ = errors.send(err);
+}
+
+// Block until the offset log has changed, then skip any other changes already queued up since
+// they'll all be indexed together. Returns `false` once the watcher has been dropped.
+fn wait_for_change(rx: &Receiver<DebouncedEvent>) -> bool {
+    loop {
+        match rx.recv() {
+            Ok(DebouncedEvent::Write(_)) | Ok(DebouncedEvent::Create(_)) => {
+                rx.try_iter().for_each(drop);
+                return true;
+            }
+            Ok(_) => continue,
+            Err(_) => return false,
+        }
+    }
+}
@@ -1,13 +1,161 @@
+//! The parts of an ssb message that `ssb-db` needs to index it.
 use serde::{Deserialize, Serialize};
+use ssb_multiformats::multihash::Multihash;
+use ssb_multiformats::multikey::Multikey;
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct SsbValue {
     pub author: String,
     pub sequence: u32,
+    #[serde(default)]
+    pub previous: Option<String>,
+    #[serde(default)]
+    pub content: serde_json::Value,
+    /// When the author claims they wrote the message, in ms since the unix epoch.
+    #[serde(default)]
+    pub timestamp: Option<f64>,
+}
+
+impl SsbValue {
+    /// The `type` of the message content, or `None` if the content isn't an object with a
+    /// string `type` (eg. a private message).
+    pub fn content_type(&self) -> Option<&str> {
+        self.content.get("type").and_then(|t| t.as_str())
+    }
+
+    /// Every distinct feed id mentioned anywhere in the message content, eg. in `mentions` or a
+    /// contact message's `contact`.
+    pub fn mentioned_feeds(&self) -> Vec<String> {
+        collect_ids(
+            &self.content,
+            &|id| match Multikey::from_legacy(id.as_bytes()) {
+                Ok((_, rest)) => rest.is_empty(),
+                Err(_) => false,
+            },
+        )
+    }
+
+    /// Every distinct message key linked to anywhere in the message content, eg. a reply's
+    /// `root` and `branch`, or the message a vote is for.
+    pub fn linked_messages(&self) -> Vec<String> {
+        collect_ids(
+            &self.content,
+            &|id| match Multihash::from_legacy(id.as_bytes()) {
+                Ok((Multihash::Message(_), rest)) => rest.is_empty(),
+                _ => false,
+            },
+        )
+    }
+}
+
+// Every distinct string anywhere in `value` that `is_id`, sorted.
+fn collect_ids(value: &serde_json::Value, is_id: &dyn Fn(&str) -> bool) -> Vec<String> {
+    let mut ids = Vec::new();
+    collect_strings(value, is_id, &mut ids);
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+fn collect_strings(value: &serde_json::Value, keep: &dyn Fn(&str) -> bool, out: &mut Vec<String>) {
+    match value {
+        serde_json::Value::String(string) if keep(string) => out.push(string.to_owned()),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .for_each(|value| collect_strings(value, keep, out)),
+        serde_json::Value::Object(map) => map
+            .values()
+            .for_each(|value| collect_strings(value, keep, out)),
+        _ => (),
+    }
+}
+
+/// The message content types most ssb apps use, so you don't have to spell them out as strings.
+///
+/// `Other` covers everything else.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CommonContentType {
+    Post,
+    Contact,
+    Vote,
+    About,
+    Pub,
+    Other(String),
+}
+
+impl CommonContentType {
+    /// The content `type` string for this type, eg. `"post"`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            CommonContentType::Post => "post",
+            CommonContentType::Contact => "contact",
+            CommonContentType::Vote => "vote",
+            CommonContentType::About => "about",
+            CommonContentType::Pub => "pub",
+            CommonContentType::Other(content_type) => content_type,
+        }
+    }
+}
+
+impl From<&str> for CommonContentType {
+    fn from(content_type: &str) -> Self {
+        match content_type {
+            "post" => CommonContentType::Post,
+            "contact" => CommonContentType::Contact,
+            "vote" => CommonContentType::Vote,
+            "about" => CommonContentType::About,
+            "pub" => CommonContentType::Pub,
+            other => CommonContentType::Other(other.to_owned()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct SsbMessage {
     pub key: String,
     pub value: SsbValue,
+    /// When the message was received, in ms since the unix epoch.
+    #[serde(default)]
+    pub timestamp: Option<f64>,
+}
+
+/// Whether `bytes` looks like a whole ssb message: a `key`, and a `value` with an `author`,
+/// `sequence`, `signature` and `content`.
+///
+/// This only checks the fields are there with the right types, it doesn't verify the signature.
+pub fn is_structurally_valid(bytes: &[u8]) -> bool {
+    let message = match serde_json::from_slice::<serde_json::Value>(bytes) {
+        Ok(message) => message,
+        Err(_) => return false,
+    };
+    let value = &message["value"];
+
+    message["key"].is_string()
+        && value["author"].is_string()
+        && value["sequence"].is_u64()
+        && value["signature"].is_string()
+        // Private messages have their encrypted content as a string.
+        && (value["content"].is_object() || value["content"].is_string())
+}
+
+/// Parses raw offset log entries into [SsbMessage]s for indexing.
+///
+/// Indexing a large log is dominated by json parsing, so this lets you plug in a faster parser.
+/// Return `None` for entries that aren't messages (eg. zeroed out deleted entries), they will be
+/// skipped.
+///
+/// It's `Send + Sync` so a [SqliteSsbDb](crate::SqliteSsbDb) using it can be moved to another
+/// thread.
+pub trait SsbMessageParser: Send + Sync {
+    fn parse_message(&self, bytes: &[u8]) -> Option<SsbMessage>;
+}
+
+/// The default [SsbMessageParser], backed by `serde_json`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SerdeJsonParser;
+
+impl SsbMessageParser for SerdeJsonParser {
+    fn parse_message(&self, bytes: &[u8]) -> Option<SsbMessage> {
+        serde_json::from_slice(bytes).ok()
+    }
 }